@@ -1,48 +1,413 @@
+use std::collections::HashMap;
 use std::io::stdout;
 
 use anyhow::Result;
+use chrono::{Datelike, Local};
 use crossterm::event::{DisableMouseCapture, EnableMouseCapture, Event, self, KeyCode};
 use crossterm::execute;
 use crossterm::terminal::{enable_raw_mode, disable_raw_mode, LeaveAlternateScreen, EnterAlternateScreen};
 use tui::Terminal;
 use tui::backend::{CrosstermBackend, Backend};
 
+use crate::athlete::Athlete;
+use crate::critical_power::{self, CriticalPowerFit};
+use crate::ftp_estimate::{self, EstimateConfig, FtpEstimate};
+use crate::gear::Bike;
+use crate::import::ImportLogEntry;
 use crate::parser::Session;
-use crate::db;
+use crate::personal_record::PersonalRecordBoard;
+use crate::pmc::{self, PmcDay, TimeConstants};
+use crate::power_curve::{CurvePeriod, CURVE_PERIODS, PowerCurve};
+use crate::store::SessionStore;
+use crate::summary::{CumulativeYear, DateRange, Period, PeriodSummary, Summary, ROLLING_WINDOWS};
+use crate::threshold::ThresholdHistory;
 use crate::ui;
 use crate::util;
+use crate::zones::{self, TimeInZones, ZoneConfig};
+
+/// Imports shown in the imports view
+const IMPORT_LOG_LIMIT: i64 = 200;
+
+/// Days the performance chart can show, switched with z
+pub const PMC_WINDOWS: [usize; 4] = [42, 90, 365, usize::MAX];
+
+/// What the key presses are currently used for
+#[derive(Default, Clone, PartialEq)]
+pub enum InputMode {
+    #[default]
+    Normal,
+    /// Field being edited and the text typed so far
+    Edit(EditField, String),
+    ConfirmDelete
+}
+
+/// Screens of the dashboard, switched with tab
+#[derive(Default, Clone, Copy, PartialEq)]
+pub enum View {
+    #[default]
+    Sessions,
+    Trends,
+    YearOverYear,
+    Performance,
+    PowerCurve,
+    PersonalRecords,
+    Imports
+}
+
+pub const VIEWS: [View; 7] = [
+    View::Sessions,
+    View::Trends,
+    View::YearOverYear,
+    View::Performance,
+    View::PowerCurve,
+    View::PersonalRecords,
+    View::Imports
+];
+
+impl View {
+    pub fn title(&self) -> &str {
+        return match self {
+            View::Sessions => "Sessions",
+            View::Trends => "Trends",
+            View::YearOverYear => "Year over year",
+            View::Performance => "Performance",
+            View::PowerCurve => "Power curve",
+            View::PersonalRecords => "Personal records",
+            View::Imports => "Imports"
+        };
+    }
+
+    pub fn next(&self) -> Self {
+        let idx = VIEWS.iter().position(|x| x == self).unwrap_or(0);
+        return VIEWS[(idx + 1) % VIEWS.len()];
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum EditField {
+    Title,
+    SubSport,
+    Notes,
+    Tags,
+    Search
+}
 
 pub struct App {
+    pub store: Box<dyn SessionStore>,
     pub sessions: Vec<Session>,
     pub selected_session: Option<Session>,
-    pub selected_session_index: Option<usize>
+    pub selected_session_index: Option<usize>,
+    /// Year the dashboard summary cards are calculated for
+    pub summary_year: i32,
+    /// Totals of the summary year on the dashboard cards
+    pub year_total: ui::Total,
+    /// Periods the trends view groups the sessions by
+    pub trend_period: Period,
+    /// Summary of every rolling window of the trends view
+    pub rolling_summaries: Vec<Summary>,
+    /// Latest periods of the trends view, oldest first
+    pub trend_periods: Vec<PeriodSummary>,
+    /// Running totals of the summary year and the years before it
+    pub compared_years: Vec<CumulativeYear>,
+    /// Time constants of fitness and fatigue on the performance view
+    pub time_constants: TimeConstants,
+    /// Index in `PMC_WINDOWS` of the days the performance chart shows
+    pub pmc_window: usize,
+    /// Fitness, fatigue and form of every day with the time constants, read again with the athlete's sessions
+    pub performance: Vec<PmcDay>,
+    /// Periods the power curve view shows, the second one is drawn under the first when set
+    pub curve_period: CurvePeriod,
+    pub compared_curve_period: Option<CurvePeriod>,
+    /// Curves of the shown periods, the compared one second
+    pub power_curves: Vec<PowerCurve>,
+    /// Critical power models fitted to the first of the shown curves
+    pub power_curve_fits: Vec<CriticalPowerFit>,
+    pub input_mode: InputMode,
+    pub view: View,
+    pub bikes: Vec<Bike>,
+    pub athletes: Vec<Athlete>,
+    /// Profile whose sessions and summaries are shown
+    pub athlete_id: i64,
+    pub thresholds: ThresholdHistory,
+    /// Daily critical power fits, W′bal of a session uses the one of its day
+    pub critical_power: Vec<CriticalPowerFit>,
+    pub personal_records: PersonalRecordBoard,
+    /// How the FTP is estimated, from .env
+    pub estimate_config: EstimateConfig,
+    /// Estimated FTP when it moved far enough from the threshold history to be accepted with a
+    pub ftp_estimate: Option<FtpEstimate>,
+    /// Power and heart rate zones from .env
+    pub zone_config: ZoneConfig,
+    /// Time in zones of the current week's sessions
+    pub week_zones: TimeInZones,
+    /// Latest imports, newest first
+    pub import_log: Vec<ImportLogEntry>,
+    /// Full text filter for the session list, empty shows every session
+    pub search_query: String,
+    /// Error of the last key press, shown in the tab row until the next one
+    pub error: Option<String>,
+    /// Sessions with laps and records loaded, keyed by session id
+    session_details: HashMap<i64, Session>
 }
 
-impl Default for App {
-    fn default() -> Self {
-        App {
-            selected_session_index: None,
+impl App {
+    pub fn new(store: Box<dyn SessionStore>) -> Self {
+        let mut app = Self {
+            store,
             sessions: Vec::new(),
-            selected_session: None
+            selected_session: None,
+            selected_session_index: Some(0),
+            summary_year: Local::now().year(),
+            year_total: ui::Total::default(),
+            trend_period: Period::default(),
+            rolling_summaries: Vec::new(),
+            trend_periods: Vec::new(),
+            compared_years: Vec::new(),
+            time_constants: TimeConstants::from_env().unwrap_or_default(),
+            pmc_window: 1,
+            performance: Vec::new(),
+            curve_period: CurvePeriod::Last42Days,
+            compared_curve_period: Some(CurvePeriod::AllTime),
+            power_curves: Vec::new(),
+            power_curve_fits: Vec::new(),
+            input_mode: InputMode::default(),
+            view: View::default(),
+            bikes: Vec::new(),
+            athletes: Vec::new(),
+            athlete_id: 0,
+            thresholds: ThresholdHistory::default(),
+            critical_power: Vec::new(),
+            personal_records: PersonalRecordBoard::default(),
+            estimate_config: EstimateConfig::from_env().unwrap_or_default(),
+            ftp_estimate: None,
+            zone_config: ZoneConfig::from_env().unwrap_or_default(),
+            week_zones: TimeInZones::default(),
+            import_log: Vec::new(),
+            search_query: String::new(),
+            error: None,
+            session_details: HashMap::new()
+        };
+        app.athletes = app.store.get_athletes().unwrap_or_default();
+        app.athlete_id = app.athletes.first().map(|x| x.id).unwrap_or(0);
+        app.reload_athlete();
+
+        return app;
+    }
+
+    /// Reads the session list again after sessions have been edited or searched, what is derived from them stays
+    pub fn reload_sessions(&mut self) {
+        self.sessions = if self.search_query.trim().is_empty() {
+            self.store.get_all_sessions(self.athlete_id).unwrap_or_default()
+        } else {
+            self.store.search_sessions(self.athlete_id, &self.search_query).unwrap_or_default()
+        };
+        self.bikes = self.store.get_bikes().unwrap_or_default();
+        self.athletes = self.store.get_athletes().unwrap_or_default();
+        self.import_log = self.store.get_import_log(IMPORT_LOG_LIMIT).unwrap_or_default();
+        self.session_details.clear();
+        self.reload_year_total();
+
+        let index = self.selected_session_index
+            .unwrap_or(0)
+            .min(self.sessions.len().saturating_sub(1));
+
+        self.selected_session_index = Some(index);
+        self.change_selected_session(index);
+    }
+
+    /// Reads the session list and what is derived from the athlete's sessions and thresholds again,
+    /// after another athlete was picked, a session was deleted or a threshold added
+    pub fn reload_athlete(&mut self) {
+        self.thresholds = self.store.get_thresholds(self.athlete_id).unwrap_or_default();
+        self.critical_power = self.store.get_critical_power_history(self.athlete_id).unwrap_or_default();
+        self.personal_records = self.store.get_personal_records(self.athlete_id).unwrap_or_default();
+        self.ftp_estimate = ftp_estimate::estimate(self.store.as_ref(), self.athlete_id, &self.thresholds, &self.estimate_config)
+            .unwrap_or_default()
+            .filter(|x| x.is_change(&self.estimate_config));
+        self.week_zones = Period::Week.latest(1)
+            .and_then(|weeks| zones::of_ranges(self.store.as_ref(), self.athlete_id, &self.thresholds, &self.zone_config, &weeks))
+            .ok()
+            .and_then(|x| x.into_iter().next())
+            .unwrap_or_default();
+        self.performance = pmc::performance(self.store.as_ref(), self.athlete_id, self.time_constants).unwrap_or_default();
+        self.reload_sessions();
+        self.reload_power_curves();
+        self.reload_trends();
+        self.reload_compared_years();
+    }
+
+    /// Reads the rolling windows and latest periods again, after the period was switched or the sessions changed
+    pub fn reload_trends(&mut self) {
+        self.rolling_summaries = ROLLING_WINDOWS.iter()
+            .map(|days| Summary::rolling(self.store.as_ref(), self.athlete_id, *days).unwrap_or_default())
+            .collect();
+        self.trend_periods = Summary::latest_periods(self.store.as_ref(), self.athlete_id, self.trend_period, ui::TREND_PERIODS_COUNT)
+            .unwrap_or_default();
+    }
+
+    /// Reads the totals of the summary year again, after the year was switched or the sessions changed
+    pub fn reload_year_total(&mut self) {
+        self.year_total = match DateRange::year(self.summary_year) {
+            Ok(range) => ui::Total::new(self.store.as_ref(), self.athlete_id, &range),
+            Err(_) => ui::Total::default()
+        };
+    }
+
+    /// Reads the running totals of the years again, after the summary year was switched or the sessions changed
+    pub fn reload_compared_years(&mut self) {
+        self.compared_years = Summary::compared_years(self.store.as_ref(), self.athlete_id, self.summary_year, ui::COMPARED_YEARS_COUNT)
+            .unwrap_or_default();
+    }
+
+    /// Reads the curves of the shown periods again and fits the models, after a period was switched or the sessions changed
+    pub fn reload_power_curves(&mut self) {
+        self.power_curves = [Some(self.curve_period), self.compared_curve_period].into_iter()
+            .flatten()
+            .map(|period| period.range()
+                .and_then(|range| self.store.get_power_curve(self.athlete_id, &range))
+                .unwrap_or_default())
+            .collect();
+
+        let range = self.curve_period.range().unwrap_or(DateRange::rolling(critical_power::FIT_WINDOW_DAYS));
+        self.power_curve_fits = critical_power::fit_models(self.athlete_id
+            , range.end
+            , self.power_curves.first().unwrap_or(&PowerCurve::default())
+            , critical_power::device_threshold_power(&self.sessions, &range));
+    }
+
+    pub fn update_selected_session(&mut self, field: EditField, value: String) -> Result<()> {
+        let mut session = match self.selected_session.to_owned() {
+            Some(value) => value,
+            None => return Ok(())
+        };
+
+        match field {
+            EditField::Title => session.title = Some(value),
+            EditField::SubSport if !value.trim().is_empty() => session.sub_sport = value.trim().to_string(),
+            EditField::Notes => session.notes = Some(value),
+            EditField::Tags => session.tags = util::parse_tags(&value),
+            _ => return Ok(())
         }
+
+        self.store.update_session(&session)?;
+        self.reload_sessions();
+
+        return Ok(());
     }
-}
 
-impl App {
-    pub fn new() -> Self {
-        let all_sessions = db::get_all_sessions().unwrap();
-        let selected = all_sessions.first().cloned();
+    /// Moves the selected session to the next bike, after the last bike the session is left without one
+    pub fn cycle_selected_session_bike(&mut self) -> Result<()> {
+        let mut session = match self.selected_session.to_owned() {
+            Some(value) => value,
+            None => return Ok(())
+        };
 
-        return Self {
-            sessions: all_sessions,
-            selected_session_index: Some(0),
-            selected_session: selected,
-            ..App::default()
+        let next_idx = match session.bike_id {
+            Some(bike_id) => self.bikes.iter().position(|x| x.id == bike_id).map(|x| x + 1),
+            None => Some(0)
+        };
+
+        session.bike_id = next_idx
+            .and_then(|idx| self.bikes.get(idx))
+            .map(|x| x.id);
+
+        self.store.update_session(&session)?;
+        self.reload_sessions();
+
+        return Ok(());
+    }
+
+    pub fn athlete(&self) -> Option<&Athlete> {
+        return self.athletes.iter().find(|x| x.id == self.athlete_id);
+    }
+
+    /// Threshold power of the selected session from the athlete's threshold history
+    pub fn selected_threshold_power(&self) -> i64 {
+        return match &self.selected_session {
+            Some(session) => self.thresholds.ftp_for(session),
+            None => 0
+        };
+    }
+
+    /// Time in zones of the selected session
+    pub fn selected_zones(&self) -> Option<TimeInZones> {
+        return self.selected_session.as_ref().map(|x| TimeInZones::of_session(x, &self.thresholds, &self.zone_config));
+    }
+
+    /// Critical power fit of the selected session's day with its W′bal at every record
+    pub fn selected_w_prime_balance(&self) -> Option<(&CriticalPowerFit, Vec<f64>)> {
+        let session = self.selected_session.as_ref()?;
+        let fit = critical_power::fit_at(&self.critical_power, session.start_time)?;
+
+        return Some((fit, critical_power::session_w_prime_balance(&session.records, fit)?));
+    }
+
+    /// Adds the FTP estimate to the threshold history from now on
+    pub fn accept_ftp_estimate(&mut self) -> Result<()> {
+        let estimate = match &self.ftp_estimate {
+            Some(value) => value,
+            None => return Ok(())
+        };
+
+        self.store.insert_threshold(&estimate.threshold(self.athlete_id))?;
+        self.reload_athlete();
+
+        return Ok(());
+    }
+
+    /// Switches the dashboard to the next athlete profile
+    pub fn next_athlete(&mut self) {
+        if self.athletes.is_empty() {
+            return;
         }
+
+        let next_idx = self.athletes.iter()
+            .position(|x| x.id == self.athlete_id)
+            .map(|x| (x + 1) % self.athletes.len())
+            .unwrap_or(0);
+
+        self.athlete_id = self.athletes[next_idx].id;
+        self.search_query.clear();
+        self.selected_session_index = Some(0);
+        self.reload_athlete();
+    }
+
+    pub fn delete_selected_session(&mut self) -> Result<()> {
+        let session_id = match &self.selected_session {
+            Some(session) => session.id.unwrap_or(0),
+            None => return Ok(())
+        };
+
+        self.store.delete_session(session_id)?;
+        self.reload_athlete();
+
+        return Ok(());
     }
 
     pub fn change_selected_session(&mut self, index: usize) {
-        self.selected_session = self.sessions.get(index).cloned(); 
+        let session = match self.sessions.get(index) {
+            Some(value) => value,
+            None => {
+                self.selected_session = None;
+                return;
+            }
+        };
+
+        // A session whose details couldn't be read is shown without them and read again the next time
+        let session_id = session.id.unwrap_or(0);
+        if !self.session_details.contains_key(&session_id) {
+            match self.store.get_session_details(session) {
+                Ok(detailed) => {
+                    self.session_details.insert(session_id, detailed);
+                },
+                Err(_) => {
+                    self.selected_session = Some(session.clone());
+                    return;
+                }
+            }
+        }
+
+        self.selected_session = self.session_details.get(&session_id).cloned();
     }
 
     pub fn start_ui(self) -> Result<()> {
@@ -73,28 +438,111 @@ impl App {
 
     fn draw<B: Backend>(mut self, terminal: &mut Terminal<B>) -> Result<()> {
         loop {
-            terminal.draw(|f| ui::draw_dashboard(f, &self))?;
+            terminal.draw(|f| ui::draw(f, &self))?;
 
             if let Event::Key(key) = event::read()? {
-                match key.code {
-                    KeyCode::Char('q') => {
-                        return Ok(());
-                    },
-                    KeyCode::Char('j') => {
-                        util::move_down_event(&mut self);
-                    },
-                    KeyCode::Char('k') => {
-                        util::move_up_event(&mut self);
-                    },
-                    KeyCode::Down => {
-                        util::move_down_event(&mut self);
-                    },
-                    KeyCode::Up => {
-                        util::move_up_event(&mut self);
-                    },
-                    _ => ()
+                self.error = None;
+
+                match self.key_event(key.code) {
+                    Ok(true) => return Ok(()),
+                    Ok(false) => (),
+                    Err(error) => self.error = Some(error.to_string())
                 }
             }
         }
     }
+
+    /// Handles a key press, true when the dashboard should quit. Errors are shown and the dashboard keeps running
+    fn key_event(&mut self, key: KeyCode) -> Result<bool> {
+        if self.input_mode != InputMode::Normal {
+            util::input_event(self, key)?;
+            return Ok(false);
+        }
+
+        match key {
+            KeyCode::Char('q') => {
+                return Ok(true);
+            },
+            KeyCode::Tab => {
+                self.view = self.view.next();
+            },
+            KeyCode::Char('p') => {
+                self.next_athlete();
+            },
+            KeyCode::Char('a') if self.ftp_estimate.is_some() => {
+                self.accept_ftp_estimate()?;
+            },
+            KeyCode::Char('w') if self.view == View::Trends => {
+                self.trend_period = Period::Week;
+                self.reload_trends();
+            },
+            KeyCode::Char('m') if self.view == View::Trends => {
+                self.trend_period = Period::Month;
+                self.reload_trends();
+            },
+            KeyCode::Char('y') if self.view == View::Trends => {
+                self.trend_period = Period::Year;
+                self.reload_trends();
+            },
+            KeyCode::Char('z') if self.view == View::Performance => {
+                self.pmc_window = (self.pmc_window + 1) % PMC_WINDOWS.len();
+            },
+            KeyCode::Char('c') if self.view == View::PowerCurve => {
+                self.curve_period = self.curve_period.next();
+                self.reload_power_curves();
+            },
+            KeyCode::Char('v') if self.view == View::PowerCurve => {
+                self.compared_curve_period = match self.compared_curve_period {
+                    None => Some(CURVE_PERIODS[0]),
+                    Some(period) if period == CURVE_PERIODS[CURVE_PERIODS.len() - 1] => None,
+                    Some(period) => Some(period.next())
+                };
+                self.reload_power_curves();
+            },
+            KeyCode::Char('h') | KeyCode::Left if self.view == View::Sessions || self.view == View::YearOverYear => {
+                util::previous_year_event(self);
+            },
+            KeyCode::Char('l') | KeyCode::Right if self.view == View::Sessions || self.view == View::YearOverYear => {
+                util::next_year_event(self);
+            },
+            // The other keys work on the session list
+            _ if self.view != View::Sessions => (),
+            KeyCode::Char('j') => {
+                util::move_down_event(self);
+            },
+            KeyCode::Char('k') => {
+                util::move_up_event(self);
+            },
+            KeyCode::Down => {
+                util::move_down_event(self);
+            },
+            KeyCode::Up => {
+                util::move_up_event(self);
+            },
+            KeyCode::Char('e') => {
+                util::edit_event(self, EditField::Title);
+            },
+            KeyCode::Char('s') => {
+                util::edit_event(self, EditField::SubSport);
+            },
+            KeyCode::Char('n') => {
+                util::edit_event(self, EditField::Notes);
+            },
+            KeyCode::Char('t') => {
+                util::edit_event(self, EditField::Tags);
+            },
+            KeyCode::Char('/') => {
+                util::edit_event(self, EditField::Search);
+            },
+            KeyCode::Char('b') => {
+                self.cycle_selected_session_bike()?;
+            },
+            KeyCode::Char('d') => {
+                self.input_mode = InputMode::ConfirmDelete;
+            },
+            _ => ()
+        }
+
+        return Ok(false);
+    }
 }
@@ -0,0 +1,62 @@
+use anyhow::{Result, bail};
+use chrono::Local;
+
+use crate::store::SessionStore;
+use crate::threshold::optional_to_string;
+use crate::util::flag_value;
+
+#[derive(Debug, Clone)]
+pub struct Athlete {
+    pub id: i64,
+    pub name: String,
+    pub sessions_count: i64
+}
+
+impl Athlete {
+    /// Reads the `--athlete <id|name>` flag, defaults to the first athlete
+    pub fn from_args(store: &dyn SessionStore, args: &[String]) -> Result<Self> {
+        let athletes = store.get_athletes()?;
+
+        let athlete = match flag_value(args, "--athlete") {
+            Some(value) => athletes.into_iter()
+                .find(|x| x.name == *value || x.id.to_string() == *value),
+            None if args.iter().any(|x| x == "--athlete") => bail!("Missing value for --athlete"),
+            None => athletes.into_iter().next()
+        };
+
+        return match athlete {
+            Some(value) => Ok(value),
+            None => bail!("Unknown athlete '{}'", flag_value(args, "--athlete").cloned().unwrap_or_default())
+        };
+    }
+}
+
+/// Handles `athlete <add|list|delete>` commands, thresholds are kept with the `threshold` commands
+pub fn command(store: &mut dyn SessionStore, args: &[String]) -> Result<()> {
+    let command: Vec<&str> = args.iter().take(2).map(|x| x.as_str()).collect();
+
+    match command[..] {
+        ["athlete", "add"] if args.len() > 2 => {
+            let athlete_id = store.insert_athlete(&args[2])?;
+            println!("Athlete {} added", athlete_id);
+        },
+        ["athlete", "list"] => {
+            for athlete in store.get_athletes()? {
+                let thresholds = store.get_thresholds(athlete.id)?;
+
+                println!("{}: {} {} sessions, FTP {}"
+                    , athlete.id
+                    , athlete.name
+                    , athlete.sessions_count
+                    , optional_to_string(thresholds.ftp_at(Local::now().timestamp())));
+            }
+        },
+        ["athlete", "delete"] if args.len() > 2 => {
+            store.delete_athlete(args[2].parse::<i64>()?)?;
+            println!("Athlete {} deleted", args[2]);
+        },
+        _ => bail!("Unknown athlete command: {}", args.join(" "))
+    }
+
+    return Ok(());
+}
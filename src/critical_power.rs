@@ -0,0 +1,360 @@
+use std::str::FromStr;
+use anyhow::{Result, bail};
+use chrono::Duration;
+
+use crate::athlete::Athlete;
+use crate::parser::{Record, Session};
+use crate::power;
+use crate::power_curve::{CurvePeriod, PowerCurve};
+use crate::store::SessionStore;
+use crate::summary::{DateRange, local_date, local_midnight};
+use crate::ui::util::date_as_string;
+use crate::util::{flag_value, positional_args};
+
+/// Days of best power the fits of the history are made from
+pub const FIT_WINDOW_DAYS: i64 = 42;
+
+/// Efforts the 2-parameter model is fitted to, shorter ones are limited by Pmax and longer ones by fatigue
+const TWO_PARAMETER_SECONDS: (i64, i64) = (120, 1200);
+const THREE_PARAMETER_SECONDS: (i64, i64) = (1, 1800);
+/// Largest `W′ / (Pmax - CP)` the 3-parameter fit tries, in seconds
+const MAX_TIME_OFFSET: i64 = 600;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CpModel {
+    /// `P = CP + W′ / t`
+    TwoParameter,
+    /// `P = CP + W′ / (t + W′ / (Pmax - CP))`, Morton's model which also fits the shortest efforts
+    ThreeParameter
+}
+
+pub const CP_MODELS: [CpModel; 2] = [CpModel::TwoParameter, CpModel::ThreeParameter];
+
+impl FromStr for CpModel {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        return match CP_MODELS.iter().find(|x| x.as_str() == value) {
+            Some(model) => Ok(*model),
+            None => bail!("Unknown critical power model '{}'", value)
+        };
+    }
+}
+
+impl CpModel {
+    pub fn as_str(&self) -> &str {
+        return match self {
+            CpModel::TwoParameter => "2",
+            CpModel::ThreeParameter => "3"
+        };
+    }
+
+    pub fn label(&self) -> &str {
+        return match self {
+            CpModel::TwoParameter => "2-parameter",
+            CpModel::ThreeParameter => "3-parameter"
+        };
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CriticalPowerFit {
+    pub athlete_id: i64,
+    pub model: CpModel,
+    /// End of the range the best power came from
+    pub fitted_at: i64,
+    /// Watts
+    pub critical_power: f64,
+    /// Joules
+    pub w_prime: f64,
+    /// Only fitted by the 3-parameter model
+    pub p_max: Option<f64>,
+    pub r_squared: f64,
+    /// Watts between the curve and the model
+    pub rmse: f64,
+    /// Latest threshold power a device recorded in the range
+    pub device_threshold_power: Option<i64>
+}
+
+impl CriticalPowerFit {
+    /// Watts the model predicts for an effort of the given seconds
+    pub fn power_at(&self, seconds: f64) -> f64 {
+        return match self.p_max {
+            Some(p_max) if p_max > self.critical_power => {
+                self.critical_power + self.w_prime / (seconds + self.w_prime / (p_max - self.critical_power))
+            },
+            _ => self.critical_power + self.w_prime / seconds
+        };
+    }
+
+    /// CP, W′, Pmax, fit quality and how CP compares with the device threshold
+    pub fn description(&self) -> Vec<String> {
+        let mut lines = vec![
+            format!("CP: {:.0}W W′: {:.1}kJ", self.critical_power, self.w_prime / 1000.0),
+            format!("R²: {:.3} RMSE: {:.1}W", self.r_squared, self.rmse)
+        ];
+
+        if let Some(p_max) = self.p_max {
+            lines.insert(1, format!("Pmax: {:.0}W", p_max));
+        }
+
+        lines.push(match self.device_threshold_power {
+            Some(ftp) if ftp > 0 => format!("Device FTP: {}W (CP {:+.0}W)", ftp, self.critical_power - ftp as f64),
+            _ => String::from("Device FTP: -")
+        });
+
+        return lines;
+    }
+}
+
+/// Fits the model to the curve, none when there are too few efforts or they don't give a positive CP and W′.
+/// The athlete, date and device threshold are left for the caller
+pub fn fit(model: CpModel, curve: &PowerCurve) -> Option<CriticalPowerFit> {
+    let (min_seconds, max_seconds) = match model {
+        CpModel::TwoParameter => TWO_PARAMETER_SECONDS,
+        CpModel::ThreeParameter => THREE_PARAMETER_SECONDS
+    };
+
+    let points: Vec<(f64, f64)> = curve.points.iter()
+        .filter(|x| x.seconds >= min_seconds && x.seconds <= max_seconds && x.power > 0.0)
+        .map(|x| (x.seconds as f64, x.power))
+        .collect();
+
+    let (critical_power, w_prime, p_max) = match model {
+        CpModel::TwoParameter => {
+            if points.len() < 3 {
+                return None;
+            }
+
+            // Work is linear in time, its slope is CP and its intercept W′
+            let (slope, intercept) = linear_fit(&points.iter().map(|(t, p)| (*t, p * t)).collect::<Vec<(f64, f64)>>())?;
+            (slope, intercept, None)
+        },
+        CpModel::ThreeParameter => {
+            if points.len() < 5 {
+                return None;
+            }
+
+            // Power is linear in 1 / (t + k) for a fixed time offset k, the offset with the least error wins
+            let (_, critical_power, w_prime, offset) = (1..=MAX_TIME_OFFSET)
+                .filter_map(|offset| {
+                    let transformed: Vec<(f64, f64)> = points.iter().map(|(t, p)| (1.0 / (t + offset as f64), *p)).collect();
+                    let (slope, intercept) = linear_fit(&transformed)?;
+                    let error: f64 = transformed.iter().map(|(x, p)| (p - (intercept + slope * x)).powi(2)).sum();
+                    return Some((error, intercept, slope, offset));
+                })
+                .min_by(|a, b| a.0.total_cmp(&b.0))?;
+
+            (critical_power, w_prime, Some(critical_power + w_prime / offset as f64))
+        }
+    };
+
+    if critical_power <= 0.0 || w_prime <= 0.0 {
+        return None;
+    }
+
+    let mut fit = CriticalPowerFit {
+        athlete_id: 0,
+        model,
+        fitted_at: 0,
+        critical_power,
+        w_prime,
+        p_max,
+        r_squared: 0.0,
+        rmse: 0.0,
+        device_threshold_power: None
+    };
+
+    let mean = points.iter().map(|x| x.1).sum::<f64>() / points.len() as f64;
+    let total: f64 = points.iter().map(|(_, p)| (p - mean).powi(2)).sum();
+    let residual: f64 = points.iter().map(|(t, p)| (p - fit.power_at(*t)).powi(2)).sum();
+    fit.r_squared = if total > 0.0 { 1.0 - residual / total } else { 1.0 };
+    fit.rmse = (residual / points.len() as f64).sqrt();
+
+    return Some(fit);
+}
+
+/// Fits of every model the curve allows, dated at the end of its range
+pub fn fit_models(athlete_id: i64, fitted_at: i64, curve: &PowerCurve, device_threshold_power: Option<i64>) -> Vec<CriticalPowerFit> {
+    return CP_MODELS.iter()
+        .filter_map(|model| fit(*model, curve))
+        .map(|x| CriticalPowerFit { athlete_id, fitted_at, device_threshold_power, ..x })
+        .collect();
+}
+
+/// Fit window ending with the local day of the timestamp
+pub fn fit_window(timestamp: i64) -> DateRange {
+    let date = local_date(timestamp);
+    return DateRange {
+        start: local_midnight(date - Duration::days(FIT_WINDOW_DAYS - 1)),
+        end: local_midnight(date + Duration::days(1))
+    };
+}
+
+/// Days whose fit window has the timestamp in it, their fits change with a session at that time
+pub fn fit_days(timestamp: i64) -> DateRange {
+    let date = local_date(timestamp);
+    return DateRange { start: local_midnight(date), end: local_midnight(date + Duration::days(FIT_WINDOW_DAYS)) };
+}
+
+/// Latest fit up to the day of the timestamp, the 3-parameter model when both were fitted that day
+pub fn fit_at(history: &[CriticalPowerFit], timestamp: i64) -> Option<&CriticalPowerFit> {
+    let end = fit_window(timestamp).end;
+    let latest = history.iter().filter(|x| x.fitted_at <= end).map(|x| x.fitted_at).max()?;
+
+    return history.iter()
+        .filter(|x| x.fitted_at == latest)
+        .max_by_key(|x| x.model == CpModel::ThreeParameter);
+}
+
+/// W′ left after every second of the power with Skiba's model, recovery below CP is faster the easier it is
+pub fn w_prime_balance(power: &[f64], critical_power: f64, w_prime: f64) -> Vec<f64> {
+    let below: Vec<f64> = power.iter().copied().filter(|x| *x < critical_power).collect();
+    let recovery_power = match below.len() {
+        0 => 0.0,
+        count => critical_power - below.iter().sum::<f64>() / count as f64
+    };
+    let time_constant = 546.0 * (-0.01 * recovery_power).exp() + 316.0;
+    let decay = (-1.0 / time_constant).exp();
+
+    // Work above CP recovers exponentially, so the sum of what's still missing only needs the second before
+    let mut expended = 0.0;
+
+    return power.iter()
+        .map(|value| {
+            expended = expended * decay + (value - critical_power).max(0.0);
+            return w_prime - expended;
+        })
+        .collect();
+}
+
+/// W′bal in joules at every record of the session, none when it has no power
+pub fn session_w_prime_balance(records: &[Record], fit: &CriticalPowerFit) -> Option<Vec<f64>> {
+    if records.iter().all(|x| x.power <= 0) {
+        return None;
+    }
+
+    let balance = w_prime_balance(&power::power_per_second(records), fit.critical_power, fit.w_prime);
+
+    return Some(power::record_seconds(records).iter()
+        .map(|second| second.and_then(|x| balance.get(x).copied()).unwrap_or(fit.w_prime))
+        .collect());
+}
+
+/// Threshold power the device recorded with the latest session in the range
+pub fn device_threshold_power(sessions: &[Session], range: &DateRange) -> Option<i64> {
+    return sessions.iter()
+        .filter(|x| x.start_time >= range.start && x.start_time < range.end && x.threshold_power > 0)
+        .max_by_key(|x| x.start_time)
+        .map(|x| x.threshold_power);
+}
+
+/// Simple least squares line through the points as slope and intercept
+fn linear_fit(points: &[(f64, f64)]) -> Option<(f64, f64)> {
+    let count = points.len() as f64;
+    let mean_x = points.iter().map(|x| x.0).sum::<f64>() / count;
+    let mean_y = points.iter().map(|x| x.1).sum::<f64>() / count;
+    let variance: f64 = points.iter().map(|(x, _)| (x - mean_x).powi(2)).sum();
+
+    if variance == 0.0 {
+        return None;
+    }
+
+    let slope = points.iter().map(|(x, y)| (x - mean_x) * (y - mean_y)).sum::<f64>() / variance;
+    return Some((slope, mean_y - slope * mean_x));
+}
+
+/// Handles `cp [history] [<period> | <date range>] [--model 2|3]`, the last 42 days when no range is given
+pub fn command(store: &dyn SessionStore, args: &[String]) -> Result<()> {
+    let athlete = Athlete::from_args(store, args)?;
+    let positional = positional_args(args);
+    let models: Vec<CpModel> = match flag_value(args, "--model") {
+        Some(value) => vec![value.parse::<CpModel>()?],
+        None => CP_MODELS.to_vec()
+    };
+
+    if positional.first().map(|x| x.as_str()) == Some("history") {
+        for fit in store.get_critical_power_history(athlete.id)?.iter().filter(|x| models.contains(&x.model)) {
+            println!("{} {}: {}", date_as_string(fit.fitted_at - 1), fit.model.label(), fit.description().join(", "));
+        }
+
+        return Ok(());
+    }
+
+    let range = match positional.first() {
+        None => CurvePeriod::Last42Days.range()?,
+        Some(value) => match value.parse::<CurvePeriod>() {
+            Ok(period) => period.range()?,
+            Err(_) => DateRange::from_args(&positional)?
+        }
+    };
+
+    let curve = store.get_power_curve(athlete.id, &range)?;
+    let device_ftp = device_threshold_power(&store.get_all_sessions(athlete.id)?, &range);
+    let fits = fit_models(athlete.id, range.end, &curve, device_ftp);
+
+    for model in models {
+        match fits.iter().find(|x| x.model == model) {
+            Some(fit) => println!("{}: {}", model.label(), fit.description().join(", ")),
+            None => println!("{}: not enough efforts in the range", model.label())
+        }
+    }
+
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::power_curve::{PowerCurvePoint, CURVE_DURATIONS};
+
+    fn curve(power_at: impl Fn(f64) -> f64) -> PowerCurve {
+        return PowerCurve {
+            points: CURVE_DURATIONS.iter()
+                .map(|x| PowerCurvePoint { seconds: *x, power: power_at(*x as f64), session_id: 1, achieved_at: 0 })
+                .collect()
+        };
+    }
+
+    #[test]
+    fn two_parameter_fit_recovers_cp_and_w_prime() {
+        let fit = fit(CpModel::TwoParameter, &curve(|t| 250.0 + 20000.0 / t)).unwrap();
+
+        assert!((fit.critical_power - 250.0).abs() < 0.01);
+        assert!((fit.w_prime - 20000.0).abs() < 1.0);
+        assert!(fit.r_squared > 0.999);
+    }
+
+    #[test]
+    fn three_parameter_fit_recovers_cp_w_prime_and_p_max() {
+        // W′ / (Pmax - CP) is 20 s
+        let fit = fit(CpModel::ThreeParameter, &curve(|t| 250.0 + 20000.0 / (t + 20.0))).unwrap();
+
+        assert!((fit.critical_power - 250.0).abs() < 0.01);
+        assert!((fit.w_prime - 20000.0).abs() < 1.0);
+        assert!((fit.p_max.unwrap() - 1250.0).abs() < 0.1);
+    }
+
+    #[test]
+    fn fit_needs_enough_efforts() {
+        let short = PowerCurve { points: curve(|t| 250.0 + 20000.0 / t).points.into_iter().filter(|x| x.seconds < 120).collect() };
+        assert!(fit(CpModel::TwoParameter, &short).is_none());
+    }
+
+    #[test]
+    fn w_prime_balance_drains_above_cp_and_recovers_below() {
+        let mut power = vec![300.0; 120];
+        power.extend(vec![100.0; 600]);
+        let balance = w_prime_balance(&power, 250.0, 20000.0);
+
+        // 50 W above CP for two minutes spends 6 kJ less what recovers meanwhile, 150 W under CP gives a 438 s time constant
+        let decay = (-1.0 / (546.0 * (-1.5f64).exp() + 316.0)).exp();
+        let expended = 50.0 * (1.0 - decay.powi(120)) / (1.0 - decay);
+        assert!(balance[..120].windows(2).all(|x| x[1] < x[0]));
+        assert!((balance[119] - (20000.0 - expended)).abs() < 0.01);
+        assert!(expended > 5000.0 && expended < 6000.0);
+
+        assert!(balance[120..].windows(2).all(|x| x[1] > x[0]));
+        assert!(balance[719] < 20000.0 && balance[719] > balance[119]);
+    }
+}
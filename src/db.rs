@@ -1,27 +1,277 @@
+use std::collections::BTreeSet;
+use std::io::{Read, Write};
+use std::ops::{Deref, DerefMut};
+use std::sync::Mutex;
+use std::time::Duration;
 use anyhow::Result;
 use anyhow::anyhow;
 use anyhow::bail;
-use rusqlite::Connection;
-
+use chrono::NaiveDate;
+use flate2::Compression;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use rusqlite::{Connection, OptionalExtension, Row, Transaction, TransactionBehavior, params};
+use itertools::Itertools;
+use uuid::Uuid;
+
+use crate::athlete::Athlete;
+use crate::critical_power::{self, CpModel, CriticalPowerFit};
+use crate::gear::{Bike, Component};
+use crate::import::ImportLogEntry;
 use crate::parser::Record;
 use crate::parser::Session;
 use crate::parser::Lap;
-use crate::summary::Summary;
+use crate::parser;
+use crate::power::{self, TrainingLoad};
+use crate::power_curve::{self, PowerCurve, PowerCurvePoint};
+use crate::personal_record::{self, PersonalRecord, PersonalRecordBoard, RecordKind, SessionBests};
+use crate::store::SessionStore;
+use crate::stream::RecordColumns;
+use crate::threshold::{Threshold, ThresholdHistory, ThresholdSource};
+use crate::summary::{Summary, DateRange, day_start, local_midnight};
+
+const DATABASE_PATH: &str = "test.db";
+
+/// How long a write waits for the dashboard or an import in another process before failing with "database is locked"
+const BUSY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Connections which have been set up before and are free to be used again
+static CONNECTIONS: Mutex<Vec<Connection>> = Mutex::new(Vec::new());
+
+/// Bumped every time a migration is added to `migrate`
+const SCHEMA_VERSION: i64 = 17;
+
+/// Original FIT file a session was imported from
+#[derive(Clone)]
+pub struct SourceFile {
+    pub file_name: String,
+    pub bytes: Vec<u8>
+}
+
+/// Sessions stored in the SQLite database
+#[derive(Default)]
+pub struct SqliteStore {
+    /// Athletes whose personal record boards are built again when the running import ends, none outside of imports
+    stale_boards: Option<BTreeSet<i64>>
+}
+
+impl SessionStore for SqliteStore {
+    fn insert_session(&mut self, session: Session, source_file: &SourceFile) -> Result<i64> {
+        return insert_session(session, source_file, self.stale_boards.as_mut());
+    }
+
+    fn replace_session(&mut self, existing: &Session, session: Session, source_file: &SourceFile) -> Result<i64> {
+        return replace_session(existing, session, source_file, self.stale_boards.as_mut());
+    }
+
+    fn update_session(&mut self, session: &Session) -> Result<()> {
+        return update_session(session);
+    }
+
+    fn delete_session(&mut self, session_id: i64) -> Result<()> {
+        return delete_session(session_id);
+    }
+
+    fn get_all_sessions(&self, athlete_id: i64) -> Result<Vec<Session>> {
+        return get_all_sessions(athlete_id);
+    }
+
+    fn get_session(&self, session_id: i64) -> Result<Session> {
+        return get_session(session_id);
+    }
+
+    fn get_session_details(&self, session: &Session) -> Result<Session> {
+        return get_session_details(session);
+    }
+
+    fn search_sessions(&self, athlete_id: i64, query: &str) -> Result<Vec<Session>> {
+        return search_sessions(athlete_id, query);
+    }
+
+    fn find_duplicate_session(&self, session: &Session) -> Result<Option<Session>> {
+        return find_duplicate_session(session);
+    }
+
+    fn get_overall_summary(&self, athlete_id: i64, range: &DateRange) -> Result<Summary> {
+        return get_overall_summary(athlete_id, range);
+    }
+
+    fn get_detailed_summary(&self, athlete_id: i64, range: &DateRange) -> Result<Vec<Summary>> {
+        return get_detailed_summary(athlete_id, range);
+    }
+
+    fn get_daily_summaries(&self, athlete_id: i64, range: &DateRange) -> Result<Vec<(i64, Summary)>> {
+        return get_daily_summaries(athlete_id, range);
+    }
+
+    fn get_bikes(&self) -> Result<Vec<Bike>> {
+        return get_bikes();
+    }
+
+    fn insert_bike(&mut self, name: &str, rule_sub_sport: Option<&String>, rule_serial_number: Option<i64>) -> Result<i64> {
+        return insert_bike(name, rule_sub_sport, rule_serial_number);
+    }
+
+    fn delete_bike(&mut self, bike_id: i64) -> Result<()> {
+        return delete_bike(bike_id);
+    }
+
+    fn apply_bike_rules(&mut self) -> Result<i64> {
+        return apply_bike_rules();
+    }
+
+    fn insert_component(&mut self, bike_id: i64, name: &str, installed_at: i64, service_distance: Option<f64>, service_time: Option<f64>) -> Result<i64> {
+        return insert_component(bike_id, name, installed_at, service_distance, service_time);
+    }
+
+    fn service_component(&mut self, component_id: i64, serviced_at: i64) -> Result<()> {
+        return service_component(component_id, serviced_at);
+    }
+
+    fn delete_component(&mut self, component_id: i64) -> Result<()> {
+        return delete_component(component_id);
+    }
+
+    fn get_athletes(&self) -> Result<Vec<Athlete>> {
+        return get_athletes();
+    }
+
+    fn insert_athlete(&mut self, name: &str) -> Result<i64> {
+        return insert_athlete(name);
+    }
+
+    fn delete_athlete(&mut self, athlete_id: i64) -> Result<()> {
+        return delete_athlete(athlete_id);
+    }
+
+    fn get_thresholds(&self, athlete_id: i64) -> Result<ThresholdHistory> {
+        return get_thresholds(&*open_connection()?, athlete_id);
+    }
+
+    fn insert_threshold(&mut self, threshold: &Threshold) -> Result<i64> {
+        return insert_threshold(threshold);
+    }
+
+    fn get_personal_records(&self, athlete_id: i64) -> Result<PersonalRecordBoard> {
+        return get_personal_records(&*open_connection()?, athlete_id);
+    }
+
+    fn get_power_curve(&self, athlete_id: i64, range: &DateRange) -> Result<PowerCurve> {
+        return get_power_curve(&*open_connection()?, athlete_id, range);
+    }
+
+    fn get_critical_power_history(&self, athlete_id: i64) -> Result<Vec<CriticalPowerFit>> {
+        return get_critical_power_history(athlete_id);
+    }
 
-fn open_connection() -> Result<Connection> {
-    let conn = match Connection::open("test.db") {
+    fn delete_threshold(&mut self, threshold_id: i64) -> Result<()> {
+        return delete_threshold(threshold_id);
+    }
+
+    fn get_archived_session_ids(&self) -> Result<Vec<i64>> {
+        return get_archived_session_ids();
+    }
+
+    fn get_source_file(&self, session_id: i64) -> Result<SourceFile> {
+        return get_source_file(session_id);
+    }
+
+    fn reprocess_session(&mut self, session_id: i64, session: Session) -> Result<()> {
+        return reprocess_session(session_id, session, self.stale_boards.as_mut());
+    }
+
+    fn begin_import(&mut self) {
+        self.stale_boards = Some(BTreeSet::new());
+    }
+
+    fn end_import(&mut self) -> Result<()> {
+        return rebuild_stale_boards(self.stale_boards.take().unwrap_or_default());
+    }
+
+    fn log_import(&mut self, entry: &ImportLogEntry) -> Result<i64> {
+        return log_import(entry);
+    }
+
+    fn get_import_log(&self, limit: i64) -> Result<Vec<ImportLogEntry>> {
+        return get_import_log(limit);
+    }
+}
+
+/// Connection borrowed from `CONNECTIONS`, it goes back there when dropped
+struct PooledConnection(Option<Connection>);
+
+impl Deref for PooledConnection {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        return self.0.as_ref().expect("Connection used after it was returned");
+    }
+}
+
+impl DerefMut for PooledConnection {
+    fn deref_mut(&mut self) -> &mut Connection {
+        return self.0.as_mut().expect("Connection used after it was returned");
+    }
+}
+
+impl Drop for PooledConnection {
+    fn drop(&mut self) {
+        if let (Some(connection), Ok(mut connections)) = (self.0.take(), CONNECTIONS.lock()) {
+            connections.push(connection);
+        }
+    }
+}
+
+/// Reuses a free connection, a new one is only opened when every connection is in use
+fn open_connection() -> Result<PooledConnection> {
+    let free = CONNECTIONS.lock().ok().and_then(|mut x| x.pop());
+
+    let connection = match free {
+        Some(value) => value,
+        None => new_connection()?
+    };
+
+    return Ok(PooledConnection(Some(connection)));
+}
+
+/// WAL lets the dashboard read while an import writes, writers wait for each other up to the busy timeout
+fn new_connection() -> Result<Connection> {
+    let conn = match Connection::open(DATABASE_PATH) {
         Ok(connection) => connection,
         Err(e) => bail!(e),
     };
+    conn.busy_timeout(BUSY_TIMEOUT)?;
+    conn.pragma_update_and_check(None, "journal_mode", "wal", |row| row.get::<_, String>(0))?;
+    conn.pragma_update(None, "synchronous", "normal")?;
+    conn.pragma_update(None, "foreign_keys", true)?;
+
     return Ok(conn);
 }
 
+/// Takes the write lock up front, a deferred transaction which reads before writing
+/// fails right away when another process wrote in between instead of waiting
+fn write_transaction(connection: &mut Connection) -> Result<Transaction<'_>> {
+    return Ok(connection.transaction_with_behavior(TransactionBehavior::Immediate)?);
+}
+
 pub fn create() -> Result<()> {
-    let connection = open_connection()?;
+    let mut connection = open_connection()?;
 
+    migrate(&mut connection)?;
+    create_tables(&connection)?;
+    create_indexes(&connection)?;
+    assign_default_athlete(&connection)?;
+
+    connection.pragma_update(None, "user_version", SCHEMA_VERSION)?;
+
+    return Ok(());
+}
+
+fn create_tables(connection: &Connection) -> Result<()> {
     connection.execute(
         "create table if not exists session (
             id integer primary key,
+            uuid text null,
             sport text not null,
             sub_sport text not null,
             avg_power integer null,
@@ -31,8 +281,24 @@ pub fn create() -> Result<()> {
             total_elapsed_time real not null,
             avg_cadence integer null,
             serial_number integer null,
-            start_time text not null,
-            threshold_power integer null
+            start_time integer not null,
+            threshold_power integer null,
+            file_hash text null,
+            title text null,
+            notes text null,
+            bike_id integer null,
+            athlete_id integer null,
+            total_ascent real null,
+            total_work real null,
+            normalized_power real null,
+            intensity_factor real null,
+            variability_index real null,
+            training_stress_score real null,
+            foreign key (bike_id)
+                references bike (id)
+                on delete set null,
+            foreign key (athlete_id)
+                references athlete (id)
         )",
         []
     )?;
@@ -42,441 +308,2174 @@ pub fn create() -> Result<()> {
             id integer primary key,
             avg_heart_rate integer null,
             avg_power integer null,
-            start_time text not null,
+            start_time integer not null,
             distance real null,
             total_moving_time real null,
             session_id integer not null,
             foreign key (session_id)
                 references session (id)
+                on delete cascade
+        )", [])?;
+
+    connection.execute(
+        "create table if not exists record_stream (
+            session_id integer primary key,
+            records_count integer not null,
+            timestamp blob not null,
+            heart_rate blob not null,
+            power blob not null,
+            cadence blob not null,
+            distance blob not null,
+            speed blob not null,
+            altitude blob not null,
+            position_lat blob not null,
+            position_long blob not null,
+            foreign key (session_id)
+                references session (id)
+                on delete cascade
+        )", [])?;
+
+    connection.execute(
+        "create table if not exists source_file (
+            id integer primary key,
+            session_id integer not null unique,
+            file_name text not null,
+            file_size integer not null,
+            data blob not null,
+            foreign key (session_id)
+                references session (id)
+                on delete cascade
         )", [])?;
 
-    connection.execute(
-        "create table if not exists record (
-            id integer primary key,
-            heart_rate integer null,
-            power integer null,
-            timestamp text not null,
-            distance real null,
-            session_id integer not null,
-            foreign key (session_id)
-                references session (id)
-        )", [])?;
+    connection.execute(
+        "create table if not exists athlete (
+            id integer primary key,
+            name text not null unique
+        )", [])?;
+
+    connection.execute(
+        "create table if not exists threshold (
+            id integer primary key,
+            athlete_id integer not null,
+            effective_from integer not null,
+            ftp integer null,
+            lthr integer null,
+            max_heart_rate integer null,
+            weight real null,
+            source text not null,
+            session_id integer null,
+            foreign key (athlete_id)
+                references athlete (id)
+                on delete cascade,
+            foreign key (session_id)
+                references session (id)
+                on delete set null
+        )", [])?;
+
+    connection.execute(
+        "create table if not exists personal_record (
+            id integer primary key,
+            athlete_id integer not null,
+            session_id integer not null,
+            kind text not null,
+            value real not null,
+            achieved_at integer not null,
+            foreign key (athlete_id)
+                references athlete (id)
+                on delete cascade,
+            foreign key (session_id)
+                references session (id)
+                on delete cascade
+        )", [])?;
+
+    connection.execute(
+        "create table if not exists session_best (
+            session_id integer not null,
+            kind text not null,
+            value real not null,
+            primary key (session_id, kind),
+            foreign key (session_id)
+                references session (id)
+                on delete cascade
+        )", [])?;
+
+    connection.execute(
+        "create table if not exists power_curve (
+            session_id integer not null,
+            duration integer not null,
+            power real not null,
+            primary key (session_id, duration),
+            foreign key (session_id)
+                references session (id)
+                on delete cascade
+        )", [])?;
+
+    connection.execute(
+        "create table if not exists critical_power (
+            id integer primary key,
+            athlete_id integer not null,
+            model text not null,
+            fitted_at integer not null,
+            critical_power real not null,
+            w_prime real not null,
+            p_max real null,
+            r_squared real not null,
+            rmse real not null,
+            device_threshold_power integer null,
+            unique (athlete_id, model, fitted_at),
+            foreign key (athlete_id)
+                references athlete (id)
+                on delete cascade
+        )", [])?;
+
+    connection.execute(
+        "create table if not exists import_log (
+            id integer primary key,
+            path text not null,
+            file_hash text null,
+            imported_at integer not null,
+            outcome text not null,
+            error text null,
+            session_id integer null,
+            foreign key (session_id)
+                references session (id)
+                on delete set null
+        )", [])?;
+
+    connection.execute(
+        "create table if not exists bike (
+            id integer primary key,
+            name text not null unique,
+            rule_sub_sport text null,
+            rule_serial_number integer null
+        )", [])?;
+
+    connection.execute(
+        "create table if not exists component (
+            id integer primary key,
+            bike_id integer not null,
+            name text not null,
+            installed_at integer not null,
+            serviced_at integer not null,
+            service_distance real null,
+            service_time real null,
+            foreign key (bike_id)
+                references bike (id)
+                on delete cascade
+        )", [])?;
+
+    connection.execute(
+        "create table if not exists session_tag (
+            session_id integer not null,
+            name text not null,
+            primary key (session_id, name),
+            foreign key (session_id)
+                references session (id)
+                on delete cascade
+        )", [])?;
+
+    create_search_index(connection)?;
+
+    return Ok(());
+}
+
+/// Created after the migrations, so every column they index exists by then
+fn create_indexes(connection: &Connection) -> Result<()> {
+    connection.execute("create unique index if not exists session_uuid_idx on session (uuid)", [])?;
+    connection.execute("create index if not exists session_start_time_idx on session (start_time)", [])?;
+    connection.execute("create index if not exists session_file_hash_idx on session (file_hash)", [])?;
+    connection.execute("create index if not exists session_bike_id_idx on session (bike_id)", [])?;
+    connection.execute("create index if not exists session_athlete_id_idx on session (athlete_id, start_time)", [])?;
+    connection.execute("create index if not exists lap_session_id_idx on lap (session_id)", [])?;
+    connection.execute("create index if not exists threshold_athlete_id_idx on threshold (athlete_id, effective_from)", [])?;
+    connection.execute("create index if not exists personal_record_athlete_id_idx on personal_record (athlete_id, achieved_at)", [])?;
+    connection.execute("create index if not exists personal_record_session_id_idx on personal_record (session_id)", [])?;
+    connection.execute("create index if not exists import_log_imported_at_idx on import_log (imported_at)", [])?;
+    connection.execute("create index if not exists import_log_session_id_idx on import_log (session_id)", [])?;
+
+    return Ok(());
+}
+
+/// Full text index over the session texts, rowid is the session id.
+/// Kept up to date by triggers so every write path to `session` and `session_tag` is covered
+fn create_search_index(connection: &Connection) -> Result<()> {
+    connection.execute_batch(
+        "create virtual table if not exists session_search using fts5 (title, notes, tags, sub_sport);
+
+        create trigger if not exists session_search_insert after insert on session begin
+            insert into session_search (rowid, title, notes, tags, sub_sport)
+            values (new.id, new.title, new.notes, '', new.sub_sport);
+        end;
+
+        create trigger if not exists session_search_update after update of title, notes, sub_sport on session begin
+            update session_search
+            set title = new.title, notes = new.notes, sub_sport = new.sub_sport
+            where rowid = new.id;
+        end;
+
+        create trigger if not exists session_search_delete after delete on session begin
+            delete from session_search where rowid = old.id;
+        end;
+
+        create trigger if not exists session_tag_insert after insert on session_tag begin
+            update session_search
+            set tags = (select group_concat(name, ' ') from session_tag where session_id = new.session_id)
+            where rowid = new.session_id;
+        end;
+
+        create trigger if not exists session_tag_delete after delete on session_tag begin
+            update session_search
+            set tags = coalesce((select group_concat(name, ' ') from session_tag where session_id = old.session_id), '')
+            where rowid = old.session_id;
+        end;")?;
+
+    return Ok(());
+}
+
+/// Steps are only ever appended, every step is recorded in `user_version` once it ran so an interrupted
+/// migration goes on with the step after the last finished one
+fn migrate(connection: &mut Connection) -> Result<()> {
+    let version: i64 = connection.query_row("pragma user_version", [], |row| row.get(0))?;
+    let has_sessions = table_exists(connection, "session")?;
+
+    if version < 1 && has_sessions {
+        migrate_to_typed_columns(connection)?;
+    }
+    set_user_version(connection, version, 1)?;
+
+    if version < 2 && has_sessions {
+        migrate_to_session_uuid(connection)?;
+    }
+    set_user_version(connection, version, 2)?;
+
+    if version < 3 && has_sessions && !column_exists(connection, "session", "file_hash")? {
+        connection.execute("alter table session add column file_hash text null", [])?;
+    }
+    set_user_version(connection, version, 3)?;
+
+    if version < 4 && has_sessions {
+        if !column_exists(connection, "session", "title")? {
+            connection.execute("alter table session add column title text null", [])?;
+        }
+
+        // Records are moved out of the `record` table in version 8
+        rebuild_tables(connection, &["lap", "source_file"])?;
+    }
+    set_user_version(connection, version, 4)?;
+
+    if version < 5 && has_sessions && !column_exists(connection, "session", "bike_id")? {
+        connection.execute(
+            "alter table session add column bike_id integer null references bike (id) on delete set null", [])?;
+    }
+    set_user_version(connection, version, 5)?;
+
+    if version < 6 && has_sessions {
+        if !column_exists(connection, "session", "notes")? {
+            connection.execute("alter table session add column notes text null", [])?;
+        }
+
+        create_tables(connection)?;
+        connection.execute(
+            "insert into session_search (rowid, title, notes, tags, sub_sport)
+            select id, title, notes, '', sub_sport
+            from session
+            where id not in (select rowid from session_search)", [])?;
+    }
+    set_user_version(connection, version, 6)?;
+
+    if version < 7 && has_sessions && !column_exists(connection, "session", "athlete_id")? {
+        connection.execute(
+            "alter table session add column athlete_id integer null references athlete (id)", [])?;
+    }
+    set_user_version(connection, version, 7)?;
+
+    if version < 8 && table_exists(connection, "record")? {
+        migrate_to_record_streams(connection)?;
+    }
+    set_user_version(connection, version, 8)?;
+
+    if version < 9 && has_sessions {
+        migrate_to_threshold_history(connection)?;
+    }
+    set_user_version(connection, version, 9)?;
+
+    if version < 10 && has_sessions {
+        migrate_to_personal_records(connection)?;
+    }
+    set_user_version(connection, version, 10)?;
+
+    if version < 11 && has_sessions {
+        if !column_exists(connection, "session", "total_ascent")? {
+            connection.execute_batch(
+                "alter table session add column total_ascent real null;
+                alter table session add column total_work real null;")?;
+        }
+
+        migrate_to_session_totals(connection)?;
+    }
+    set_user_version(connection, version, 11)?;
+
+    if version < 12 && has_sessions {
+        if !column_exists(connection, "session", "normalized_power")? {
+            connection.execute_batch(
+                "alter table session add column normalized_power real null;
+                alter table session add column intensity_factor real null;
+                alter table session add column variability_index real null;
+                alter table session add column training_stress_score real null;")?;
+        }
+
+        migrate_to_training_load(connection)?;
+    }
+    set_user_version(connection, version, 12)?;
+
+    if version < 13 && has_sessions {
+        migrate_to_power_curves(connection)?;
+    }
+    set_user_version(connection, version, 13)?;
+
+    // The critical power history of version 14 is fitted once by version 17, over the final power curves
+    if version < 14 && has_sessions {
+        create_tables(connection)?;
+    }
+    set_user_version(connection, version, 14)?;
+
+    if version < 15 && has_sessions {
+        migrate_to_session_bests(connection)?;
+    }
+    set_user_version(connection, version, 15)?;
+
+    if version < 16 && has_sessions {
+        migrate_to_filled_power(connection)?;
+    }
+    set_user_version(connection, version, 16)?;
+
+    // Fits are dated at local midnights from version 17, they were at midnight UTC before
+    if version < 17 && has_sessions {
+        migrate_to_critical_power(connection)?;
+    }
+    set_user_version(connection, version, 17)?;
+
+    return Ok(());
+}
+
+/// Records a finished migration step, versions are never lowered
+fn set_user_version(connection: &Connection, version: i64, step: i64) -> Result<()> {
+    if version < step {
+        connection.pragma_update(None, "user_version", step)?;
+    }
+
+    return Ok(());
+}
+
+/// Makes sure there is at least one athlete and gives sessions without one to the first athlete,
+/// which is how sessions imported before profiles existed end up in a profile
+fn assign_default_athlete(connection: &Connection) -> Result<()> {
+    connection.execute(
+        "insert into athlete (name) select 'Default' where not exists (select 1 from athlete)", [])?;
+    connection.execute(
+        "update session set athlete_id = (select min(id) from athlete) where athlete_id is null", [])?;
+
+    return Ok(());
+}
+
+fn table_exists(connection: &Connection, name: &str) -> Result<bool> {
+    let count: i64 = connection.query_row(
+        "select count(*) from sqlite_master where type = 'table' and name = ?1",
+        [name],
+        |row| row.get(0))?;
+
+    return Ok(count > 0);
+}
+
+fn column_exists(connection: &Connection, table: &str, column: &str) -> Result<bool> {
+    let count: i64 = connection.query_row(
+        "select count(*) from pragma_table_info(?1) where name = ?2",
+        [table, column],
+        |row| row.get(0))?;
+
+    return Ok(count > 0);
+}
+
+/// Recreates existing tables with their definition in `create_tables` and copies the rows over,
+/// for schema changes `alter table` can't do
+fn rebuild_tables(connection: &mut Connection, tables: &[&str]) -> Result<()> {
+    let transaction = write_transaction(connection)?;
+
+    let mut rebuilt: Vec<&str> = Vec::new();
+    for table in tables {
+        if table_exists(&transaction, table)? {
+            transaction.execute_batch(&format!("alter table {0} rename to {0}_old", table))?;
+            rebuilt.push(table);
+        }
+    }
+
+    create_tables(&transaction)?;
+
+    for table in rebuilt {
+        let columns = transaction
+            .prepare("select name from pragma_table_info(?1)")?
+            .query_map([format!("{}_old", table)], |row| row.get::<_, String>(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()?
+            .join(", ");
+
+        transaction.execute_batch(&format!(
+            "insert into {0} ({1}) select {1} from {0}_old;
+            drop table {0}_old;", table, columns))?;
+    }
+
+    transaction.commit()?;
+
+    return Ok(());
+}
+
+/// Tables created before version 1 stored every value as text
+fn migrate_to_typed_columns(connection: &mut Connection) -> Result<()> {
+    let transaction = write_transaction(connection)?;
+
+    transaction.execute_batch(
+        "alter table session rename to session_text;
+        alter table lap rename to lap_text;
+        alter table record rename to record_text;")?;
+
+    create_tables(&transaction)?;
+
+    transaction.execute_batch(
+        "insert into session (id, sport, sub_sport, avg_power, avg_heart_rate, total_distance, total_moving_time
+            , total_elapsed_time, avg_cadence, serial_number, start_time, threshold_power)
+        select id, sport, sub_sport
+            , cast(avg_power as integer)
+            , cast(avg_heart_rate as integer)
+            , cast(total_distance as real)
+            , cast(total_moving_time as real)
+            , cast(total_elapsed_time as real)
+            , cast(avg_cadence as integer)
+            , cast(serial_number as integer)
+            , cast(start_time as integer)
+            , cast(threshold_power as integer)
+        from session_text;
+
+        insert into lap (id, avg_heart_rate, avg_power, start_time, distance, total_moving_time, session_id)
+        select id
+            , cast(avg_heart_rate as integer)
+            , cast(avg_power as integer)
+            , cast(start_time as integer)
+            , cast(distance as real)
+            , cast(total_moving_time as real)
+            , cast(session_id as integer)
+        from lap_text;
+
+        create table record as
+        select id
+            , cast(heart_rate as integer) as heart_rate
+            , cast(power as integer) as power
+            , cast(timestamp as integer) as timestamp
+            , cast(distance as real) as distance
+            , cast(session_id as integer) as session_id
+        from record_text;
+
+        drop table record_text;
+        drop table lap_text;
+        drop table session_text;")?;
+
+    transaction.commit()?;
+
+    return Ok(());
+}
+
+/// Records were stored a row per second before version 8
+fn migrate_to_record_streams(connection: &mut Connection) -> Result<()> {
+    let transaction = write_transaction(connection)?;
+    create_tables(&transaction)?;
+
+    let session_ids = transaction
+        .prepare("select distinct cast(session_id as integer) from record where session_id in (select id from session)")?
+        .query_map([], |row| row.get::<_, i64>(0))?
+        .collect::<rusqlite::Result<Vec<i64>>>()?;
+
+    for session_id in session_ids {
+        let records = transaction
+            .prepare(
+                "select timestamp
+                    , heart_rate
+                    , power
+                    , distance
+                from record
+                where session_id = ?1
+                order by timestamp")?
+            .query_map([session_id], |row| {
+                Ok(Record {
+                    timestamp: row.get(0)?,
+                    heart_rate: row.get::<_, Option<i64>>(1)?.unwrap_or(0),
+                    power: row.get::<_, Option<i64>>(2)?.unwrap_or(0),
+                    cadence: 0,
+                    distance: row.get::<_, Option<f64>>(3)?.unwrap_or(0.0),
+                    speed: 0.0,
+                    altitude: 0.0,
+                    position_lat: 0,
+                    position_long: 0
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<Record>>>()?;
+
+        write_records(&transaction, session_id, &records)?;
+    }
+
+    transaction.execute("drop table record", [])?;
+    transaction.commit()?;
+
+    // Gives the space of the dropped rows back to the file system
+    connection.execute("vacuum", [])?;
+
+    return Ok(());
+}
+
+/// Thresholds were read from each session, or set per athlete in version 7, before version 9
+fn migrate_to_threshold_history(connection: &mut Connection) -> Result<()> {
+    let transaction = write_transaction(connection)?;
+    create_tables(&transaction)?;
+
+    if column_exists(&transaction, "athlete", "threshold_power")? {
+        transaction.execute_batch(
+            "insert into threshold (athlete_id, effective_from, ftp, source)
+            select id, 0, threshold_power, 'manual'
+            from athlete
+            where threshold_power is not null;
+
+            alter table athlete drop column threshold_power;")?;
+    }
+
+    assign_default_athlete(&transaction)?;
+
+    // Only the columns of version 9, the later ones don't exist yet
+    let sessions = transaction
+        .prepare("select id, start_time, threshold_power from session order by start_time")?
+        .query_map([], |row| Ok(Session {
+            id: row.get(0)?,
+            start_time: row.get(1)?,
+            threshold_power: row.get::<_, Option<i64>>(2)?.unwrap_or(0),
+            ..Session::default()
+        }))?
+        .collect::<rusqlite::Result<Vec<Session>>>()?;
+
+    for session in sessions {
+        record_device_thresholds(&transaction, session.id.unwrap_or(0), &session)?;
+    }
+
+    transaction.commit()?;
+
+    return Ok(());
+}
+
+/// Personal records are kept from version 10, sessions imported before are gone through oldest first
+fn migrate_to_personal_records(connection: &mut Connection) -> Result<()> {
+    let transaction = write_transaction(connection)?;
+    create_tables(&transaction)?;
+
+    // Only the columns of version 10, the ascent of these sessions comes from their records
+    let sessions = transaction
+        .prepare("select id, athlete_id, start_time, total_distance from session order by start_time")?
+        .query_map([], |row| Ok(Session {
+            id: row.get(0)?,
+            athlete_id: row.get(1)?,
+            start_time: row.get(2)?,
+            total_distance: row.get::<_, Option<f64>>(3)?.unwrap_or(0.0),
+            ..Session::default()
+        }))?
+        .collect::<rusqlite::Result<Vec<Session>>>()?;
+
+    let mut athletes: Vec<(i64, Vec<SessionBests>)> = Vec::new();
+
+    for mut session in sessions {
+        let session_id = session.id.unwrap_or(0);
+        let athlete_id = session.athlete_id.unwrap_or(0);
+        session.records = get_records_by_session_id(&transaction, session_id)?;
+
+        let bests = SessionBests { session_id, start_time: session.start_time, bests: personal_record::session_bests(&session) };
+        match athletes.iter_mut().find(|x| x.0 == athlete_id) {
+            Some((_, sessions)) => sessions.push(bests),
+            None => athletes.push((athlete_id, vec![bests]))
+        }
+    }
+
+    for (athlete_id, sessions) in athletes {
+        write_personal_records(&transaction, athlete_id, &PersonalRecordBoard::from_sessions(athlete_id, sessions))?;
+    }
+
+    transaction.commit()?;
+
+    return Ok(());
+}
+
+/// Elevation and work are kept from version 11, sessions imported before get them from their records.
+/// Reprocessing replaces them with the totals of the device where it recorded them
+fn migrate_to_session_totals(connection: &mut Connection) -> Result<()> {
+    let transaction = write_transaction(connection)?;
+
+    let session_ids = transaction
+        .prepare("select id from session where total_ascent is null or total_work is null")?
+        .query_map([], |row| row.get::<_, i64>(0))?
+        .collect::<rusqlite::Result<Vec<i64>>>()?;
+
+    for session_id in session_ids {
+        let records = get_records_by_session_id(&transaction, session_id)?;
+
+        transaction.execute(
+            "update session set total_ascent = ?2, total_work = ?3 where id = ?1",
+            params![session_id, parser::total_ascent(&records), power::total_work(&records)])?;
+    }
+
+    transaction.commit()?;
+
+    return Ok(());
+}
+
+/// Training load is kept from version 12, sessions imported before get it from their records
+fn migrate_to_training_load(connection: &mut Connection) -> Result<()> {
+    let transaction = write_transaction(connection)?;
+
+    let sessions = transaction
+        .prepare(&format!("select {} from session where normalized_power is null", SESSION_COLUMNS))?
+        .query_map([], session_from_row)?
+        .collect::<rusqlite::Result<Vec<Session>>>()?;
+
+    for session in &sessions {
+        let session_id = session.id.unwrap_or(0);
+        let records = get_records_by_session_id(&transaction, session_id)?;
+        let training_load = TrainingLoad::from_records(&records, session.avg_power);
+
+        transaction.execute(
+            "update session set normalized_power = ?2, variability_index = ?3 where id = ?1",
+            params![session_id, training_load.normalized_power, training_load.variability_index])?;
+    }
+
+    let athlete_ids: Vec<i64> = transaction
+        .prepare("select distinct athlete_id from session where athlete_id is not null")?
+        .query_map([], |row| row.get(0))?
+        .collect::<rusqlite::Result<Vec<i64>>>()?;
+
+    for athlete_id in athlete_ids {
+        update_training_loads(&transaction, athlete_id, i64::MIN)?;
+    }
+
+    transaction.commit()?;
+
+    return Ok(());
+}
+
+/// Power curves are cached from version 13, sessions imported before get theirs from their records
+fn migrate_to_power_curves(connection: &mut Connection) -> Result<()> {
+    let transaction = write_transaction(connection)?;
+    create_tables(&transaction)?;
+
+    let session_ids = transaction
+        .prepare("select id from session where id not in (select session_id from power_curve)")?
+        .query_map([], |row| row.get::<_, i64>(0))?
+        .collect::<rusqlite::Result<Vec<i64>>>()?;
+
+    for session_id in session_ids {
+        let records = get_records_by_session_id(&transaction, session_id)?;
+        write_power_curve(&transaction, session_id, &records)?;
+    }
+
+    transaction.commit()?;
+
+    return Ok(());
+}
+
+/// Bests of every session are kept from version 15 and the boards are built again from them
+fn migrate_to_session_bests(connection: &mut Connection) -> Result<()> {
+    let transaction = write_transaction(connection)?;
+    create_tables(&transaction)?;
+
+    let sessions = transaction
+        .prepare(&format!("select {} from session", SESSION_COLUMNS))?
+        .query_map([], session_from_row)?
+        .collect::<rusqlite::Result<Vec<Session>>>()?;
+
+    for mut session in sessions {
+        let session_id = session.id.unwrap_or(0);
+        session.records = get_records_by_session_id(&transaction, session_id)?;
+        write_session_bests(&transaction, session_id, &session)?;
+    }
+
+    let athlete_ids = transaction
+        .prepare("select distinct athlete_id from session where athlete_id is not null")?
+        .query_map([], |row| row.get::<_, i64>(0))?
+        .collect::<rusqlite::Result<Vec<i64>>>()?;
+
+    for athlete_id in athlete_ids {
+        rebuild_personal_records(&transaction, athlete_id)?;
+    }
+
+    transaction.commit()?;
+
+    return Ok(());
+}
+
+/// Power series fill the gaps of smart recording from version 16, everything calculated from them is calculated again.
+/// Work is left as it is because the device value can't be told apart, reprocessing recalculates it.
+/// Critical power is fitted again from the new curves by version 17
+fn migrate_to_filled_power(connection: &mut Connection) -> Result<()> {
+    let transaction = write_transaction(connection)?;
+
+    let sessions = transaction
+        .prepare(&format!("select {} from session", SESSION_COLUMNS))?
+        .query_map([], session_from_row)?
+        .collect::<rusqlite::Result<Vec<Session>>>()?;
+
+    for mut session in sessions {
+        let session_id = session.id.unwrap_or(0);
+        session.records = get_records_by_session_id(&transaction, session_id)?;
+        let training_load = TrainingLoad::from_records(&session.records, session.avg_power);
+
+        transaction.execute(
+            "update session set normalized_power = ?2, variability_index = ?3 where id = ?1",
+            params![session_id, training_load.normalized_power, training_load.variability_index])?;
+        write_power_curve(&transaction, session_id, &session.records)?;
+        write_session_bests(&transaction, session_id, &session)?;
+    }
+
+    let athlete_ids: Vec<i64> = transaction
+        .prepare("select distinct athlete_id from session where athlete_id is not null")?
+        .query_map([], |row| row.get(0))?
+        .collect::<rusqlite::Result<Vec<i64>>>()?;
+
+    for athlete_id in athlete_ids {
+        update_training_loads(&transaction, athlete_id, i64::MIN)?;
+        rebuild_personal_records(&transaction, athlete_id)?;
+    }
+
+    transaction.commit()?;
+
+    return Ok(());
+}
+
+/// Critical power is fitted for every day with sessions, dated at local midnights from version 17
+fn migrate_to_critical_power(connection: &mut Connection) -> Result<()> {
+    let transaction = write_transaction(connection)?;
+    create_tables(&transaction)?;
+
+    let athlete_ids: Vec<i64> = transaction
+        .prepare("select distinct athlete_id from session where athlete_id is not null")?
+        .query_map([], |row| row.get(0))?
+        .collect::<rusqlite::Result<Vec<i64>>>()?;
+
+    for athlete_id in athlete_ids {
+        update_critical_power(&transaction, athlete_id, &DateRange { start: 0, end: i64::MAX })?;
+    }
+
+    transaction.commit()?;
+
+    return Ok(());
+}
+
+/// Sessions stored before version 2 have no uuid
+fn migrate_to_session_uuid(connection: &mut Connection) -> Result<()> {
+    let transaction = write_transaction(connection)?;
+
+    if !column_exists(&transaction, "session", "uuid")? {
+        transaction.execute("alter table session add column uuid text null", [])?;
+    }
+
+    let session_ids = transaction
+        .prepare("select id from session where uuid is null")?
+        .query_map([], |row| row.get::<_, i64>(0))?
+        .collect::<rusqlite::Result<Vec<i64>>>()?;
+
+    for session_id in session_ids {
+        transaction.execute(
+            "update session set uuid = ?1 where id = ?2",
+            params![Uuid::new_v4().to_string(), session_id])?;
+    }
+
+    transaction.commit()?;
+
+    return Ok(());
+}
+
+fn insert_session(session: Session, source_file: &SourceFile, stale_boards: Option<&mut BTreeSet<i64>>) -> Result<i64> {
+    let mut connection = open_connection()?;
+    let transaction = write_transaction(&mut connection)?;
+
+    let session_id = write_session(&transaction, &session)?;
+    write_laps_and_records(&transaction, session_id, &session)?;
+    write_source_file(&transaction, session_id, source_file)?;
+    assign_bikes_by_rules(&transaction, Some(session_id))?;
+    let threshold_added = record_device_thresholds(&transaction, session_id, &session)?;
+    record_training_load(&transaction, session_id, threshold_added)?;
+    record_personal_records(&transaction, session_id, stale_boards)?;
+    record_critical_power(&transaction, session_id)?;
+
+    transaction.commit()?;
+
+    return Ok(session_id);
+}
+
+/// Replaces the data of an existing session with a new import of it, keeping its id and uuid
+fn replace_session(existing: &Session, session: Session, source_file: &SourceFile, stale_boards: Option<&mut BTreeSet<i64>>) -> Result<i64> {
+    let mut connection = open_connection()?;
+    let transaction = write_transaction(&mut connection)?;
+
+    let session_id = existing.id.unwrap_or(0);
+    update_session_data(&transaction, session_id, &session)?;
+    write_source_file(&transaction, session_id, source_file)?;
+    record_training_load(&transaction, session_id, false)?;
+    rebuild_session_board(&transaction, session_id, stale_boards)?;
+    record_critical_power(&transaction, session_id)?;
+
+    transaction.commit()?;
+
+    return Ok(session_id);
+}
+
+/// Updates the fields read from the FIT file after it has been parsed again
+fn reprocess_session(session_id: i64, session: Session, stale_boards: Option<&mut BTreeSet<i64>>) -> Result<()> {
+    let mut connection = open_connection()?;
+    let transaction = write_transaction(&mut connection)?;
+
+    update_session_data(&transaction, session_id, &session)?;
+    let threshold_added = record_device_thresholds(&transaction, session_id, &session)?;
+    record_training_load(&transaction, session_id, threshold_added)?;
+    rebuild_session_board(&transaction, session_id, stale_boards)?;
+    record_critical_power(&transaction, session_id)?;
+
+    transaction.commit()?;
+
+    return Ok(());
+}
+
+fn write_session(transaction: &Transaction, session: &Session) -> Result<i64> {
+    let insert_session = transaction.execute(
+        "insert into session (uuid
+            , sport
+            , sub_sport
+            , avg_power
+            , avg_heart_rate
+            , total_distance
+            , total_moving_time
+            , total_elapsed_time
+            , avg_cadence
+            , serial_number
+            , start_time
+            , threshold_power
+            , file_hash
+            , athlete_id
+            , total_ascent
+            , total_work
+            , normalized_power
+            , variability_index)
+            values (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, coalesce(?14, (select min(id) from athlete)), ?15, ?16, ?17, ?18)"
+        , params![session.uuid.clone().unwrap_or_else(|| Uuid::new_v4().to_string())
+            , session.sport
+            , session.sub_sport
+            , session.avg_power
+            , session.avg_heart_rate
+            , session.total_distance
+            , session.total_moving_time
+            , session.total_elapsed_time
+            , session.avg_cadence
+            , session.serial_num
+            , session.start_time
+            , session.threshold_power
+            , session.file_hash
+            , session.athlete_id
+            , session.total_ascent
+            , session.total_work
+            , session.training_load.normalized_power
+            , session.training_load.variability_index]);
+
+    if insert_session.is_err() {
+        return Err(anyhow!("Could not insert session!"));
+    }
+
+    return Ok(transaction.last_insert_rowid());
+}
+
+/// Sub sport is left alone as it can be corrected by the user
+fn update_session_data(transaction: &Transaction, session_id: i64, session: &Session) -> Result<()> {
+    let updated = transaction.execute(
+        "update session set sport = ?2
+            , avg_power = ?3
+            , avg_heart_rate = ?4
+            , total_distance = ?5
+            , total_moving_time = ?6
+            , total_elapsed_time = ?7
+            , avg_cadence = ?8
+            , serial_number = ?9
+            , start_time = ?10
+            , threshold_power = ?11
+            , file_hash = ?12
+            , total_ascent = ?13
+            , total_work = ?14
+            , normalized_power = ?15
+            , variability_index = ?16
+        where id = ?1"
+        , params![session_id
+            , session.sport
+            , session.avg_power
+            , session.avg_heart_rate
+            , session.total_distance
+            , session.total_moving_time
+            , session.total_elapsed_time
+            , session.avg_cadence
+            , session.serial_num
+            , session.start_time
+            , session.threshold_power
+            , session.file_hash
+            , session.total_ascent
+            , session.total_work
+            , session.training_load.normalized_power
+            , session.training_load.variability_index])?;
+
+    if updated == 0 {
+        bail!("Session {} does not exist", session_id);
+    }
+
+    transaction.execute("delete from lap where session_id = ?1", [session_id])?;
+    write_laps_and_records(transaction, session_id, session)?;
+
+    return Ok(());
+}
+
+fn write_laps_and_records(transaction: &Transaction, session_id: i64, session: &Session) -> Result<()> {
+    write_records(transaction, session_id, &session.records)?;
+    write_power_curve(transaction, session_id, &session.records)?;
+    write_session_bests(transaction, session_id, session)?;
+
+    for lap in &session.laps {
+        transaction.execute(
+            "insert into lap (
+                  avg_heart_rate
+                , avg_power
+                , start_time
+                , distance
+                , total_moving_time
+                , session_id
+            ) values (?1, ?2, ?3, ?4, ?5, ?6)"
+            , params![lap.avg_heart_rate
+                , lap.avg_power
+                , lap.start_time
+                , lap.total_distance
+                , lap.total_moving_time
+                , session_id])?;
+    }
+
+    return Ok(());
+}
+
+/// All records of a session go into one row, replacing the previous ones
+fn write_records(connection: &Connection, session_id: i64, records: &[Record]) -> Result<()> {
+    let columns = RecordColumns::from_records(records)?;
+
+    connection.execute(
+        "insert or replace into record_stream (
+              session_id
+            , records_count
+            , timestamp
+            , heart_rate
+            , power
+            , cadence
+            , distance
+            , speed
+            , altitude
+            , position_lat
+            , position_long
+        ) values (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)"
+        , params![session_id
+            , columns.count
+            , columns.timestamp
+            , columns.heart_rate
+            , columns.power
+            , columns.cadence
+            , columns.distance
+            , columns.speed
+            , columns.altitude
+            , columns.position_lat
+            , columns.position_long])?;
+
+    return Ok(());
+}
+
+fn record_critical_power(connection: &Connection, session_id: i64) -> Result<()> {
+    let (athlete_id, start_time): (i64, i64) = connection.query_row(
+        "select athlete_id, start_time from session where id = ?1", [session_id], |row| Ok((row.get(0)?, row.get(1)?)))?;
+
+    return update_critical_power(connection, athlete_id, &critical_power::fit_days(start_time));
+}
+
+/// Fits the models again for every day in the range, days without sessions have no fit
+fn update_critical_power(connection: &Connection, athlete_id: i64, days: &DateRange) -> Result<()> {
+    connection.execute(
+        "delete from critical_power where athlete_id = ?1 and fitted_at > ?2 and fitted_at <= ?3",
+        params![athlete_id, days.start, days.end])?;
+
+    let session_days = connection
+        .prepare(
+            "select distinct start_time
+            from session
+            where athlete_id = ?1
+                and start_time >= ?2
+                and start_time < ?3")?
+        .query_map([athlete_id, days.start, days.end], |row| row.get::<_, i64>(0))?
+        .collect::<rusqlite::Result<Vec<i64>>>()?
+        .into_iter()
+        .map(day_start)
+        .sorted()
+        .dedup();
+
+    for day in session_days {
+        let window = critical_power::fit_window(day);
+        let curve = get_power_curve(connection, athlete_id, &window)?;
+        let device_threshold_power: Option<i64> = connection.query_row(
+            "select threshold_power
+            from session
+            where athlete_id = ?1
+                and start_time >= ?2
+                and start_time < ?3
+                and threshold_power > 0
+            order by start_time desc
+            limit 1",
+            [athlete_id, window.start, window.end],
+            |row| row.get(0))
+            .optional()?;
+
+        for fit in critical_power::fit_models(athlete_id, window.end, &curve, device_threshold_power) {
+            connection.execute(
+                "insert into critical_power (
+                      athlete_id
+                    , model
+                    , fitted_at
+                    , critical_power
+                    , w_prime
+                    , p_max
+                    , r_squared
+                    , rmse
+                    , device_threshold_power
+                ) values (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)"
+                , params![fit.athlete_id
+                    , fit.model.as_str()
+                    , fit.fitted_at
+                    , fit.critical_power
+                    , fit.w_prime
+                    , fit.p_max
+                    , fit.r_squared
+                    , fit.rmse
+                    , fit.device_threshold_power])?;
+        }
+    }
+
+    return Ok(());
+}
+
+/// Fits of every day with sessions, oldest first
+fn get_critical_power_history(athlete_id: i64) -> Result<Vec<CriticalPowerFit>> {
+    let conn = open_connection()?;
+
+    let mut query = conn.prepare(
+        "select athlete_id
+            , model
+            , fitted_at
+            , critical_power
+            , w_prime
+            , p_max
+            , r_squared
+            , rmse
+            , device_threshold_power
+        from critical_power
+        where athlete_id = ?1
+        order by fitted_at, model")?;
+
+    let fits = query
+        .query_map([athlete_id], |row| {
+            let model_col: String = row.get(1)?;
+
+            // Models this version doesn't know are left out
+            let model = match model_col.parse::<CpModel>() {
+                Ok(value) => value,
+                Err(_) => return Ok(None)
+            };
+
+            Ok(Some(CriticalPowerFit {
+                athlete_id: row.get(0)?,
+                model,
+                fitted_at: row.get(2)?,
+                critical_power: row.get(3)?,
+                w_prime: row.get(4)?,
+                p_max: row.get(5)?,
+                r_squared: row.get(6)?,
+                rmse: row.get(7)?,
+                device_threshold_power: row.get(8)?
+            }))
+        })?
+        .collect::<rusqlite::Result<Vec<Option<CriticalPowerFit>>>>()?;
+
+    return Ok(fits.into_iter().flatten().collect());
+}
+
+/// Caches the mean-maximal power of the records, replacing the previous curve
+fn write_power_curve(connection: &Connection, session_id: i64, records: &[Record]) -> Result<()> {
+    connection.execute("delete from power_curve where session_id = ?1", [session_id])?;
+
+    for (seconds, power) in power_curve::session_curve(records) {
+        connection.execute(
+            "insert into power_curve (session_id, duration, power) values (?1, ?2, ?3)",
+            params![session_id, seconds, power])?;
+    }
+
+    return Ok(());
+}
+
+/// Best cached power of every duration over the sessions in the range
+fn get_power_curve(connection: &Connection, athlete_id: i64, range: &DateRange) -> Result<PowerCurve> {
+    // SQLite takes the bare columns from the row with the max power
+    let mut query = connection.prepare(
+        "select power_curve.duration
+            , max(power_curve.power)
+            , power_curve.session_id
+            , session.start_time
+        from power_curve
+        inner join session on session.id = power_curve.session_id
+        where session.athlete_id = ?1
+            and session.start_time >= ?2
+            and session.start_time < ?3
+        group by power_curve.duration
+        order by power_curve.duration")?;
+
+    let points = query
+        .query_map([athlete_id, range.start, range.end], |row| {
+            Ok(PowerCurvePoint {
+                seconds: row.get(0)?,
+                power: row.get(1)?,
+                session_id: row.get(2)?,
+                achieved_at: row.get(3)?
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<PowerCurvePoint>>>()?;
+
+    return Ok(PowerCurve { points });
+}
+
+fn write_source_file(transaction: &Transaction, session_id: i64, source_file: &SourceFile) -> Result<()> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::best());
+    encoder.write_all(&source_file.bytes)?;
+    let compressed = encoder.finish()?;
+
+    transaction.execute(
+        "insert or replace into source_file (
+              session_id
+            , file_name
+            , file_size
+            , data
+        ) values (?1, ?2, ?3, ?4)"
+        , params![session_id
+            , source_file.file_name
+            , source_file.bytes.len() as i64
+            , compressed])?;
+
+    return Ok(());
+}
+
+/// Ids of sessions which have their original FIT file archived
+fn get_archived_session_ids() -> Result<Vec<i64>> {
+    let conn = open_connection()?;
+
+    let mut query = conn.prepare("select session_id from source_file order by session_id")?;
+    let session_ids = query
+        .query_map([], |row| row.get::<_, i64>(0))?
+        .collect::<rusqlite::Result<Vec<i64>>>()?;
+
+    return Ok(session_ids);
+}
+
+fn get_source_file(session_id: i64) -> Result<SourceFile> {
+    let conn = open_connection()?;
+
+    let (file_name, compressed): (String, Vec<u8>) = conn.query_row(
+        "select file_name, data from source_file where session_id = ?1",
+        [session_id],
+        |row| Ok((row.get(0)?, row.get(1)?)))?;
+
+    let mut bytes = Vec::new();
+    ZlibDecoder::new(compressed.as_slice()).read_to_end(&mut bytes)?;
+
+    return Ok(SourceFile { file_name, bytes });
+}
+
+const SESSION_COLUMNS: &str = "id
+    , uuid
+    , sport
+    , sub_sport
+    , avg_power
+    , avg_heart_rate
+    , total_distance
+    , total_moving_time
+    , total_elapsed_time
+    , avg_cadence
+    , serial_number
+    , start_time
+    , threshold_power
+    , file_hash
+    , title
+    , notes
+    , bike_id
+    , athlete_id
+    , (select group_concat(name, ',') from session_tag where session_id = session.id)
+    , total_ascent
+    , total_work
+    , normalized_power
+    , intensity_factor
+    , variability_index
+    , training_stress_score";
+
+/// Maps a row selected with `SESSION_COLUMNS` into a session header
+fn session_from_row(row: &Row) -> rusqlite::Result<Session> {
+    let session_id: i64 = row.get(0)?;
+    let uuid_col: Option<String> = row.get(1)?;
+    let sport_col: String = row.get(2)?;
+    let sub_sport_col: String = row.get(3)?;
+    let avg_power_col: Option<i64> = row.get(4)?;
+    let avg_heart_rate_col: Option<i64> = row.get(5)?;
+    let total_distance_col: Option<f64> = row.get(6)?;
+    let total_moving_time_col: Option<f64> = row.get(7)?;
+    let total_elapsed_time_col: f64 = row.get(8)?;
+    let avg_cadence_col: Option<i64> = row.get(9)?;
+    let serial_num_col: Option<i64> = row.get(10)?;
+    let start_time_col: i64 = row.get(11)?;
+    let threshold_power_col: Option<i64> = row.get(12)?;
+    let file_hash_col: Option<String> = row.get(13)?;
+    let title_col: Option<String> = row.get(14)?;
+    let notes_col: Option<String> = row.get(15)?;
+    let bike_id_col: Option<i64> = row.get(16)?;
+    let athlete_id_col: Option<i64> = row.get(17)?;
+    let tags_col: Option<String> = row.get(18)?;
+    let total_ascent_col: Option<f64> = row.get(19)?;
+    let total_work_col: Option<f64> = row.get(20)?;
+    let training_load = TrainingLoad {
+        normalized_power: row.get::<_, Option<f64>>(21)?.unwrap_or(0.0),
+        intensity_factor: row.get::<_, Option<f64>>(22)?.unwrap_or(0.0),
+        variability_index: row.get::<_, Option<f64>>(23)?.unwrap_or(0.0),
+        training_stress_score: row.get::<_, Option<f64>>(24)?.unwrap_or(0.0)
+    };
+
+    return Ok(Session {
+        id: Some(session_id),
+        uuid: uuid_col,
+        sport: sport_col,
+        sub_sport: sub_sport_col,
+        avg_power: avg_power_col.unwrap_or(0),
+        avg_heart_rate: avg_heart_rate_col.unwrap_or(0),
+        total_distance: total_distance_col.unwrap_or(0.0),
+        total_moving_time: total_moving_time_col.unwrap_or(0.0),
+        total_elapsed_time: total_elapsed_time_col,
+        avg_cadence: avg_cadence_col.unwrap_or(0),
+        total_ascent: total_ascent_col.unwrap_or(0.0),
+        total_work: total_work_col.unwrap_or(0.0),
+        training_load,
+        serial_num: serial_num_col.unwrap_or(0),
+        start_time: start_time_col,
+        threshold_power: threshold_power_col.unwrap_or(0),
+        file_hash: file_hash_col,
+        title: title_col,
+        notes: notes_col,
+        tags: tags_col
+            .map(|x| x.split(',').map(String::from).collect())
+            .unwrap_or_default(),
+        bike_id: bike_id_col,
+        athlete_id: athlete_id_col,
+        threshold_heart_rate: 0,
+        max_heart_rate: 0,
+        weight: 0.0,
+        laps: Vec::new(),
+        records: Vec::new()
+    });
+}
+
+/// Gets session headers only, laps and records are loaded with `get_session_details`
+fn get_all_sessions(athlete_id: i64) -> Result<Vec<Session>> {
+    let conn = open_connection()?;
+
+    let mut query = conn.prepare(&format!(
+        "select {} from session where athlete_id = ?1 order by start_time desc", SESSION_COLUMNS))?;
+
+    let query_result = query.query_map([athlete_id], session_from_row)?;
+
+    let sessions = query_result.collect::<rusqlite::Result<Vec<Session>>>()?;
 
-    return Ok(());
+    return Ok(sessions);
 }
 
-pub fn insert_session(session: Session) -> Result<i64> {
-    if session_exists(session.clone())? {
-        return Err(anyhow!("Session already exists during this time period!"));
-    }
+/// Gets a session header by id
+fn get_session(session_id: i64) -> Result<Session> {
+    let conn = open_connection()?;
 
-    let connection = open_connection()?;
+    let session = conn.query_row(
+        &format!("select {} from session where id = ?1", SESSION_COLUMNS),
+        [session_id],
+        session_from_row);
 
-    let insert_session = connection.execute(
-        "insert into session (sport
-            , sub_sport
-            , avg_power
-            , avg_heart_rate
-            , total_distance
-            , total_moving_time
-            , total_elapsed_time
-            , avg_cadence
-            , serial_number
-            , start_time
-            , threshold_power)
-            values (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)"
-        , [session.sport
-            , session.sub_sport
-            , session.avg_power.to_string()
-            , session.avg_heart_rate.to_string()
-            , session.total_distance.to_string()
-            , session.total_moving_time.to_string()
-            , session.total_elapsed_time.to_string()
-            , session.avg_cadence.to_string()
-            , session.serial_num.to_string()
-            , session.start_time.to_string()
-            , session.threshold_power.to_string()]);
+    return match session {
+        Ok(value) => Ok(value),
+        Err(rusqlite::Error::QueryReturnedNoRows) => bail!("Session {} does not exist", session_id),
+        Err(e) => bail!(e)
+    };
+}
 
-    if insert_session.is_err() {
-        return Err(anyhow!("Could not insert session!"));
-    }
+/// Saves the fields which can be edited by the user, the rest come from the FIT file
+fn update_session(session: &Session) -> Result<()> {
+    let mut connection = open_connection()?;
+    let transaction = write_transaction(&mut connection)?;
 
-    let session_id = connection.last_insert_rowid();
+    let title = session.title.as_deref().filter(|x| !x.trim().is_empty());
+    let notes = session.notes.as_deref().filter(|x| !x.trim().is_empty());
 
-    let laps = session.laps;
-    for lap in laps {
-        connection.execute(
-            "insert into lap (
-                  avg_heart_rate
-                , avg_power
-                , start_time
-                , distance
-                , total_moving_time
-                , session_id
-            ) values (?1, ?2, ?3, ?4, ?5, ?6)"
-            , [lap.avg_heart_rate.to_string()
-                , lap.avg_power.to_string()
-                , lap.start_time.to_string()
-                , lap.total_distance.to_string()
-                , lap.total_moving_time.to_string()
-                , session_id.to_string()])?;
+    let updated = transaction.execute(
+        "update session set sub_sport = ?2, title = ?3, notes = ?4, bike_id = ?5 where id = ?1",
+        params![session.id, session.sub_sport, title, notes, session.bike_id])?;
+
+    if updated == 0 {
+        bail!("Session {} does not exist", session.id.unwrap_or(0));
     }
 
-    let records = session.records;
-    for record in records {
-        connection.execute(
-            "insert into record (
-                  heart_rate
-                , power
-                , timestamp
-                , distance
-                , session_id
-            ) values (?1, ?2, ?3, ?4, ?5)"
-            , [record.heart_rate.to_string()
-                , record.power.to_string()
-                , record.timestamp.to_string()
-                , record.distance.to_string()
-                , session_id.to_string()])?;
+    transaction.execute("delete from session_tag where session_id = ?1", [session.id])?;
+    for tag in &session.tags {
+        transaction.execute(
+            "insert or ignore into session_tag (session_id, name) values (?1, ?2)",
+            params![session.id, tag])?;
     }
 
-    return Ok(session_id);
+    transaction.commit()?;
+
+    return Ok(());
 }
 
-pub fn get_all_sessions() -> Result<Vec<Session>> {
+/// Gets the headers of sessions whose title, notes, tags or sub sport contain every word of the query
+fn search_sessions(athlete_id: i64, query: &str) -> Result<Vec<Session>> {
     let conn = open_connection()?;
 
-    let mut query = conn.prepare(
-        "select id 
-            , sport
-            , sub_sport
-            , avg_power
-            , avg_heart_rate
-            , total_distance
-            , total_moving_time
-            , total_elapsed_time
-            , avg_cadence
-            , serial_number
-            , start_time
-            , threshold_power
-        from session order by start_time desc")?;
-
-    let query_result = query.query_map([], |row| {
-        let session_id: i16 = match row.get(0) {
-            Ok(value) => value,
-            Err(_) => 0
-        };
-
-        let sport_col: String = match row.get(1) {
-            Ok(value) => value,
-            Err(_) => String::from("")
-        };
-
-        let sub_sport_col: String = match row.get(2) {
-            Ok(value) => value,
-            Err(_) => String::from("")
-        };
-
-        let avg_power_col: i64 = match row.get(3) {
-            Ok(value) => value,
-            Err(_) => 0
-        };
-
-        let avg_heart_rate_col: i64 = match row.get(4) {
-            Ok(value) => value,
-            Err(_) => 0
-        };
-
-        let total_distance_col: f64 = match row.get(5) {
-            Ok(value) => value,
-            Err(_) => 0.0
-        };
-
-        let total_moving_time_col: f64 = match row.get(6) {
-            Ok(value) => value,
-            Err(_) => 0.0
-        };
-
-        let total_elapsed_time_col: f64 = match row.get(7) {
-            Ok(value) => value,
-            Err(_) => 0.0
-        };
-        
-        let avg_cadence_col: i64 = match row.get(8) {
-            Ok(value) => value,
-            Err(_) => 0
-        };
-
-        let serial_num_col: i64 = match row.get(9) {
-            Ok(value) => value,
-            Err(_) => 0
-        };
-
-        let start_time_col: String = match row.get(10) {
-            Ok(value) => value,
-            Err(_) => String::from("")
-        };
-
-        let threshold_power_col: i64 = match row.get(11) {
-            Ok(value) => value,
-            Err(_) => 0
-        };
-
-        let session_laps = get_laps_by_session_id(session_id.to_string()).unwrap();
-        let session_records = get_records_by_session_id(session_id.to_string()).unwrap();
-
-        Ok(Session {
-            id: Some(session_id),
-            sport: sport_col,
-            sub_sport: sub_sport_col,
-            avg_power: avg_power_col,
-            avg_heart_rate: avg_heart_rate_col,
-            total_distance: total_distance_col,
-            total_moving_time: total_moving_time_col,
-            total_elapsed_time: total_elapsed_time_col,
-            avg_cadence: avg_cadence_col,
-            serial_num: serial_num_col,
-            start_time: start_time_col.parse::<i64>().unwrap(),
-            threshold_power: threshold_power_col,
-            laps: session_laps,
-            records: session_records
-        })
-    })?;
+    // Every word is quoted so the user input can't be read as FTS syntax, `*` matches word prefixes
+    let match_query = query.split_whitespace()
+        .map(|x| format!("\"{}\"*", x.replace('"', "\"\"")))
+        .collect::<Vec<String>>()
+        .join(" ");
 
-    let sessions: Vec<Session> = query_result.into_iter()
-        .map(|x| x.unwrap())
-        .collect();
+    if match_query.is_empty() {
+        return get_all_sessions(athlete_id);
+    }
+
+    let mut statement = conn.prepare(&format!(
+        "select {}
+        from session
+        where athlete_id = ?1
+            and id in (select rowid from session_search where session_search match ?2)
+        order by start_time desc", SESSION_COLUMNS))?;
+
+    let sessions = statement
+        .query_map(params![athlete_id, match_query], session_from_row)?
+        .collect::<rusqlite::Result<Vec<Session>>>()?;
 
     return Ok(sessions);
 }
 
-fn get_laps_by_session_id(session_id: String) -> Result<Vec<Lap>> {
+/// Deletes a session together with its laps, records and archived FIT file
+fn delete_session(session_id: i64) -> Result<()> {
+    let mut connection = open_connection()?;
+    let transaction = write_transaction(&mut connection)?;
+
+    let session: Option<(Option<i64>, i64)> = transaction
+        .query_row("select athlete_id, start_time from session where id = ?1", [session_id], |row| Ok((row.get(0)?, row.get(1)?)))
+        .optional()?;
+
+    let (athlete_id, start_time) = match session {
+        Some(value) => value,
+        None => bail!("Session {} does not exist", session_id)
+    };
+
+    transaction.execute("delete from session where id = ?1", [session_id])?;
+
+    if let Some(athlete_id) = athlete_id {
+        rebuild_personal_records(&transaction, athlete_id)?;
+        update_critical_power(&transaction, athlete_id, &critical_power::fit_days(start_time))?;
+    }
+
+    transaction.commit()?;
+
+    return Ok(());
+}
+
+/// Loads laps and records of a session which are left out of `get_all_sessions`
+fn get_session_details(session: &Session) -> Result<Session> {
     let conn = open_connection()?;
+    let session_id = session.id.unwrap_or(0);
+
+    let mut detailed = session.clone();
+    detailed.laps = get_laps_by_session_id(&conn, session_id)?;
+    detailed.records = get_records_by_session_id(&conn, session_id)?;
 
+    return Ok(detailed);
+}
+
+fn get_laps_by_session_id(conn: &Connection, session_id: i64) -> Result<Vec<Lap>> {
     let mut query = conn.prepare(
-        "select id 
-            , avg_heart_rate
+        "select avg_heart_rate
             , avg_power
             , start_time
             , distance
             , total_moving_time
         from lap
-        where session_id = ?")?;
+        where session_id = ?
+        order by start_time")?;
 
     let query_result = query.query_map([session_id], |row| {
-        let lap_id: i16 = match row.get(0) {
-            Ok(value) => value,
-            Err(_) => 0
-        };
-
-        let avg_heart_rate_col: i64 = match row.get(1) {
-            Ok(value) => value,
-            Err(_) => 0
-        };
-
-        let avg_power_col: i64 = match row.get(2) {
-            Ok(value) => value,
-            Err(_) => 0
-        };
-
-        let start_time_col: String = match row.get(3) {
-            Ok(value) => value,
-            Err(_) => String::from("")
-        };
-
-        let distance_col: f64 = match row.get(4) {
-            Ok(value) => value,
-            Err(_) => 0.0
-        };
-        
-        let total_moving_time_col: f64 = match row.get(5) {
-            Ok(value) => value,
-            Err(_) => 0.0
-        };
+        let avg_heart_rate_col: Option<i64> = row.get(0)?;
+        let avg_power_col: Option<i64> = row.get(1)?;
+        let start_time_col: i64 = row.get(2)?;
+        let distance_col: Option<f64> = row.get(3)?;
+        let total_moving_time_col: Option<f64> = row.get(4)?;
 
         Ok(Lap {
-            id: Some(lap_id),
-            avg_heart_rate: avg_heart_rate_col,
-            avg_power: avg_power_col,
-            start_time: start_time_col.parse::<i64>().unwrap(),
-            total_distance: distance_col,
-            total_moving_time: total_moving_time_col
+            avg_heart_rate: avg_heart_rate_col.unwrap_or(0),
+            avg_power: avg_power_col.unwrap_or(0),
+            start_time: start_time_col,
+            total_distance: distance_col.unwrap_or(0.0),
+            total_moving_time: total_moving_time_col.unwrap_or(0.0)
         })
     })?;
 
-    let laps: Vec<Lap> = query_result.into_iter()
-        .map(|x| x.unwrap())
-        .collect();
+    let laps = query_result.collect::<rusqlite::Result<Vec<Lap>>>()?;
 
     return Ok(laps);
 }
 
-fn get_records_by_session_id(session_id: String) -> Result<Vec<Record>> {
-    let conn = open_connection()?;
-
-    let mut query = conn.prepare(
-        "select id 
+fn get_records_by_session_id(conn: &Connection, session_id: i64) -> Result<Vec<Record>> {
+    let columns = conn.query_row(
+        "select records_count
+            , timestamp
             , heart_rate
             , power
-            , timestamp
+            , cadence
             , distance
-        from record
-        where session_id = ?")?;
+            , speed
+            , altitude
+            , position_lat
+            , position_long
+        from record_stream
+        where session_id = ?1",
+        [session_id],
+        |row| {
+            Ok(RecordColumns {
+                count: row.get(0)?,
+                timestamp: row.get(1)?,
+                heart_rate: row.get(2)?,
+                power: row.get(3)?,
+                cadence: row.get(4)?,
+                distance: row.get(5)?,
+                speed: row.get(6)?,
+                altitude: row.get(7)?,
+                position_lat: row.get(8)?,
+                position_long: row.get(9)?
+            })
+        });
+
+    return match columns {
+        Ok(value) => value.into_records(),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(Vec::new()),
+        Err(e) => bail!(e)
+    };
+}
 
-    let query_result = query.query_map([session_id], |row| {
-        let record_id: i16 = match row.get(0) {
-            Ok(value) => value,
-            Err(_) => 0
-        };
-
-        let heart_rate_col: i64 = match row.get(1) {
-            Ok(value) => value,
-            Err(_) => 0
-        };
-
-        let power_col: i64 = match row.get(2) {
-            Ok(value) => value,
-            Err(_) => 0
-        };
-
-        let timestamp_col: String = match row.get(3) {
-            Ok(value) => value,
-            Err(_) => String::from("")
-        };
-
-        let distance_col: f64 = match row.get(4) {
-            Ok(value) => value,
-            Err(_) => 0.0
-        };
-
-        Ok(Record {
-            id: Some(record_id),
-            heart_rate: heart_rate_col,
-            power: power_col,
-            timestamp: timestamp_col.parse::<i64>().unwrap(),
-            distance: distance_col
-        })
-    })?;
+fn get_overall_summary(athlete_id: i64, range: &DateRange) -> Result<Summary> {
+    let conn = open_connection()?;
 
-    let records: Vec<Record> = query_result.into_iter()
-        .map(|x| {
-            return match x {
-                Ok(value) => value,
-                Err(_) => Record { id: None, timestamp: 0, heart_rate: 0, power: 0, distance: 0.0 }
-            };
-        })
-        .collect();
+    let overall = conn.query_row(
+        "select coalesce(sum(total_distance), 0)
+            , coalesce(sum(total_moving_time), 0)
+            , count(*)
+            , coalesce(sum(total_ascent), 0)
+            , coalesce(sum(total_work), 0)
+            , coalesce(sum(training_stress_score), 0)
+        from session
+        where athlete_id = ?1
+            and start_time >= ?2
+            and start_time < ?3",
+        [athlete_id, range.start, range.end],
+        |row| {
+            Ok(Summary {
+                sub_sport: None,
+                total_distance: row.get(0)?,
+                total_time: row.get(1)?,
+                rides_count: row.get(2)?,
+                total_ascent: row.get(3)?,
+                total_work: row.get(4)?,
+                total_training_stress: row.get(5)?
+            })
+        })?;
 
-    return Ok(records);
+    return Ok(overall);
 }
 
-pub fn get_overall_summary(year: i64) -> Result<Summary> {
+fn get_detailed_summary(athlete_id: i64, range: &DateRange) -> Result<Vec<Summary>> {
     let conn = open_connection()?;
-    
+
     let mut query = conn.prepare(
-        "select sum(total_distance)
-            , sum(total_moving_time)
+        "select sub_sport
+            , coalesce(sum(total_distance), 0)
+            , coalesce(sum(total_moving_time), 0)
             , count(*)
-        from session")?;
+            , coalesce(sum(total_ascent), 0)
+            , coalesce(sum(total_work), 0)
+            , coalesce(sum(training_stress_score), 0)
+        from session
+        where athlete_id = ?1
+            and start_time >= ?2
+            and start_time < ?3
+        group by sub_sport")?;
+
+    let query_result = query.query_map([athlete_id, range.start, range.end], |row| {
+        Ok(Summary {
+            sub_sport: Some(row.get(0)?),
+            total_distance: row.get(1)?,
+            total_time: row.get(2)?,
+            rides_count: row.get(3)?,
+            total_ascent: row.get(4)?,
+            total_work: row.get(5)?,
+            total_training_stress: row.get(6)?
+        })
+    })?;
 
-    let query_result = query.query_map([], |row| {
-        let total_distance_field: f64 = match row.get(0) {
-            Ok(value) => value,
-            Err(_) => 0.0
-        };
+    let result = query_result.collect::<rusqlite::Result<Vec<Summary>>>()?;
 
-        let total_moving_time_field: f64 = match row.get(1) {
-            Ok(value) => value,
-            Err(_) => 0.0
-        };
+    return Ok(result);
+}
 
-        let rides_cound_field: i64 = match row.get(2) {
-            Ok(value) => value,
-            Err(_) => 0
-        };
+/// Days start at local midnight like the date ranges, the local dates are the ones of SQLite's `localtime`
+fn get_daily_summaries(athlete_id: i64, range: &DateRange) -> Result<Vec<(i64, Summary)>> {
+    let conn = open_connection()?;
 
-        Ok(Summary {
+    let mut query = conn.prepare(
+        "select date(start_time, 'unixepoch', 'localtime') as day
+            , coalesce(sum(total_distance), 0)
+            , coalesce(sum(total_moving_time), 0)
+            , count(*)
+            , coalesce(sum(total_ascent), 0)
+            , coalesce(sum(total_work), 0)
+            , coalesce(sum(training_stress_score), 0)
+        from session
+        where athlete_id = ?1
+            and start_time >= ?2
+            and start_time < ?3
+        group by day
+        order by day")?;
+
+    let query_result = query.query_map([athlete_id, range.start, range.end], |row| {
+        Ok((row.get::<_, String>(0)?, Summary {
             sub_sport: None,
-            total_distance: total_distance_field,
-            total_time: total_moving_time_field,
-            rides_count: rides_cound_field
-        })
+            total_distance: row.get(1)?,
+            total_time: row.get(2)?,
+            rides_count: row.get(3)?,
+            total_ascent: row.get(4)?,
+            total_work: row.get(5)?,
+            total_training_stress: row.get(6)?
+        }))
     })?;
 
-    let result = query_result.into_iter()
-        .map(|x| x.unwrap())
-        .collect::<Vec<Summary>>();
+    let mut result: Vec<(i64, Summary)> = Vec::new();
+    for (day, summary) in query_result.collect::<rusqlite::Result<Vec<(String, Summary)>>>()? {
+        result.push((local_midnight(NaiveDate::parse_from_str(&day, "%Y-%m-%d")?), summary));
+    }
 
-    let overall = result.first()
-        .unwrap()
-        .to_owned();
+    return Ok(result);
+}
 
-    return Ok(overall);
+/// Finds a session of the same athlete imported from the same file, or recorded by the same device at an overlapping time
+fn find_duplicate_session(session: &Session) -> Result<Option<Session>> {
+    let conn = open_connection()?;
+    let start_time = session.start_time;
+    let end_time = session.total_elapsed_time as i64 + start_time;
+
+    let mut query = conn.prepare(&format!(
+        "select {}
+        from session
+        where athlete_id = coalesce(?5, (select min(id) from athlete))
+            and (file_hash = ?1
+                or (serial_number = ?2
+                    and start_time < ?4
+                    and ?3 < (start_time + total_elapsed_time)))
+        order by file_hash = ?1 desc
+        limit 1", SESSION_COLUMNS))?;
+
+    let mut rows = query.query(params![session.file_hash, session.serial_num, start_time, end_time, session.athlete_id])?;
+
+    return match rows.next()? {
+        Some(row) => Ok(Some(session_from_row(row)?)),
+        None => Ok(None)
+    };
+}
+
+/// Assigns sessions without a bike to the bike whose rules match them best,
+/// a bike matching both sub sport and device wins over one matching only either of them
+fn assign_bikes_by_rules(connection: &Connection, session_id: Option<i64>) -> Result<()> {
+    connection.execute(
+        "update session set bike_id = (
+            select b.id
+            from bike b
+            where (b.rule_sub_sport is not null or b.rule_serial_number is not null)
+                and (b.rule_sub_sport is null or b.rule_sub_sport = session.sub_sport)
+                and (b.rule_serial_number is null or b.rule_serial_number = session.serial_number)
+            order by (b.rule_sub_sport is not null) + (b.rule_serial_number is not null) desc, b.id
+            limit 1)
+        where bike_id is null
+            and (?1 is null or id = ?1)",
+        [session_id])?;
+
+    return Ok(());
 }
 
-pub fn get_detailed_summary(year: i64) -> Result<Vec<Summary>> {
+/// Runs the bike rules over every session which has no bike yet
+fn apply_bike_rules() -> Result<i64> {
     let conn = open_connection()?;
-    
-    let mut query = conn.prepare(
-        "select sub_sport 
-            , sum(total_distance)
-            , sum(total_moving_time)
-            , count(*)
-        from session group by sub_sport")?;
+    let count_unassigned = "select count(*) from session where bike_id is null";
 
-    let query_result = query.query_map([], |row| {
-        let sub_sport_field: String = match row.get(0) {
-            Ok(value) => value,
-            Err(_) => String::from("")
-        };
+    let unassigned_before: i64 = conn.query_row(count_unassigned, [], |row| row.get(0))?;
+    assign_bikes_by_rules(&conn, None)?;
+    let unassigned_after: i64 = conn.query_row(count_unassigned, [], |row| row.get(0))?;
+
+    return Ok(unassigned_before - unassigned_after);
+}
 
-        let total_distance_field: f64 = match row.get(1) {
-            Ok(value) => value,
-            Err(_) => 0.0
-        };
+fn insert_bike(name: &str, rule_sub_sport: Option<&String>, rule_serial_number: Option<i64>) -> Result<i64> {
+    let conn = open_connection()?;
 
-        let total_moving_time_field: f64 = match row.get(2) {
-            Ok(value) => value,
-            Err(_) => 0.0
-        };
+    conn.execute(
+        "insert into bike (name, rule_sub_sport, rule_serial_number) values (?1, ?2, ?3)",
+        params![name, rule_sub_sport, rule_serial_number])?;
 
-        let rides_cound_field: i64 = match row.get(3) {
-            Ok(value) => value,
-            Err(_) => 0
-        };
+    return Ok(conn.last_insert_rowid());
+}
 
-        Ok(Summary {
-            sub_sport: Some(sub_sport_field),
-            total_distance: total_distance_field,
-            total_time: total_moving_time_field,
-            rides_count: rides_cound_field
-        })
-    })?;
+fn delete_bike(bike_id: i64) -> Result<()> {
+    let conn = open_connection()?;
 
-    let result = query_result.into_iter()
-        .map(|x| x.unwrap())
-        .collect::<Vec<Summary>>();
+    if conn.execute("delete from bike where id = ?1", [bike_id])? == 0 {
+        bail!("Bike {} does not exist", bike_id);
+    }
 
-    return Ok(result);
+    return Ok(());
 }
 
-fn session_exists(session: Session) -> Result<bool> {
+fn insert_component(bike_id: i64, name: &str, installed_at: i64, service_distance: Option<f64>, service_time: Option<f64>) -> Result<i64> {
+    let conn = open_connection()?;
+
+    conn.execute(
+        "insert into component (bike_id, name, installed_at, serviced_at, service_distance, service_time)
+        values (?1, ?2, ?3, ?3, ?4, ?5)",
+        params![bike_id, name, installed_at, service_distance, service_time])?;
+
+    return Ok(conn.last_insert_rowid());
+}
+
+/// Restarts the service interval of a component from the given time
+fn service_component(component_id: i64, serviced_at: i64) -> Result<()> {
+    let conn = open_connection()?;
+
+    if conn.execute("update component set serviced_at = ?2 where id = ?1", [component_id, serviced_at])? == 0 {
+        bail!("Component {} does not exist", component_id);
+    }
+
+    return Ok(());
+}
+
+fn delete_component(component_id: i64) -> Result<()> {
+    let conn = open_connection()?;
+
+    if conn.execute("delete from component where id = ?1", [component_id])? == 0 {
+        bail!("Component {} does not exist", component_id);
+    }
+
+    return Ok(());
+}
+
+/// Gets all bikes with their components and the distance and time ridden on them
+fn get_bikes() -> Result<Vec<Bike>> {
+    let conn = open_connection()?;
+
+    let mut bike_query = conn.prepare(
+        "select b.id
+            , b.name
+            , b.rule_sub_sport
+            , b.rule_serial_number
+            , coalesce(sum(s.total_distance), 0)
+            , coalesce(sum(s.total_moving_time), 0)
+        from bike b
+        left join session s on s.bike_id = b.id
+        group by b.id
+        order by b.name")?;
+
+    let mut bikes = bike_query
+        .query_map([], |row| {
+            Ok(Bike {
+                id: row.get(0)?,
+                name: row.get(1)?,
+                rule_sub_sport: row.get(2)?,
+                rule_serial_number: row.get(3)?,
+                total_distance: row.get(4)?,
+                total_time: row.get(5)?,
+                components: Vec::new()
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<Bike>>>()?;
+
+    let mut component_query = conn.prepare(
+        "select c.id
+            , c.bike_id
+            , c.name
+            , c.installed_at
+            , c.serviced_at
+            , c.service_distance
+            , c.service_time
+            , coalesce(sum(s.total_distance), 0)
+            , coalesce(sum(s.total_moving_time), 0)
+            , coalesce(sum(case when s.start_time >= c.serviced_at then s.total_distance end), 0)
+            , coalesce(sum(case when s.start_time >= c.serviced_at then s.total_moving_time end), 0)
+        from component c
+        left join session s on s.bike_id = c.bike_id and s.start_time >= c.installed_at
+        group by c.id
+        order by c.name")?;
+
+    let components = component_query
+        .query_map([], |row| {
+            Ok(Component {
+                id: row.get(0)?,
+                bike_id: row.get(1)?,
+                name: row.get(2)?,
+                installed_at: row.get(3)?,
+                serviced_at: row.get(4)?,
+                service_distance: row.get(5)?,
+                service_time: row.get(6)?,
+                total_distance: row.get(7)?,
+                total_time: row.get(8)?,
+                distance_since_service: row.get(9)?,
+                time_since_service: row.get(10)?
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<Component>>>()?;
+
+    for component in components {
+        if let Some(bike) = bikes.iter_mut().find(|x| x.id == component.bike_id) {
+            bike.components.push(component);
+        }
+    }
+
+    return Ok(bikes);
+}
+
+fn insert_athlete(name: &str) -> Result<i64> {
+    let conn = open_connection()?;
+
+    conn.execute("insert into athlete (name) values (?1)", [name])?;
+
+    return Ok(conn.last_insert_rowid());
+}
+
+/// Only athletes without sessions can be deleted
+fn delete_athlete(athlete_id: i64) -> Result<()> {
+    let conn = open_connection()?;
+
+    let session_count: i64 = conn.query_row(
+        "select count(*) from session where athlete_id = ?1", [athlete_id], |row| row.get(0))?;
+
+    if session_count > 0 {
+        bail!("Athlete {} still has {} sessions", athlete_id, session_count);
+    }
+
+    if conn.execute("delete from athlete where id = ?1", [athlete_id])? == 0 {
+        bail!("Athlete {} does not exist", athlete_id);
+    }
+
+    return Ok(());
+}
+
+/// Gets all athletes, the first one is the default profile
+fn get_athletes() -> Result<Vec<Athlete>> {
     let conn = open_connection()?;
-    let start_time = session.start_time;
-    let end_time = session.total_elapsed_time.to_string().parse::<i64>().unwrap() + start_time;
 
     let mut query = conn.prepare(
-        "select s.id
-              , s.start_time + s.total_elapsed_time as end_time
-        from session s
-        where (?1 between s.start_time and (s.start_time + s.total_elapsed_time) or ?2 between s.start_time and (s.start_time + s.total_elapsed_time))
-            or (?1 <= s.start_time and ?2 >= (s.start_time + s.total_elapsed_time))")?;
+        "select a.id
+            , a.name
+            , count(s.id)
+        from athlete a
+        left join session s on s.athlete_id = a.id
+        group by a.id
+        order by a.id")?;
+
+    let athletes = query
+        .query_map([], |row| {
+            Ok(Athlete {
+                id: row.get(0)?,
+                name: row.get(1)?,
+                sessions_count: row.get(2)?
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<Athlete>>>()?;
+
+    return Ok(athletes);
+}
+
+/// Adds the thresholds the device recorded with a session to its athlete's history when they changed, returns whether it did
+fn record_device_thresholds(connection: &Connection, session_id: i64, session: &Session) -> Result<bool> {
+    let athlete_id: i64 = connection.query_row(
+        "select athlete_id from session where id = ?1", [session_id], |row| row.get(0))?;
+
+    let history = get_thresholds(connection, athlete_id)?;
+    return match history.device_entry(athlete_id, session_id, session) {
+        Some(threshold) => {
+            write_threshold(connection, &threshold)?;
+            Ok(true)
+        },
+        None => Ok(false)
+    };
+}
+
+/// Training load of the written session, the later sessions of the athlete only change when it added a threshold entry
+fn record_training_load(connection: &Connection, session_id: i64, threshold_added: bool) -> Result<()> {
+    let session = connection.query_row(
+        &format!("select {} from session where id = ?1", SESSION_COLUMNS), [session_id], session_from_row)?;
+    let athlete_id = session.athlete_id.unwrap_or(0);
+
+    if threshold_added {
+        return update_training_loads(connection, athlete_id, session.start_time);
+    }
+
+    return write_training_load(connection, &get_thresholds(connection, athlete_id)?, &session);
+}
+
+/// Applies the threshold history to the intensity factor and TSS of the athlete's sessions from the given time,
+/// a threshold entry changes every session after it
+fn update_training_loads(connection: &Connection, athlete_id: i64, since: i64) -> Result<()> {
+    let history = get_thresholds(connection, athlete_id)?;
+
+    let sessions = connection
+        .prepare(&format!("select {} from session where athlete_id = ?1 and start_time >= ?2", SESSION_COLUMNS))?
+        .query_map([athlete_id, since], session_from_row)?
+        .collect::<rusqlite::Result<Vec<Session>>>()?;
+
+    for session in sessions {
+        write_training_load(connection, &history, &session)?;
+    }
+
+    return Ok(());
+}
+
+fn write_training_load(connection: &Connection, history: &ThresholdHistory, session: &Session) -> Result<()> {
+    let training_load = session.training_load.with_threshold(history.ftp_for(session), session.total_moving_time);
+    if training_load == session.training_load {
+        return Ok(());
+    }
+
+    connection.execute(
+        "update session set intensity_factor = ?2, training_stress_score = ?3 where id = ?1",
+        params![session.id, training_load.intensity_factor, training_load.training_stress_score])?;
+
+    return Ok(());
+}
+
+fn write_threshold(connection: &Connection, threshold: &Threshold) -> Result<i64> {
+    connection.execute(
+        "insert into threshold (
+              athlete_id
+            , effective_from
+            , ftp
+            , lthr
+            , max_heart_rate
+            , weight
+            , source
+            , session_id
+        ) values (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)"
+        , params![threshold.athlete_id
+            , threshold.effective_from
+            , threshold.ftp
+            , threshold.lthr
+            , threshold.max_heart_rate
+            , threshold.weight
+            , threshold.source.as_str()
+            , threshold.session_id])?;
+
+    return Ok(connection.last_insert_rowid());
+}
+
+pub fn insert_threshold(threshold: &Threshold) -> Result<i64> {
+    let mut connection = open_connection()?;
+    let transaction = write_transaction(&mut connection)?;
+
+    let threshold_id = write_threshold(&transaction, threshold)?;
+    update_training_loads(&transaction, threshold.athlete_id, threshold.effective_from)?;
+
+    transaction.commit()?;
+
+    return Ok(threshold_id);
+}
+
+fn delete_threshold(threshold_id: i64) -> Result<()> {
+    let mut connection = open_connection()?;
+    let transaction = write_transaction(&mut connection)?;
+
+    let threshold: Option<(i64, i64)> = transaction
+        .query_row("select athlete_id, effective_from from threshold where id = ?1", [threshold_id], |row| Ok((row.get(0)?, row.get(1)?)))
+        .optional()?;
+
+    let (athlete_id, effective_from) = match threshold {
+        Some(value) => value,
+        None => bail!("Threshold {} does not exist", threshold_id)
+    };
+
+    transaction.execute("delete from threshold where id = ?1", [threshold_id])?;
+    update_training_loads(&transaction, athlete_id, effective_from)?;
+
+    transaction.commit()?;
+
+    return Ok(());
+}
+
+fn get_thresholds(connection: &Connection, athlete_id: i64) -> Result<ThresholdHistory> {
+    let mut query = connection.prepare(
+        "select id
+            , athlete_id
+            , effective_from
+            , ftp
+            , lthr
+            , max_heart_rate
+            , weight
+            , source
+            , session_id
+        from threshold
+        where athlete_id = ?1")?;
+
+    let thresholds = query
+        .query_map([athlete_id], |row| {
+            let source_col: String = row.get(7)?;
+
+            Ok(Threshold {
+                id: row.get(0)?,
+                athlete_id: row.get(1)?,
+                effective_from: row.get(2)?,
+                ftp: row.get(3)?,
+                lthr: row.get(4)?,
+                max_heart_rate: row.get(5)?,
+                weight: row.get(6)?,
+                source: source_col.parse::<ThresholdSource>().unwrap_or(ThresholdSource::Device),
+                session_id: row.get(8)?
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<Threshold>>>()?;
+
+    return Ok(ThresholdHistory::new(thresholds));
+}
+
+/// Adds the records a new session sets to its athlete's board, the board is built again when the session
+/// is older than one of its records
+fn record_personal_records(connection: &Connection, session_id: i64, stale_boards: Option<&mut BTreeSet<i64>>) -> Result<()> {
+    let (athlete_id, start_time): (i64, i64) = connection.query_row(
+        "select athlete_id, start_time from session where id = ?1", [session_id], |row| Ok((row.get(0)?, row.get(1)?)))?;
+
+    let bests = connection
+        .prepare("select kind, value from session_best where session_id = ?1")?
+        .query_map([session_id], |row| Ok((row.get::<_, String>(0)?, row.get::<_, f64>(1)?)))?
+        .collect::<rusqlite::Result<Vec<(String, f64)>>>()?
+        .into_iter()
+        .filter_map(|(kind, value)| kind.parse::<RecordKind>().ok().map(|x| (x, value)))
+        .collect();
+
+    let board = get_personal_records(connection, athlete_id)?;
+    return match board.appended(athlete_id, &SessionBests { session_id, start_time, bests }) {
+        Some(records) => records.iter().try_for_each(|x| write_personal_record(connection, x)),
+        None => rebuild_session_board(connection, session_id, stale_boards)
+    };
+}
+
+/// Builds the board of the session's athlete again, during an import the athlete is kept for `end_import` instead
+fn rebuild_session_board(connection: &Connection, session_id: i64, stale_boards: Option<&mut BTreeSet<i64>>) -> Result<()> {
+    let athlete_id: i64 = connection.query_row(
+        "select athlete_id from session where id = ?1", [session_id], |row| row.get(0))?;
+
+    return match stale_boards {
+        Some(athletes) => {
+            athletes.insert(athlete_id);
+            Ok(())
+        },
+        None => rebuild_personal_records(connection, athlete_id)
+    };
+}
+
+/// Builds the boards an import left behind, all in one transaction
+fn rebuild_stale_boards(athlete_ids: BTreeSet<i64>) -> Result<()> {
+    let mut connection = open_connection()?;
+    let transaction = write_transaction(&mut connection)?;
+
+    for athlete_id in athlete_ids {
+        rebuild_personal_records(&transaction, athlete_id)?;
+    }
+
+    transaction.commit()?;
 
-    let mut rows = query.query([start_time, end_time])?;
-    let value = rows.next()?;
+    return Ok(());
+}
+
+/// Keeps the bests of the session the boards are built from, replacing the previous ones
+fn write_session_bests(connection: &Connection, session_id: i64, session: &Session) -> Result<()> {
+    connection.execute("delete from session_best where session_id = ?1", [session_id])?;
+
+    for (kind, value) in personal_record::session_bests(session) {
+        connection.execute(
+            "insert into session_best (session_id, kind, value) values (?1, ?2, ?3)",
+            params![session_id, kind.as_str(), value])?;
+    }
+
+    return Ok(());
+}
+
+/// Builds the athlete's board from the bests of every session, oldest first
+fn rebuild_personal_records(connection: &Connection, athlete_id: i64) -> Result<()> {
+    let rows = connection
+        .prepare(
+            "select session.id
+                , session.start_time
+                , session_best.kind
+                , session_best.value
+            from session_best
+            inner join session on session.id = session_best.session_id
+            where session.athlete_id = ?1
+            order by session.start_time, session.id")?
+        .query_map([athlete_id], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?, row.get::<_, String>(2)?, row.get::<_, f64>(3)?)))?
+        .collect::<rusqlite::Result<Vec<(i64, i64, String, f64)>>>()?;
+
+    let sessions: Vec<SessionBests> = rows.into_iter()
+        .group_by(|(session_id, start_time, _, _)| (*session_id, *start_time))
+        .into_iter()
+        .map(|((session_id, start_time), bests)| SessionBests {
+            session_id,
+            start_time,
+            // Kinds this version doesn't know are left out
+            bests: bests.filter_map(|(_, _, kind, value)| kind.parse::<RecordKind>().ok().map(|x| (x, value))).collect()
+        })
+        .collect();
 
-    if value.is_some() {
-        return Ok(true);
+    return write_personal_records(connection, athlete_id, &PersonalRecordBoard::from_sessions(athlete_id, sessions));
+}
+
+/// Replaces every personal record of the athlete with the board
+fn write_personal_records(connection: &Connection, athlete_id: i64, board: &PersonalRecordBoard) -> Result<()> {
+    connection.execute("delete from personal_record where athlete_id = ?1", [athlete_id])?;
+
+    for record in &board.records {
+        write_personal_record(connection, record)?;
     }
 
-    return Ok(false);
+    return Ok(());
+}
+
+fn write_personal_record(connection: &Connection, record: &PersonalRecord) -> Result<()> {
+    connection.execute(
+        "insert into personal_record (
+              athlete_id
+            , session_id
+            , kind
+            , value
+            , achieved_at
+        ) values (?1, ?2, ?3, ?4, ?5)"
+        , params![record.athlete_id
+            , record.session_id
+            , record.kind.as_str()
+            , record.value
+            , record.achieved_at])?;
+
+    return Ok(());
+}
+
+fn get_personal_records(connection: &Connection, athlete_id: i64) -> Result<PersonalRecordBoard> {
+    let mut query = connection.prepare(
+        "select id
+            , athlete_id
+            , session_id
+            , kind
+            , value
+            , achieved_at
+        from personal_record
+        where athlete_id = ?1")?;
+
+    let records = query
+        .query_map([athlete_id], |row| {
+            let record_id: i64 = row.get(0)?;
+            let athlete_id_col: i64 = row.get(1)?;
+            let session_id_col: i64 = row.get(2)?;
+            let kind_col: String = row.get(3)?;
+            let value_col: f64 = row.get(4)?;
+            let achieved_at_col: i64 = row.get(5)?;
+
+            // Kinds this version doesn't know are left out
+            Ok(kind_col.parse::<RecordKind>().ok().map(|kind| PersonalRecord {
+                id: Some(record_id),
+                athlete_id: athlete_id_col,
+                session_id: session_id_col,
+                kind,
+                value: value_col,
+                achieved_at: achieved_at_col
+            }))
+        })?
+        .collect::<rusqlite::Result<Vec<Option<PersonalRecord>>>>()?;
+
+    return Ok(PersonalRecordBoard::new(records.into_iter().flatten().collect()));
+}
+
+fn log_import(entry: &ImportLogEntry) -> Result<i64> {
+    let conn = open_connection()?;
+
+    conn.execute(
+        "insert into import_log (
+              path
+            , file_hash
+            , imported_at
+            , outcome
+            , error
+            , session_id
+        ) values (?1, ?2, ?3, ?4, ?5, ?6)"
+        , params![entry.path
+            , entry.file_hash
+            , entry.imported_at
+            , entry.outcome
+            , entry.error
+            , entry.session_id])?;
+
+    return Ok(conn.last_insert_rowid());
+}
+
+/// Gets the latest import log entries, newest first
+fn get_import_log(limit: i64) -> Result<Vec<ImportLogEntry>> {
+    let conn = open_connection()?;
+
+    let mut query = conn.prepare(
+        "select id
+            , path
+            , file_hash
+            , imported_at
+            , outcome
+            , error
+            , session_id
+        from import_log
+        order by imported_at desc, id desc
+        limit ?1")?;
+
+    let entries = query
+        .query_map([limit], |row| {
+            Ok(ImportLogEntry {
+                id: row.get(0)?,
+                path: row.get(1)?,
+                file_hash: row.get(2)?,
+                imported_at: row.get(3)?,
+                outcome: row.get(4)?,
+                error: row.get(5)?,
+                session_id: row.get(6)?
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<ImportLogEntry>>>()?;
+
+    return Ok(entries);
 }
@@ -0,0 +1,199 @@
+use std::str::FromStr;
+use anyhow::{Context, Result, bail};
+use chrono::Local;
+
+use crate::athlete::Athlete;
+use crate::critical_power::{self, CpModel};
+use crate::store::SessionStore;
+use crate::summary::DateRange;
+use crate::threshold::{Threshold, ThresholdHistory, ThresholdSource};
+use crate::util::flag_value;
+
+/// Share of the best 20 minute power taken as FTP
+const TWENTY_MINUTE_FACTOR: f64 = 0.95;
+const TWENTY_MINUTES: i64 = 1200;
+
+/// What the FTP is estimated from
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EstimateMethod {
+    /// 95% of the best 20 minute power
+    TwentyMinute,
+    /// CP of the 3-parameter model, or the 2-parameter model when short efforts are missing
+    CriticalPower
+}
+
+impl FromStr for EstimateMethod {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        return match value {
+            "20min" => Ok(EstimateMethod::TwentyMinute),
+            "cp" => Ok(EstimateMethod::CriticalPower),
+            _ => bail!("Unknown FTP estimate method '{}', expected 20min or cp", value)
+        };
+    }
+}
+
+impl EstimateMethod {
+    pub fn label(&self) -> &str {
+        return match self {
+            EstimateMethod::TwentyMinute => "95% of 20 min power",
+            EstimateMethod::CriticalPower => "CP"
+        };
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EstimateConfig {
+    pub method: EstimateMethod,
+    /// Days up to now the best power is taken from
+    pub window_days: i64,
+    /// Percent the estimate has to move away from the current FTP to be a change
+    pub change_percent: f64
+}
+
+impl Default for EstimateConfig {
+    fn default() -> Self {
+        return Self { method: EstimateMethod::TwentyMinute, window_days: 42, change_percent: 5.0 };
+    }
+}
+
+impl EstimateConfig {
+    /// Read from `FTP_ESTIMATE_METHOD`, `FTP_ESTIMATE_DAYS` and `FTP_CHANGE_PERCENT` in .env
+    pub fn from_env() -> Result<Self> {
+        return Self::from_args(&[]);
+    }
+
+    /// The .env values overridden by `--method`, `--days` and `--change`
+    pub fn from_args(args: &[String]) -> Result<Self> {
+        return Self::with_values(
+            flag_value(args, "--method").cloned().or(dotenv::var("FTP_ESTIMATE_METHOD").ok()),
+            flag_value(args, "--days").cloned().or(dotenv::var("FTP_ESTIMATE_DAYS").ok()),
+            flag_value(args, "--change").cloned().or(dotenv::var("FTP_CHANGE_PERCENT").ok()));
+    }
+
+    fn with_values(method: Option<String>, days: Option<String>, change: Option<String>) -> Result<Self> {
+        let defaults = Self::default();
+
+        let config = Self {
+            method: match method {
+                Some(value) => value.parse::<EstimateMethod>()?,
+                None => defaults.method
+            },
+            window_days: match days {
+                Some(value) => value.parse::<i64>().with_context(|| format!("Invalid number of days '{}'", value))?,
+                None => defaults.window_days
+            },
+            change_percent: match change {
+                Some(value) => value.parse::<f64>().with_context(|| format!("Invalid change percent '{}'", value))?,
+                None => defaults.change_percent
+            }
+        };
+
+        if config.window_days < 1 || config.change_percent < 0.0 {
+            bail!("The estimate window has to be at least a day and the change percent positive");
+        }
+
+        return Ok(config);
+    }
+}
+
+/// FTP estimated from the best power of the window, with the FTP of the threshold history now
+#[derive(Debug, Clone, PartialEq)]
+pub struct FtpEstimate {
+    pub method: EstimateMethod,
+    pub ftp: i64,
+    pub current: Option<i64>
+}
+
+impl FtpEstimate {
+    /// Percent the estimate is above the current FTP, none without one
+    pub fn change_percent(&self) -> Option<f64> {
+        return match self.current {
+            Some(current) if current > 0 => Some((self.ftp - current) as f64 / current as f64 * 100.0),
+            _ => None
+        };
+    }
+
+    /// Estimate moved more than the configured percent from the current FTP, or there is no FTP yet
+    pub fn is_change(&self, config: &EstimateConfig) -> bool {
+        return match self.change_percent() {
+            Some(percent) => percent.abs() > config.change_percent,
+            None => true
+        };
+    }
+
+    /// `FTP estimate 265W (+6.0% on 250W)`
+    pub fn description(&self) -> String {
+        return match (self.current, self.change_percent()) {
+            (Some(current), Some(percent)) => format!("FTP estimate {}W ({:+.1}% on {}W)", self.ftp, percent, current),
+            _ => format!("FTP estimate {}W (no FTP yet)", self.ftp)
+        };
+    }
+
+    /// Manual threshold entry with the estimate from now on
+    pub fn threshold(&self, athlete_id: i64) -> Threshold {
+        return Threshold {
+            id: None,
+            athlete_id,
+            effective_from: Local::now().timestamp(),
+            ftp: Some(self.ftp),
+            lthr: None,
+            max_heart_rate: None,
+            weight: None,
+            source: ThresholdSource::Manual,
+            session_id: None
+        };
+    }
+}
+
+/// Estimate from the athlete's sessions in the window, none when they don't have the efforts the method needs
+pub fn estimate(store: &dyn SessionStore, athlete_id: i64, thresholds: &ThresholdHistory, config: &EstimateConfig) -> Result<Option<FtpEstimate>> {
+    let curve = store.get_power_curve(athlete_id, &DateRange::rolling(config.window_days))?;
+
+    let power = match config.method {
+        EstimateMethod::TwentyMinute => curve.points.iter()
+            .find(|x| x.seconds == TWENTY_MINUTES)
+            .map(|x| x.power * TWENTY_MINUTE_FACTOR),
+        EstimateMethod::CriticalPower => critical_power::fit(CpModel::ThreeParameter, &curve)
+            .or_else(|| critical_power::fit(CpModel::TwoParameter, &curve))
+            .map(|x| x.critical_power)
+    };
+
+    return Ok(power.filter(|x| *x > 0.0).map(|x| FtpEstimate {
+        method: config.method,
+        ftp: x.round() as i64,
+        current: thresholds.ftp_at(Local::now().timestamp())
+    }));
+}
+
+/// Handles `threshold estimate [--method 20min|cp] [--days N] [--change N] [--accept]`,
+/// the estimate is only added to the history with `--accept`
+pub fn command(store: &mut dyn SessionStore, args: &[String]) -> Result<()> {
+    let athlete = Athlete::from_args(store, args)?;
+    let config = EstimateConfig::from_args(args)?;
+    let thresholds = store.get_thresholds(athlete.id)?;
+
+    let estimate = match estimate(store, athlete.id, &thresholds, &config)? {
+        Some(value) => value,
+        None => {
+            println!("Not enough efforts in the last {} days for an estimate from {}", config.window_days, config.method.label());
+            return Ok(());
+        }
+    };
+
+    println!("{} from {} of the last {} days", estimate.description(), config.method.label(), config.window_days);
+
+    if !args.iter().any(|x| x == "--accept") {
+        if estimate.is_change(&config) {
+            println!("More than {}% off, add it to the threshold history with --accept", config.change_percent);
+        }
+
+        return Ok(());
+    }
+
+    let threshold_id = store.insert_threshold(&estimate.threshold(athlete.id))?;
+    println!("Threshold {} added for {}", threshold_id, athlete.name);
+
+    return Ok(());
+}
@@ -0,0 +1,175 @@
+use anyhow::{Result, bail};
+
+use crate::store::SessionStore;
+use crate::ui::util::{date_as_string, distance_as_string, moving_time_to_hour_minute_string};
+use crate::util::{date_flag_or_now, flag_value};
+
+#[derive(Debug, Clone)]
+pub struct Bike {
+    pub id: i64,
+    pub name: String,
+    /// Sessions with this sub sport are assigned to the bike on import
+    pub rule_sub_sport: Option<String>,
+    /// Sessions recorded by this device are assigned to the bike on import
+    pub rule_serial_number: Option<i64>,
+    pub total_distance: f64,
+    pub total_time: f64,
+    pub components: Vec<Component>
+}
+
+#[derive(Debug, Clone)]
+pub struct Component {
+    pub id: i64,
+    pub bike_id: i64,
+    pub name: String,
+    pub installed_at: i64,
+    pub serviced_at: i64,
+    /// Distance in meters after which the component needs a service
+    pub service_distance: Option<f64>,
+    /// Moving time in seconds after which the component needs a service
+    pub service_time: Option<f64>,
+    pub total_distance: f64,
+    pub total_time: f64,
+    pub distance_since_service: f64,
+    pub time_since_service: f64
+}
+
+impl Bike {
+    pub fn has_rules(&self) -> bool {
+        return self.rule_sub_sport.is_some() || self.rule_serial_number.is_some();
+    }
+
+    pub fn components_due(&self) -> Vec<&Component> {
+        return self.components.iter()
+            .filter(|x| x.service_due())
+            .collect();
+    }
+}
+
+impl Component {
+    pub fn service_due(&self) -> bool {
+        let distance_due = match self.service_distance {
+            Some(limit) => self.distance_since_service >= limit,
+            None => false
+        };
+
+        let time_due = match self.service_time {
+            Some(limit) => self.time_since_service >= limit,
+            None => false
+        };
+
+        return distance_due || time_due;
+    }
+
+    /// Usage since the last service against the configured interval
+    pub fn service_status(&self) -> String {
+        let mut result = format!("{} {}"
+            , distance_as_string(self.distance_since_service)
+            , moving_time_to_hour_minute_string(self.time_since_service));
+
+        if let Some(limit) = self.service_distance {
+            result = format!("{} / {}", result, distance_as_string(limit));
+        }
+
+        if let Some(limit) = self.service_time {
+            result = format!("{} / {}", result, moving_time_to_hour_minute_string(limit));
+        }
+
+        if self.service_due() {
+            result = format!("{} SERVICE DUE", result);
+        }
+
+        return result;
+    }
+}
+
+/// Handles `bike <add|list|delete|apply-rules>` and `component <add|service|delete>` commands
+pub fn command(store: &mut dyn SessionStore, args: &[String]) -> Result<()> {
+    let command: Vec<&str> = args.iter().take(2).map(|x| x.as_str()).collect();
+
+    match command[..] {
+        ["bike", "add"] if args.len() > 2 => {
+            let serial_number = match flag_value(args, "--serial") {
+                Some(value) => Some(value.parse::<i64>()?),
+                None => None
+            };
+
+            let bike_id = store.insert_bike(&args[2], flag_value(args, "--sub-sport"), serial_number)?;
+            println!("Bike {} added", bike_id);
+        },
+        ["bike", "list"] => {
+            for bike in store.get_bikes()? {
+                print_bike(&bike);
+            }
+        },
+        ["bike", "delete"] if args.len() > 2 => {
+            store.delete_bike(args[2].parse::<i64>()?)?;
+            println!("Bike {} deleted", args[2]);
+        },
+        ["bike", "apply-rules"] => {
+            let assigned = store.apply_bike_rules()?;
+            println!("{} sessions assigned to a bike", assigned);
+        },
+        ["component", "add"] if args.len() > 3 => {
+            let service_distance = match flag_value(args, "--service-km") {
+                Some(value) => Some(value.parse::<f64>()? * 1000.0),
+                None => None
+            };
+
+            let service_time = match flag_value(args, "--service-hours") {
+                Some(value) => Some(value.parse::<f64>()? * 3600.0),
+                None => None
+            };
+
+            let component_id = store.insert_component(
+                args[2].parse::<i64>()?,
+                &args[3],
+                date_flag_or_now(args, "--installed")?,
+                service_distance,
+                service_time)?;
+            println!("Component {} added", component_id);
+        },
+        ["component", "service"] if args.len() > 2 => {
+            store.service_component(args[2].parse::<i64>()?, date_flag_or_now(args, "--date")?)?;
+            println!("Component {} serviced", args[2]);
+        },
+        ["component", "delete"] if args.len() > 2 => {
+            store.delete_component(args[2].parse::<i64>()?)?;
+            println!("Component {} deleted", args[2]);
+        },
+        _ => bail!("Unknown gear command: {}", args.join(" "))
+    }
+
+    return Ok(());
+}
+
+fn print_bike(bike: &Bike) {
+    let mut rules: Vec<String> = Vec::new();
+    if let Some(sub_sport) = &bike.rule_sub_sport {
+        rules.push(format!("sub sport {}", sub_sport));
+    }
+    if let Some(serial_number) = bike.rule_serial_number {
+        rules.push(format!("device {}", serial_number));
+    }
+
+    println!("{}: {} {} {}"
+        , bike.id
+        , bike.name
+        , distance_as_string(bike.total_distance)
+        , moving_time_to_hour_minute_string(bike.total_time));
+
+    if bike.has_rules() {
+        println!("    rules: {}", rules.join(", "));
+    }
+
+    for component in &bike.components {
+        println!("    {}: {} {} {} (installed {}, serviced {}), since service {}"
+            , component.id
+            , component.name
+            , distance_as_string(component.total_distance)
+            , moving_time_to_hour_minute_string(component.total_time)
+            , date_as_string(component.installed_at)
+            , date_as_string(component.serviced_at)
+            , component.service_status());
+    }
+}
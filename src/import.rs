@@ -0,0 +1,331 @@
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use anyhow::{Result, bail};
+use chrono::Local;
+
+use crate::athlete::Athlete;
+use crate::db::SourceFile;
+use crate::parser::{self, Session};
+use crate::store::{MemoryStore, SessionStore};
+use crate::ui::util::timestamp_as_string;
+use crate::util;
+
+/// What to do when an imported file duplicates a session already in the database
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ImportPolicy {
+    #[default]
+    Skip,
+    Replace,
+    KeepBoth
+}
+
+impl FromStr for ImportPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        return match value {
+            "skip" => Ok(ImportPolicy::Skip),
+            "replace" => Ok(ImportPolicy::Replace),
+            "keep-both" => Ok(ImportPolicy::KeepBoth),
+            _ => bail!("Unknown import policy '{}', expected skip, replace or keep-both", value)
+        };
+    }
+}
+
+impl ImportPolicy {
+    /// Reads the `--policy <skip|replace|keep-both>` flag, defaults to skip
+    pub fn from_args(args: &[String]) -> Result<Self> {
+        if !args.iter().any(|x| x == "--policy") {
+            return Ok(ImportPolicy::default());
+        }
+
+        return match util::flag_value(args, "--policy") {
+            Some(value) => value.parse::<ImportPolicy>(),
+            None => bail!("Missing value for --policy")
+        };
+    }
+}
+
+/// What happened to an imported file, with the hash of the file when it could be read
+pub struct ImportOutcome {
+    /// SHA-256 of the file, none when it couldn't be read
+    pub file_hash: Option<String>,
+    pub result: Result<ImportAction>
+}
+
+pub enum ImportAction {
+    Imported(i64),
+    Replaced(i64),
+    /// New session id and the existing session it duplicates
+    KeptBoth(i64, Session),
+    /// Existing session the file duplicates
+    Skipped(Session)
+}
+
+impl ImportAction {
+    pub fn as_str(&self) -> &str {
+        return match self {
+            ImportAction::Imported(_) => "imported",
+            ImportAction::Replaced(_) => "replaced",
+            ImportAction::KeptBoth(_, _) => "kept-both",
+            ImportAction::Skipped(_) => "skipped"
+        };
+    }
+
+    /// Session the file ended up in, the existing one when it was skipped
+    pub fn session_id(&self) -> i64 {
+        return match self {
+            ImportAction::Imported(session_id) | ImportAction::Replaced(session_id) | ImportAction::KeptBoth(session_id, _) => *session_id,
+            ImportAction::Skipped(existing) => existing.id.unwrap_or(0)
+        };
+    }
+}
+
+/// One file an import went through, failed imports included
+#[derive(Debug, Clone)]
+pub struct ImportLogEntry {
+    pub id: Option<i64>,
+    pub path: String,
+    /// SHA-256 of the file, none when it couldn't be read
+    pub file_hash: Option<String>,
+    pub imported_at: i64,
+    /// Imported, replaced, kept-both, skipped or failed
+    pub outcome: String,
+    pub error: Option<String>,
+    pub session_id: Option<i64>
+}
+
+impl ImportLogEntry {
+    pub fn is_failed(&self) -> bool {
+        return self.outcome == "failed";
+    }
+}
+
+/// Reads the file once, the hash is kept when the import fails after the file was read
+pub fn import_file(store: &mut dyn SessionStore, path: &String, policy: ImportPolicy, athlete_id: i64) -> ImportOutcome {
+    let bytes = match parser::read_file(path) {
+        Ok(value) => value,
+        Err(error) => return ImportOutcome { file_hash: None, result: Err(error) }
+    };
+
+    return ImportOutcome {
+        file_hash: Some(parser::file_hash(&bytes)),
+        result: import_bytes(store, path, bytes, policy, athlete_id)
+    };
+}
+
+fn import_bytes(store: &mut dyn SessionStore, path: &String, bytes: Vec<u8>, policy: ImportPolicy, athlete_id: i64) -> Result<ImportAction> {
+    let mut session = parser::parse(&bytes)?;
+    session.athlete_id = Some(athlete_id);
+
+    let source_file = SourceFile {
+        file_name: Path::new(path).file_name().unwrap_or_default().to_string_lossy().to_string(),
+        bytes
+    };
+
+    let existing = match store.find_duplicate_session(&session)? {
+        Some(value) => value,
+        None => return Ok(ImportAction::Imported(store.insert_session(session, &source_file)?))
+    };
+
+    return match policy {
+        ImportPolicy::Skip => Ok(ImportAction::Skipped(existing)),
+        ImportPolicy::Replace => Ok(ImportAction::Replaced(store.replace_session(&existing, session, &source_file)?)),
+        ImportPolicy::KeepBoth => Ok(ImportAction::KeptBoth(store.insert_session(session, &source_file)?, existing))
+    };
+}
+
+pub fn run(store: &mut dyn SessionStore, dir: &String, policy: ImportPolicy, athlete: &Athlete) -> Result<()> {
+    let mut errors: Vec<String> = Vec::new();
+    let mut files_imported: Vec<String> = Vec::new();
+    let mut files_skipped: Vec<String> = Vec::new();
+    let mut new_records: Vec<String> = Vec::new();
+    let mut written_sessions: Vec<i64> = Vec::new();
+
+    println!("Start import for {}", athlete.name);
+
+    let files = fs::read_dir(dir)?;
+
+    store.begin_import();
+    for file in files {
+        let path = match file {
+            Ok(value) => value.path().display().to_string(),
+            Err(error) => {
+                errors.push(format!("Failed to read {}; Error: {}", dir, error));
+                continue;
+            }
+        };
+        println!("{}", path);
+
+        let outcome = import_file(store, &path, policy, athlete.id);
+        let logged = store.log_import(&ImportLogEntry {
+            id: None,
+            path: path.clone(),
+            file_hash: outcome.file_hash.clone(),
+            imported_at: Local::now().timestamp(),
+            outcome: match &outcome.result {
+                Ok(value) => value.as_str().to_string(),
+                Err(_) => String::from("failed")
+            },
+            error: outcome.result.as_ref().err().map(|x| x.to_string()),
+            session_id: outcome.result.as_ref().ok().map(|x| x.session_id())
+        });
+
+        if let Err(error) = logged {
+            errors.push(format!("Failed to log the import of {}; Error: {}", &path, error));
+        }
+
+        if let Ok(ImportAction::Imported(session_id) | ImportAction::Replaced(session_id) | ImportAction::KeptBoth(session_id, _)) = &outcome.result {
+            written_sessions.push(*session_id);
+        }
+
+        let imported = match outcome.result {
+            Ok(ImportAction::Skipped(existing)) => {
+                files_skipped.push(format!("{}; Duplicate of {}", &path, session_description(&existing)));
+                false
+            },
+            Ok(ImportAction::Imported(session_id)) => {
+                files_imported.push(format!("{}; Session {}", &path, session_id));
+                true
+            },
+            Ok(ImportAction::Replaced(session_id)) => {
+                files_imported.push(format!("{}; Replaced session {}", &path, session_id));
+                true
+            },
+            Ok(ImportAction::KeptBoth(session_id, existing)) => {
+                files_imported.push(format!("{}; Session {} kept alongside {}", &path, session_id, session_description(&existing)));
+                true
+            },
+            Err(error) => {
+                errors.push(format!("Failed to import session {}; Error: {}", &path, error));
+                false
+            }
+        };
+
+        if imported {
+            if let Err(error) = fs::remove_file(&path) {
+                errors.push(format!("Failed to remove imported file {}; Error: {}", &path, error));
+            }
+        }
+    }
+
+    // The boards are complete again only after the import ended
+    if let Err(error) = store.end_import() {
+        errors.push(format!("Failed to build the personal records again; Error: {}", error));
+    }
+
+    for session_id in written_sessions {
+        match new_personal_records(store, athlete.id, session_id) {
+            Ok(records) => new_records.extend(records),
+            Err(error) => errors.push(format!("Failed to read the personal records of session {}; Error: {}", session_id, error))
+        }
+    }
+
+    println!("Errors: {:#?}", errors);
+    println!("Files skipped: {:#?}", files_skipped);
+    println!("Files imported: {:#?}", files_imported);
+    println!("New personal records: {:#?}", new_records);
+
+    return Ok(());
+}
+
+/// Prints the latest imports, `--failed` leaves out the files which were imported or skipped
+pub fn log_command(store: &dyn SessionStore, args: &[String]) -> Result<()> {
+    let limit = match util::flag_value(args, "--limit") {
+        Some(value) => value.parse::<i64>()?,
+        None => 20
+    };
+    let failed_only = args.iter().any(|x| x == "--failed");
+
+    let entries = store.get_import_log(if failed_only { i64::MAX } else { limit })?;
+
+    for entry in entries.iter().filter(|x| !failed_only || x.is_failed()).take(limit.max(0) as usize) {
+        println!("{}: {}", entry.id.unwrap_or(0), log_entry_as_string(entry));
+    }
+
+    return Ok(());
+}
+
+pub fn log_entry_as_string(entry: &ImportLogEntry) -> String {
+    let result = match (&entry.error, entry.session_id) {
+        (Some(error), _) => error.clone(),
+        (None, Some(session_id)) => format!("session {}", session_id),
+        (None, None) => String::new()
+    };
+
+    return format!("{} {} {}; {}", timestamp_as_string(entry.imported_at), entry.outcome, entry.path, result);
+}
+
+/// Reads the files into memory without importing them, the files are left in the directory.
+/// The bike rules of the store are applied to the files
+pub fn preview(store: &dyn SessionStore, dir: &String, athlete: Athlete) -> Result<MemoryStore> {
+    let athlete_id = athlete.id;
+    let mut store = MemoryStore::new(vec![athlete], store.get_bikes()?);
+
+    let files = fs::read_dir(dir)?;
+
+    store.begin_import();
+    for file in files.flatten() {
+        let path = file.path().display().to_string();
+
+        if let Err(error) = import_file(&mut store, &path, ImportPolicy::Skip, athlete_id).result {
+            println!("Failed to read session {}; Error: {}", &path, error);
+        }
+    }
+    store.end_import()?;
+
+    return Ok(store);
+}
+
+/// Runs the parser again over every archived FIT file and updates the sessions in place
+pub fn reprocess(store: &mut dyn SessionStore) -> Result<()> {
+    let mut errors: Vec<String> = Vec::new();
+    let mut sessions_reprocessed: Vec<i64> = Vec::new();
+
+    println!("Start reprocessing");
+
+    let session_ids = store.get_archived_session_ids()?;
+
+    store.begin_import();
+    for session_id in session_ids {
+        let result = store.get_source_file(session_id)
+            .and_then(|source_file| parser::parse(&source_file.bytes))
+            .and_then(|session| store.reprocess_session(session_id, session));
+
+        match result {
+            Ok(_) => sessions_reprocessed.push(session_id),
+            Err(error) => errors.push(format!("Failed to reprocess session {}; Error: {}", session_id, error))
+        }
+    }
+
+    if let Err(error) = store.end_import() {
+        errors.push(format!("Failed to build the personal records again; Error: {}", error));
+    }
+
+    println!("Errors: {:#?}", errors);
+    println!("Sessions reprocessed: {:?}", sessions_reprocessed);
+
+    return Ok(());
+}
+
+/// Personal records set by an imported session, records beaten by a session imported earlier are marked
+fn new_personal_records(store: &dyn SessionStore, athlete_id: i64, session_id: i64) -> Result<Vec<String>> {
+    let board = store.get_personal_records(athlete_id)?;
+
+    let records = board.for_session(session_id).iter()
+        .map(|x| format!("Session {}; {}{}"
+            , session_id
+            , x.description()
+            , if board.is_current(x) { "" } else { " (beaten by a later session)" }))
+        .collect();
+
+    return Ok(records);
+}
+
+fn session_description(session: &Session) -> String {
+    return format!("session {} ({} {})"
+        , session.id.unwrap_or(0)
+        , timestamp_as_string(session.start_time)
+        , session.sub_sport);
+}
@@ -1,8 +1,11 @@
 use std::env;
-use std::fs;
 use anyhow::Result;
 use app::App;
-use dotenv;
+use athlete::Athlete;
+use db::SqliteStore;
+use store::SessionStore;
+use import::ImportPolicy;
+use summary::{Summary, DateRange, Period, ROLLING_WINDOWS};
 
 mod app;
 mod ui;
@@ -10,53 +13,176 @@ mod db;
 mod parser;
 mod util;
 mod summary;
+mod import;
+mod gear;
+mod store;
+mod athlete;
+mod stream;
+mod threshold;
+mod power;
+mod personal_record;
+mod pmc;
+mod power_curve;
+mod critical_power;
+mod zones;
+mod ftp_estimate;
 
 fn main() -> Result<()> {
     dotenv::dotenv().expect("Failed to read .env file");
 
-    let mut errors: Vec<String> = Vec::new();
-    let mut files_imported: Vec<String> = Vec::new();
-
     let trainer_road_path = dotenv::var("FIT_FILE_DIR").unwrap();
     let args: Vec<String> = env::args().collect();
 
+    db::create()?;
+    let mut store = SqliteStore::default();
+
+    if args.len() > 2 && args[1] == "import" && args[2] == "log" {
+        return import::log_command(&store, &args[2..]);
+    }
+
     if args.len() > 1 && args[1] == "import" {
         // TODO: add import as MTB, road or indoor_cycling parameters
-        db::create()?;
+        let policy = ImportPolicy::from_args(&args[2..])?;
+        let athlete = Athlete::from_args(&store, &args[2..])?;
+        import::run(&mut store, &trainer_road_path, policy, &athlete)?;
+
+        return Ok(());
+    }
+
+    if args.len() > 1 && args[1] == "preview" {
+        let athlete = Athlete::from_args(&store, &args[2..])?;
+        let preview = import::preview(&store, &trainer_road_path, athlete)?;
+        App::new(Box::new(preview)).start_ui()?;
+
+        return Ok(());
+    }
+
+    if args.len() > 1 && args[1] == "reprocess" {
+        import::reprocess(&mut store)?;
+
+        return Ok(());
+    }
+
+    if args.len() > 2 && args[1] == "update" {
+        let mut session = store.get_session(args[2].parse::<i64>()?)?;
+
+        if let Some(value) = util::flag_value(&args, "--sub-sport") {
+            session.sub_sport = value.to_owned();
+        }
+
+        if let Some(value) = util::flag_value(&args, "--title") {
+            session.title = Some(value.to_owned());
+        }
+
+        if let Some(value) = util::flag_value(&args, "--notes") {
+            session.notes = Some(value.to_owned());
+        }
+
+        if let Some(value) = util::flag_value(&args, "--tags") {
+            session.tags = util::parse_tags(value);
+        }
+
+        if let Some(value) = util::flag_value(&args, "--bike") {
+            session.bike_id = if value == "none" { None } else { Some(value.parse::<i64>()?) };
+        }
+
+        store.update_session(&session)?;
+        println!("Session {} updated", args[2]);
+
+        return Ok(());
+    }
 
-        println!("{}", "Start import");
+    if args.len() > 2 && args[1] == "delete" {
+        store.delete_session(args[2].parse::<i64>()?)?;
+        println!("Session {} deleted", args[2]);
 
-        for file in fs::read_dir(trainer_road_path).unwrap() {
-            let path = file.unwrap().path().display().to_string();
-            println!("{}", path);
+        return Ok(());
+    }
+
+    if args.len() > 2 && args[1] == "search" {
+        let athlete = Athlete::from_args(&store, &args[2..])?;
+        let query = util::positional_args(&args[2..]).join(" ");
+
+        for session in store.search_sessions(athlete.id, &query)? {
+            println!("{}: {}", session.id.unwrap_or(0), ui::util::sessio_to_string(&session));
+        }
+
+        return Ok(());
+    }
+
+    if args.len() > 1 && (args[1] == "bike" || args[1] == "component") {
+        return gear::command(&mut store, &args[1..]);
+    }
+
+    if args.len() > 1 && args[1] == "threshold" {
+        return threshold::command(&mut store, &args[1..]);
+    }
+
+    if args.len() > 1 && args[1] == "curve" {
+        return power_curve::command(&store, &args[2..]);
+    }
+
+    if args.len() > 1 && args[1] == "cp" {
+        return critical_power::command(&store, &args[2..]);
+    }
+
+    if args.len() > 1 && args[1] == "zones" {
+        return zones::command(&store, &args[2..]);
+    }
+
+    if args.len() > 1 && args[1] == "pmc" {
+        return pmc::command(&store, &args[1..]);
+    }
+
+    if args.len() > 1 && args[1] == "athlete" {
+        return athlete::command(&mut store, &args[1..]);
+    }
+
+    if args.len() > 1 && args[1] == "summary" {
+        let positional = util::positional_args(&args[2..]);
+        let athlete = Athlete::from_args(&store, &args[2..])?;
 
-            let session = parser::init(&path);
-            if !session.is_ok() {
-                let err = session.err();
-                errors.push(format!("Error: {}", err.unwrap()));
-                continue;
+        if positional.first().map(|x| x.as_str()) == Some("rolling") {
+            for days in ROLLING_WINDOWS {
+                print_summary(&format!("last {} days", days), &Summary::rolling(&store, athlete.id, days)?);
             }
 
-            let session_insert_response = db::insert_session(session.unwrap());
+            return Ok(());
+        }
+
+        let range = DateRange::from_args(&positional)?;
 
-            if session_insert_response.is_ok() {
-                files_imported.push(String::from(&path));
-                fs::remove_file(path)?;
-            } else {
-                let error = session_insert_response.err();
-                errors.push(format!("Failed to import session {}; Error: {}", &path, error.unwrap()));
+        if let Some(value) = util::flag_value(&args, "--by") {
+            for period_summary in Summary::by_period(&store, athlete.id, value.parse::<Period>()?, &range)? {
+                print_summary(&period_summary.label, &period_summary.summary);
             }
+
+            return Ok(());
+        }
+
+        let overall = Summary::overall(&store, athlete.id, &range)?;
+        print_summary("overall", &overall);
+
+        for detailed in Summary::detailed(&store, athlete.id, &range)? {
+            print_summary(&detailed.sub_sport.clone().unwrap_or_default(), &detailed);
         }
 
-        println!("Errors: {:#?}", errors);
-        println!("Files imported: {:#?}", files_imported);
-        
         return Ok(());
     }
 
-    let app = App::new();
+    let app = App::new(Box::new(store));
     app.start_ui()?;
 
     return Ok(());
 }
 
+fn print_summary(title: &str, summary: &Summary) {
+    println!("{}: {} rides, {}, {}, {}, {}, {:.0} TSS"
+        , title
+        , summary.rides_count
+        , ui::util::distance_as_string(summary.total_distance)
+        , ui::util::moving_time_to_hour_minute_string(summary.total_time)
+        , ui::util::ascent_as_string(summary.total_ascent)
+        , ui::util::work_as_string(summary.total_work)
+        , summary.total_training_stress);
+}
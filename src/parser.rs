@@ -1,13 +1,14 @@
 use fitparser::{self, FitDataRecord, FitDataField, Value};
 use fitparser::profile::MesgNum;
 use core::fmt;
-use std::fs::File;
+use std::fs;
 use anyhow::{Result, Context, bail};
+use sha2::{Digest, Sha256};
 
-// todo: alse set equivalent strings like in fieldName
-enum SubSport {
-    IndoorCycling
-}
+use crate::power::{self, TrainingLoad};
+
+/// Altitude changes smaller than this are treated as noise of the altimeter, in meters
+const CLIMB_NOISE: f64 = 1.0;
 
 #[derive(Debug)]
 enum FieldName {
@@ -25,12 +26,27 @@ enum FieldName {
     Power,
     Timestamp,
     Distance,
-    HeartRate
+    HeartRate,
+    Cadence,
+    Speed,
+    EnhancedSpeed,
+    Altitude,
+    EnhancedAltitude,
+    PositionLat,
+    PositionLong,
+    FunctionalThresholdPower,
+    ThresholdHeartRate,
+    MaxHeartRate,
+    Weight,
+    TotalAscent,
+    TotalWork
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct Session {
-    pub id: Option<i16>,
+    pub id: Option<i64>,
+    /// Stable identifier for exports and links, assigned when the session is stored
+    pub uuid: Option<String>,
     pub start_time: i64,
     pub total_elapsed_time: f64,
     pub total_distance: f64,
@@ -41,14 +57,29 @@ pub struct Session {
     pub sport: String,
     pub sub_sport: String,
     pub avg_cadence: i64,
+    /// Meters
+    pub total_ascent: f64,
+    /// Joules
+    pub total_work: f64,
+    pub training_load: TrainingLoad,
     pub laps: Vec<Lap>,
     pub records: Vec<Record>,
-    pub serial_num: i64
+    pub serial_num: i64,
+    /// SHA-256 of the FIT file the session was imported from
+    pub file_hash: Option<String>,
+    pub title: Option<String>,
+    pub notes: Option<String>,
+    pub tags: Vec<String>,
+    pub bike_id: Option<i64>,
+    pub athlete_id: Option<i64>,
+    /// Read from the FIT file for the threshold history, not stored with the session
+    pub threshold_heart_rate: i64,
+    pub max_heart_rate: i64,
+    pub weight: f64
 }
 
 #[derive(Debug, Clone)]
 pub struct Lap {
-    pub id: Option<i16>,
     pub start_time: i64,
     pub avg_power: i64,
     pub avg_heart_rate: i64,
@@ -56,13 +87,21 @@ pub struct Lap {
     pub total_distance: f64
 }
 
-#[derive(Debug, Clone)]
+/// Records have no id of their own since they are stored as column streams,
+/// a record is identified by its session and its position in the session's records
+#[derive(Debug, Clone, Default)]
 pub struct Record {
-    pub id: Option<i16>,
     pub timestamp: i64,
     pub heart_rate: i64,
     pub power: i64,
-    pub distance: f64
+    pub cadence: i64,
+    pub distance: f64,
+    /// Meters per second
+    pub speed: f64,
+    pub altitude: f64,
+    /// Semicircles, 0 when the device had no GPS fix
+    pub position_lat: i64,
+    pub position_long: i64
 }
 
 impl fmt::Display for FieldName {
@@ -82,7 +121,20 @@ impl fmt::Display for FieldName {
             FieldName::Power => write!(f, "power"),
             FieldName::Timestamp => write!(f, "timestamp"),
             FieldName::Distance => write!(f, "distance"),
-            FieldName::HeartRate => write!(f, "heart_rate")
+            FieldName::HeartRate => write!(f, "heart_rate"),
+            FieldName::Cadence => write!(f, "cadence"),
+            FieldName::Speed => write!(f, "speed"),
+            FieldName::EnhancedSpeed => write!(f, "enhanced_speed"),
+            FieldName::Altitude => write!(f, "altitude"),
+            FieldName::EnhancedAltitude => write!(f, "enhanced_altitude"),
+            FieldName::PositionLat => write!(f, "position_lat"),
+            FieldName::PositionLong => write!(f, "position_long"),
+            FieldName::FunctionalThresholdPower => write!(f, "functional_threshold_power"),
+            FieldName::ThresholdHeartRate => write!(f, "threshold_heart_rate"),
+            FieldName::MaxHeartRate => write!(f, "max_heart_rate"),
+            FieldName::Weight => write!(f, "weight"),
+            FieldName::TotalAscent => write!(f, "total_ascent"),
+            FieldName::TotalWork => write!(f, "total_work")
         }
     }
 }
@@ -99,7 +151,9 @@ impl <'a>FromIterator<&'a FitDataField> for Session {
                 || x.name() == FieldName::SubSport.to_string()
                 || x.name() == FieldName::AvgHeartRate.to_string()
                 || x.name() == FieldName::StartTime.to_string()
-                || x.name() == FieldName::ThresholdPower.to_string())
+                || x.name() == FieldName::ThresholdPower.to_string()
+                || x.name() == FieldName::TotalAscent.to_string()
+                || x.name() == FieldName::TotalWork.to_string())
             .collect::<Vec<&FitDataField>>();
 
         let sport_field = fields.iter()
@@ -113,6 +167,7 @@ impl <'a>FromIterator<&'a FitDataField> for Session {
 
         return Session {
             id: None,
+            uuid: None,
             start_time: get_number_value(&fields, FieldName::StartTime),
             total_elapsed_time: get_decimal_value(&fields, FieldName::TotalElapsedTime),
             total_distance: get_decimal_value(&fields, FieldName::TotalDistace),
@@ -123,7 +178,19 @@ impl <'a>FromIterator<&'a FitDataField> for Session {
             sport: sport_field.value().to_string(),
             sub_sport: sub_sport_field.value().to_string(),
             avg_cadence: get_number_value(&fields, FieldName::AvgCadence),
+            total_ascent: get_decimal_value(&fields, FieldName::TotalAscent),
+            total_work: get_decimal_value(&fields, FieldName::TotalWork),
+            training_load: TrainingLoad::default(),
             serial_num: 0,
+            file_hash: None,
+            title: None,
+            notes: None,
+            tags: Vec::new(),
+            bike_id: None,
+            athlete_id: None,
+            threshold_heart_rate: 0,
+            max_heart_rate: 0,
+            weight: 0.0,
             laps: Vec::new(),
             records: Vec::new()
         };
@@ -141,7 +208,6 @@ impl <'a>FromIterator<&'a FitDataField> for Lap {
             .collect::<Vec<&FitDataField>>();
 
         return Lap {
-            id: None,
             start_time: get_number_value(&fields, FieldName::StartTime),
             total_distance: get_decimal_value(&fields, FieldName::TotalDistace),
             avg_power: get_number_value(&fields, FieldName::AvgPower),
@@ -157,15 +223,37 @@ impl <'a>FromIterator<&'a FitDataField> for Record {
             .filter(|x| x.name() == FieldName::Power.to_string()
                 || x.name() == FieldName::Distance.to_string()
                 || x.name() == FieldName::Timestamp.to_string()
-                || x.name() == FieldName::HeartRate.to_string())
+                || x.name() == FieldName::HeartRate.to_string()
+                || x.name() == FieldName::Cadence.to_string()
+                || x.name() == FieldName::Speed.to_string()
+                || x.name() == FieldName::EnhancedSpeed.to_string()
+                || x.name() == FieldName::Altitude.to_string()
+                || x.name() == FieldName::EnhancedAltitude.to_string()
+                || x.name() == FieldName::PositionLat.to_string()
+                || x.name() == FieldName::PositionLong.to_string())
             .collect::<Vec<&FitDataField>>();
 
+        // Newer devices only write the enhanced fields which have a bigger range
+        let speed = match get_decimal_value(&fields, FieldName::EnhancedSpeed) {
+            value if value != 0.0 => value,
+            _ => get_decimal_value(&fields, FieldName::Speed)
+        };
+
+        let altitude = match get_decimal_value(&fields, FieldName::EnhancedAltitude) {
+            value if value != 0.0 => value,
+            _ => get_decimal_value(&fields, FieldName::Altitude)
+        };
+
         return Record {
-            id: None,
             timestamp: get_number_value(&fields, FieldName::Timestamp),
             distance: get_decimal_value(&fields, FieldName::Distance),
             power: get_number_value(&fields, FieldName::Power),
-            heart_rate: get_number_value(&fields, FieldName::HeartRate)
+            heart_rate: get_number_value(&fields, FieldName::HeartRate),
+            cadence: get_number_value(&fields, FieldName::Cadence),
+            speed,
+            altitude,
+            position_lat: get_number_value(&fields, FieldName::PositionLat),
+            position_long: get_number_value(&fields, FieldName::PositionLong)
         }
     }
 }
@@ -194,79 +282,145 @@ fn get_decimal_value(fields: &Vec<&FitDataField>, field_name: FieldName) -> f64
     return value;
 }
 
-pub fn init(path: &String) -> Result<Session> {
-    println!("Parsing FIT files using Profile version: {}", fitparser::profile::VERSION);
+pub fn read_file(path: &String) -> Result<Vec<u8>> {
     if !is_fit_file(path) {
         bail!("Not a fit file");
     }
 
-    let mut fp = File::open(path)
+    let bytes = fs::read(path)
         .context("Unable to open the file")?;
 
-    let fit_data = fitparser::from_reader(&mut fp)
+    return Ok(bytes);
+}
+
+/// Parses the contents of a FIT file, used for new imports and for reprocessing archived files
+pub fn parse(bytes: &[u8]) -> Result<Session> {
+    println!("Parsing FIT files using Profile version: {}", fitparser::profile::VERSION);
+
+    let fit_data = fitparser::from_bytes(bytes)
         .context("Failed to read data from file!")?;
 
-    let session_data: Session = get_session_data(&fit_data)
+    let mut session_data: Session = get_session_data(&fit_data)
         .context("Failed getting Session data")?;
+    session_data.file_hash = Some(file_hash(bytes));
 
     return Ok(session_data);
 }
 
-fn get_session_data(data: &Vec<FitDataRecord>) -> Result<Session> {
-    let session_data: &FitDataRecord = data.into_iter()
+/// SHA-256 of a FIT file as hex
+pub fn file_hash(bytes: &[u8]) -> String {
+    return format!("{:x}", Sha256::digest(bytes));
+}
+
+fn get_session_data(data: &[FitDataRecord]) -> Result<Session> {
+    let session_data: &FitDataRecord = data.iter()
         .find(|x| x.kind() == MesgNum::Session)
         .unwrap();
 
     let data_fields: Vec<&FitDataField> = session_data
         .fields()
-        .into_iter()
+        .iter()
         .collect();
 
-    let mut parsed_data = Session::from_iter(data_fields.into_iter());
+    let mut parsed_data = Session::from_iter(data_fields);
     parsed_data.serial_num = get_file_serial_num(data).unwrap();
+    read_athlete_settings(data, &mut parsed_data);
     parsed_data.laps = get_laps_data(data).unwrap();
     parsed_data.records = get_record_data(data).unwrap();
 
+    // Not every device writes the totals
+    if parsed_data.total_ascent == 0.0 {
+        parsed_data.total_ascent = total_ascent(&parsed_data.records);
+    }
+
+    if parsed_data.total_work == 0.0 {
+        parsed_data.total_work = power::total_work(&parsed_data.records);
+    }
+
+    // The threshold of the device until the store applies the athlete's threshold history
+    parsed_data.training_load = TrainingLoad::from_records(&parsed_data.records, parsed_data.avg_power)
+        .with_threshold(parsed_data.threshold_power, parsed_data.total_moving_time);
+
     return Ok(parsed_data);
 }
 
-fn get_laps_data(data: &Vec<FitDataRecord>) -> Result<Vec<Lap>> {
-    let laps_data: Vec<&FitDataRecord> = data.into_iter()
+/// Meters climbed, rises are counted once the altitude is above the lowest point since the last rise by more than the noise
+pub fn total_ascent(records: &[Record]) -> f64 {
+    let mut ascent = 0.0;
+    let mut reference: Option<f64> = None;
+
+    for altitude in records.iter().map(|x| x.altitude) {
+        match reference {
+            Some(value) if altitude - value > CLIMB_NOISE => {
+                ascent += altitude - value;
+                reference = Some(altitude);
+            },
+            Some(value) if altitude < value => reference = Some(altitude),
+            None => reference = Some(altitude),
+            _ => ()
+        }
+    }
+
+    return ascent;
+}
+
+fn get_laps_data(data: &[FitDataRecord]) -> Result<Vec<Lap>> {
+    let laps_data: Vec<&FitDataRecord> = data.iter()
         .filter(|x| x.kind() == MesgNum::Lap)
         .collect();
 
     let laps = laps_data.iter()
         .map(|&x| {
-            let lap_fields: Vec<&FitDataField> = x.fields().into_iter().collect();
-            return Lap::from_iter(lap_fields.into_iter());
+            let lap_fields: Vec<&FitDataField> = x.fields().iter().collect();
+            return Lap::from_iter(lap_fields);
         }).collect::<Vec<Lap>>();
 
     return Ok(laps);
 }
 
-fn get_record_data(data: &Vec<FitDataRecord>) -> Result<Vec<Record>> {
-    let record_data: Vec<&FitDataRecord> = data.into_iter()
+fn get_record_data(data: &[FitDataRecord]) -> Result<Vec<Record>> {
+    let record_data: Vec<&FitDataRecord> = data.iter()
         .filter(|x| x.kind() == MesgNum::Record)
         .collect();
 
     let records = record_data.iter()
         .map(|&x| {
-            let record_fields: Vec<&FitDataField> = x.fields().into_iter().collect();
-            return Record::from_iter(record_fields.into_iter());
+            let record_fields: Vec<&FitDataField> = x.fields().iter().collect();
+            return Record::from_iter(record_fields);
         }).collect::<Vec<Record>>();
 
     return Ok(records);
 }
 
-fn get_file_serial_num(data: &Vec<FitDataRecord>) -> Result<i64> {
-    let file_data: Vec<&FitDataRecord> = data.into_iter()
+/// Thresholds and weight the device had configured when the session was recorded
+fn read_athlete_settings(data: &[FitDataRecord], session: &mut Session) {
+    let fields_of = |kind: MesgNum| -> Vec<&FitDataField> {
+        return data.iter()
+            .filter(|x| x.kind() == kind)
+            .flat_map(|x| x.fields().iter())
+            .collect();
+    };
+
+    let zones_target = fields_of(MesgNum::ZonesTarget);
+    let user_profile = fields_of(MesgNum::UserProfile);
+
+    if session.threshold_power == 0 {
+        session.threshold_power = get_number_value(&zones_target, FieldName::FunctionalThresholdPower);
+    }
+    session.threshold_heart_rate = get_number_value(&zones_target, FieldName::ThresholdHeartRate);
+    session.max_heart_rate = get_number_value(&zones_target, FieldName::MaxHeartRate);
+    session.weight = get_decimal_value(&user_profile, FieldName::Weight);
+}
+
+fn get_file_serial_num(data: &[FitDataRecord]) -> Result<i64> {
+    let file_data: Vec<&FitDataRecord> = data.iter()
         .filter(|x| x.kind() == MesgNum::FileId)
         .collect();
 
     let serial_num_value: Value = file_data.iter()
         .map(|x| {
             return x.fields()
-                .into_iter()
+                .iter()
                 .find(|x| x.name() == FieldName::SerialNumber.to_string())
                 .unwrap()
                 .value()
@@ -282,7 +436,7 @@ fn get_file_serial_num(data: &Vec<FitDataRecord>) -> Result<i64> {
     return Ok(serial_num);
 }
 
-fn is_fit_file(file: &String) -> bool {
+fn is_fit_file(file: &str) -> bool {
     if file.ends_with(".fit") {
         return true;
     }
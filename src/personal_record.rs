@@ -0,0 +1,301 @@
+use std::str::FromStr;
+use anyhow::{Result, bail};
+
+use crate::parser::{self, Record, Session};
+use crate::power;
+use crate::ui::util::{ascent_as_string, date_as_string, distance_as_string, moving_time_to_hour_minute_string};
+
+const FASTEST_DISTANCE: f64 = 40_000.0;
+
+/// Personal bests kept on the board, in the order they are shown
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RecordKind {
+    Power5s,
+    Power1min,
+    Power5min,
+    Power20min,
+    Power60min,
+    LongestRide,
+    BiggestClimb,
+    Fastest40km
+}
+
+pub const RECORD_KINDS: [RecordKind; 8] = [
+    RecordKind::Power5s,
+    RecordKind::Power1min,
+    RecordKind::Power5min,
+    RecordKind::Power20min,
+    RecordKind::Power60min,
+    RecordKind::LongestRide,
+    RecordKind::BiggestClimb,
+    RecordKind::Fastest40km
+];
+
+impl FromStr for RecordKind {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        return match RECORD_KINDS.iter().find(|x| x.as_str() == value) {
+            Some(kind) => Ok(*kind),
+            None => bail!("Unknown personal record '{}'", value)
+        };
+    }
+}
+
+impl RecordKind {
+    pub fn as_str(&self) -> &str {
+        return match self {
+            RecordKind::Power5s => "power_5s",
+            RecordKind::Power1min => "power_1min",
+            RecordKind::Power5min => "power_5min",
+            RecordKind::Power20min => "power_20min",
+            RecordKind::Power60min => "power_60min",
+            RecordKind::LongestRide => "longest_ride",
+            RecordKind::BiggestClimb => "biggest_climb",
+            RecordKind::Fastest40km => "fastest_40km"
+        };
+    }
+
+    pub fn label(&self) -> &str {
+        return match self {
+            RecordKind::Power5s => "5 s power",
+            RecordKind::Power1min => "1 min power",
+            RecordKind::Power5min => "5 min power",
+            RecordKind::Power20min => "20 min power",
+            RecordKind::Power60min => "60 min power",
+            RecordKind::LongestRide => "Longest ride",
+            RecordKind::BiggestClimb => "Biggest climb",
+            RecordKind::Fastest40km => "Fastest 40 km"
+        };
+    }
+
+    /// Fastest times are the lowest, every other record the highest
+    pub fn is_better(&self, value: f64, than: f64) -> bool {
+        return match self {
+            RecordKind::Fastest40km => value < than,
+            _ => value > than
+        };
+    }
+
+    /// Watts, meters or seconds depending on the kind
+    pub fn value_as_string(&self, value: f64) -> String {
+        return match self {
+            RecordKind::LongestRide => distance_as_string(value),
+            RecordKind::BiggestClimb => ascent_as_string(value),
+            RecordKind::Fastest40km => moving_time_to_hour_minute_string(value),
+            _ => format!("{:.0}W", value)
+        };
+    }
+
+    fn power_seconds(&self) -> Option<usize> {
+        return match self {
+            RecordKind::Power5s => Some(5),
+            RecordKind::Power1min => Some(60),
+            RecordKind::Power5min => Some(300),
+            RecordKind::Power20min => Some(1200),
+            RecordKind::Power60min => Some(3600),
+            _ => None
+        };
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PersonalRecord {
+    pub id: Option<i64>,
+    pub athlete_id: i64,
+    pub session_id: i64,
+    pub kind: RecordKind,
+    pub value: f64,
+    /// Start time of the session which set the record
+    pub achieved_at: i64
+}
+
+impl PersonalRecord {
+    pub fn description(&self) -> String {
+        return format!("{} {}", self.kind.label(), self.kind.value_as_string(self.value));
+    }
+}
+
+/// Best values of one session, the board is built from these
+#[derive(Debug, Clone)]
+pub struct SessionBests {
+    pub session_id: i64,
+    pub start_time: i64,
+    pub bests: Vec<(RecordKind, f64)>
+}
+
+/// Every personal record of one athlete, a record is only kept while nothing before it was better
+#[derive(Debug, Clone, Default)]
+pub struct PersonalRecordBoard {
+    pub records: Vec<PersonalRecord>
+}
+
+impl PersonalRecordBoard {
+    pub fn new(mut records: Vec<PersonalRecord>) -> Self {
+        records.sort_by_key(|x| (x.achieved_at, x.id));
+        return Self { records };
+    }
+
+    /// Goes through every session of the athlete oldest first, a session sets a record when it beats all the sessions before it.
+    /// The board is always built again as a whole, so deleting or replacing a session brings back the records it had beaten
+    pub fn from_sessions(athlete_id: i64, mut sessions: Vec<SessionBests>) -> Self {
+        sessions.sort_by_key(|x| (x.start_time, x.session_id));
+        let mut records: Vec<PersonalRecord> = Vec::new();
+
+        for session in sessions {
+            for (kind, value) in session.bests {
+                let is_record = match records.iter().rev().find(|x| x.kind == kind) {
+                    Some(best) => kind.is_better(value, best.value),
+                    None => true
+                };
+
+                if is_record {
+                    records.push(PersonalRecord {
+                        id: None,
+                        athlete_id,
+                        session_id: session.session_id,
+                        kind,
+                        value,
+                        achieved_at: session.start_time
+                    });
+                }
+            }
+        }
+
+        return Self { records };
+    }
+
+    /// Records a new session sets when it is added after every record on the board, none when it is older than one of them
+    /// and the board has to be built again from every session
+    pub fn appended(&self, athlete_id: i64, session: &SessionBests) -> Option<Vec<PersonalRecord>> {
+        if self.records.iter().any(|x| x.achieved_at > session.start_time) {
+            return None;
+        }
+
+        let current = self.current();
+        let records = session.bests.iter()
+            .filter(|(kind, value)| current.iter()
+                .find(|x| x.kind == *kind)
+                .is_none_or(|best| kind.is_better(*value, best.value)))
+            .map(|(kind, value)| PersonalRecord {
+                id: None,
+                athlete_id,
+                session_id: session.session_id,
+                kind: *kind,
+                value: *value,
+                achieved_at: session.start_time
+            })
+            .collect();
+
+        return Some(records);
+    }
+
+    /// Best record of every kind which has one
+    pub fn current(&self) -> Vec<&PersonalRecord> {
+        return RECORD_KINDS.iter()
+            .filter_map(|kind| self.records.iter().rev().find(|x| x.kind == *kind))
+            .collect();
+    }
+
+    pub fn is_current(&self, record: &PersonalRecord) -> bool {
+        return self.current().iter()
+            .any(|x| x.kind == record.kind && x.session_id == record.session_id);
+    }
+
+    pub fn for_session(&self, session_id: i64) -> Vec<&PersonalRecord> {
+        return self.records.iter()
+            .filter(|x| x.session_id == session_id)
+            .collect();
+    }
+
+    /// Records with the date and whether they still stand, newest first
+    pub fn history(&self) -> Vec<String> {
+        return self.records.iter()
+            .rev()
+            .map(|x| format!("{} {}{}"
+                , date_as_string(x.achieved_at)
+                , x.description()
+                , if self.is_current(x) { "" } else { " (beaten)" }))
+            .collect();
+    }
+}
+
+/// Best values of every record kind the session has data for
+pub fn session_bests(session: &Session) -> Vec<(RecordKind, f64)> {
+    let power = power::power_per_second(&session.records);
+
+    let mut bests: Vec<(RecordKind, f64)> = RECORD_KINDS.iter()
+        .filter_map(|kind| kind.power_seconds()
+            .and_then(|seconds| power::best_average_power(&power, seconds))
+            .map(|value| (*kind, value)))
+        .collect();
+
+    bests.push((RecordKind::LongestRide, session.total_distance));
+    // Sessions stored before the totals were kept have no ascent
+    bests.push((RecordKind::BiggestClimb, match session.total_ascent {
+        value if value > 0.0 => value,
+        _ => parser::total_ascent(&session.records)
+    }));
+
+    if let Some(value) = fastest_distance(&session.records, FASTEST_DISTANCE) {
+        bests.push((RecordKind::Fastest40km, value));
+    }
+
+    return bests.into_iter()
+        .filter(|(_, value)| *value > 0.0)
+        .collect();
+}
+
+/// Fewest seconds the distance was covered in, none when the session is shorter
+fn fastest_distance(records: &[Record], distance: f64) -> Option<f64> {
+    let mut fastest: Option<i64> = None;
+    let mut start_idx = 0;
+
+    for end in records {
+        while start_idx + 1 < records.len() && end.distance - records[start_idx + 1].distance >= distance {
+            start_idx += 1;
+        }
+
+        let start = &records[start_idx];
+        if end.distance - start.distance >= distance {
+            let seconds = end.timestamp - start.timestamp;
+            fastest = Some(fastest.map_or(seconds, |x| x.min(seconds)));
+        }
+    }
+
+    return fastest.map(|x| x as f64);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bests(session_id: i64, start_time: i64, power_5s: f64, distance: f64) -> SessionBests {
+        return SessionBests {
+            session_id,
+            start_time,
+            bests: vec![(RecordKind::Power5s, power_5s), (RecordKind::LongestRide, distance)]
+        };
+    }
+
+    #[test]
+    fn appending_a_newer_session_matches_the_rebuilt_board() {
+        let sessions = vec![bests(1, 1000, 800.0, 50000.0), bests(2, 2000, 900.0, 40000.0)];
+        let board = PersonalRecordBoard::from_sessions(1, sessions.clone());
+
+        let newer = bests(3, 3000, 850.0, 60000.0);
+        let appended = board.appended(1, &newer).unwrap();
+        let rebuilt = PersonalRecordBoard::from_sessions(1, [sessions, vec![newer]].concat());
+
+        assert_eq!(appended.len(), 1);
+        assert_eq!((appended[0].kind, appended[0].session_id), (RecordKind::LongestRide, 3));
+        assert_eq!(board.records.len() + appended.len(), rebuilt.records.len());
+    }
+
+    #[test]
+    fn an_older_session_needs_a_rebuild() {
+        let board = PersonalRecordBoard::from_sessions(1, vec![bests(2, 2000, 900.0, 40000.0)]);
+
+        assert!(board.appended(1, &bests(3, 1000, 950.0, 30000.0)).is_none());
+    }
+}
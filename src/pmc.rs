@@ -0,0 +1,128 @@
+use anyhow::{Context, Result, bail};
+use chrono::{Duration, Local};
+
+use crate::athlete::Athlete;
+use crate::store::SessionStore;
+use crate::summary::{DateRange, local_date, local_midnight};
+use crate::ui::util::date_as_string;
+use crate::util::flag_value;
+
+/// Days printed by the `pmc` command when `--days` isn't given
+const DEFAULT_PRINTED_DAYS: usize = 14;
+
+/// Days the fitness and fatigue averages are weighted over
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TimeConstants {
+    pub fitness_days: i64,
+    pub fatigue_days: i64
+}
+
+impl Default for TimeConstants {
+    fn default() -> Self {
+        return Self { fitness_days: 42, fatigue_days: 7 };
+    }
+}
+
+impl TimeConstants {
+    /// Read from `PMC_FITNESS_DAYS` and `PMC_FATIGUE_DAYS` in .env, the usual 42 and 7 days when they aren't set
+    pub fn from_env() -> Result<Self> {
+        let defaults = Self::default();
+
+        return Self::new(
+            days_value(dotenv::var("PMC_FITNESS_DAYS").ok(), defaults.fitness_days)?,
+            days_value(dotenv::var("PMC_FATIGUE_DAYS").ok(), defaults.fatigue_days)?);
+    }
+
+    /// The .env values overridden by `--ctl` and `--atl`
+    pub fn from_args(args: &[String]) -> Result<Self> {
+        let constants = Self::from_env()?;
+
+        return Self::new(
+            days_value(flag_value(args, "--ctl").cloned(), constants.fitness_days)?,
+            days_value(flag_value(args, "--atl").cloned(), constants.fatigue_days)?);
+    }
+
+    fn new(fitness_days: i64, fatigue_days: i64) -> Result<Self> {
+        if fitness_days < 1 || fatigue_days < 1 {
+            bail!("Time constants have to be at least one day");
+        }
+
+        return Ok(Self { fitness_days, fatigue_days });
+    }
+}
+
+fn days_value(value: Option<String>, default: i64) -> Result<i64> {
+    return match value {
+        Some(days) => days.parse::<i64>().with_context(|| format!("Invalid number of days '{}'", days)),
+        None => Ok(default)
+    };
+}
+
+/// Training load of one day and the fitness, fatigue and form after it
+#[derive(Debug, Clone)]
+pub struct PmcDay {
+    /// Start of the day
+    pub day: i64,
+    /// TSS of the day's sessions
+    pub load: f64,
+    /// Chronic training load (CTL)
+    pub fitness: f64,
+    /// Acute training load (ATL)
+    pub fatigue: f64,
+    /// Training stress balance (TSB), fitness minus fatigue of the day before so a hard day doesn't lower its own form
+    pub form: f64
+}
+
+/// Every day from the athlete's first session to today, days without sessions have no load
+pub fn performance(store: &dyn SessionStore, athlete_id: i64, constants: TimeConstants) -> Result<Vec<PmcDay>> {
+    let today = Local::now().date_naive();
+    let loads = store.get_daily_summaries(athlete_id, &DateRange { start: 0, end: local_midnight(today + Duration::days(1)) })?;
+
+    let first_day = match loads.first() {
+        Some((day, _)) => local_date(*day),
+        None => return Ok(Vec::new())
+    };
+
+    let mut days: Vec<PmcDay> = Vec::new();
+    let mut loads = loads.iter().peekable();
+    let (mut fitness, mut fatigue) = (0.0, 0.0);
+
+    for date in first_day.iter_days().take_while(|x| *x <= today) {
+        let day = local_midnight(date);
+        let load = match loads.next_if(|(load_day, _)| *load_day == day) {
+            Some((_, summary)) => summary.total_training_stress,
+            None => 0.0
+        };
+
+        let form = fitness - fatigue;
+        fitness += (load - fitness) / constants.fitness_days as f64;
+        fatigue += (load - fatigue) / constants.fatigue_days as f64;
+
+        days.push(PmcDay { day, load, fitness, fatigue, form });
+    }
+
+    return Ok(days);
+}
+
+/// Handles `pmc [--days N] [--ctl N] [--atl N]`, printing the latest days
+pub fn command(store: &dyn SessionStore, args: &[String]) -> Result<()> {
+    let athlete = Athlete::from_args(store, args)?;
+    let constants = TimeConstants::from_args(args)?;
+    let count = match flag_value(args, "--days") {
+        Some(value) => value.parse::<usize>()?,
+        None => DEFAULT_PRINTED_DAYS
+    };
+
+    let days = performance(store, athlete.id, constants)?;
+
+    for day in days.iter().skip(days.len().saturating_sub(count)) {
+        println!("{}: TSS {:.0}, fitness {:.1}, fatigue {:.1}, form {:.1}"
+            , date_as_string(day.day)
+            , day.load
+            , day.fitness
+            , day.fatigue
+            , day.form);
+    }
+
+    return Ok(());
+}
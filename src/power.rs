@@ -0,0 +1,236 @@
+use crate::parser::Record;
+
+/// Longest gap between records which is filled with the power before it, smart recording devices write a record every few seconds
+const MAX_FILLED_GAP: i64 = 10;
+/// Longer gaps are stops at 0 W, cut to this many seconds so a wrong timestamp can't make the series huge
+const MAX_STOP_SECONDS: i64 = 60 * 60;
+
+/// Second of the series every record starts at, in the order of the records. Records without a timestamp have none
+pub fn record_seconds(records: &[Record]) -> Vec<Option<usize>> {
+    let mut timed: Vec<(usize, i64)> = records.iter()
+        .enumerate()
+        .filter(|(_, x)| x.timestamp > 0)
+        .map(|(idx, x)| (idx, x.timestamp))
+        .collect();
+    timed.sort_by_key(|x| x.1);
+
+    let mut seconds = vec![None; records.len()];
+    let mut second = 0;
+
+    for (position, (idx, timestamp)) in timed.iter().enumerate() {
+        if position > 0 {
+            second += (timestamp - timed[position - 1].1).min(MAX_STOP_SECONDS) as usize;
+        }
+        seconds[*idx] = Some(second);
+    }
+
+    return seconds;
+}
+
+/// Seconds every record stands for in the order of the records, the same as in `power_per_second`:
+/// the gap to the next record when it is filled, otherwise a second. Records without a timestamp stand for none
+pub fn record_durations(records: &[Record]) -> Vec<f64> {
+    let seconds = record_seconds(records);
+    let mut starts: Vec<usize> = seconds.iter().flatten().copied().collect();
+    starts.sort();
+
+    return seconds.iter()
+        .map(|second| match second {
+            Some(start) => {
+                let position = starts.partition_point(|x| x <= start);
+                match starts.get(position) {
+                    Some(next) if next - start <= MAX_FILLED_GAP as usize => (next - start) as f64,
+                    _ => 1.0
+                }
+            },
+            None => 0.0
+        })
+        .collect();
+}
+
+/// Power of every second from the first record to the last. Gaps up to 10 s keep the power of the record before them,
+/// longer ones are stops at 0 W
+pub fn power_per_second(records: &[Record]) -> Vec<f64> {
+    let mut timed: Vec<(usize, f64)> = record_seconds(records).iter()
+        .zip(records.iter())
+        .filter_map(|(second, record)| second.map(|x| (x, record.power.max(0) as f64)))
+        .collect();
+    timed.sort_by_key(|x| x.0);
+
+    let length = match timed.last() {
+        Some((second, _)) => second + 1,
+        None => return Vec::new()
+    };
+
+    let mut power = vec![0.0; length];
+
+    for (position, (second, value)) in timed.iter().enumerate() {
+        let next = timed.get(position + 1).map(|x| x.0).unwrap_or(length);
+        // A second with two records keeps the later one
+        let filled = if next - second <= MAX_FILLED_GAP as usize { next } else { second + 1 };
+
+        for slot in power.iter_mut().take(filled).skip(*second) {
+            *slot = *value;
+        }
+    }
+
+    return power;
+}
+
+/// Highest average power held for the given number of seconds, none when the session is shorter
+pub fn best_average_power(power: &[f64], seconds: usize) -> Option<f64> {
+    if seconds == 0 || power.len() < seconds {
+        return None;
+    }
+
+    let mut sum: f64 = power[..seconds].iter().sum();
+    let mut best = sum;
+
+    for idx in seconds..power.len() {
+        sum += power[idx] - power[idx - seconds];
+        best = best.max(sum);
+    }
+
+    return Some(best / seconds as f64);
+}
+
+/// Joules done over the session
+pub fn total_work(records: &[Record]) -> f64 {
+    return power_per_second(records).iter().sum();
+}
+
+/// Seconds of the rolling average normalized power is calculated from
+const NORMALIZED_POWER_WINDOW: usize = 30;
+
+/// Fourth root of the mean of the 30 s rolling average to the fourth power, none when the session is shorter
+pub fn normalized_power(power: &[f64]) -> Option<f64> {
+    if power.len() < NORMALIZED_POWER_WINDOW {
+        return None;
+    }
+
+    let mut sum: f64 = power[..NORMALIZED_POWER_WINDOW].iter().sum();
+    let mut total = (sum / NORMALIZED_POWER_WINDOW as f64).powi(4);
+
+    for idx in NORMALIZED_POWER_WINDOW..power.len() {
+        sum += power[idx] - power[idx - NORMALIZED_POWER_WINDOW];
+        total += (sum / NORMALIZED_POWER_WINDOW as f64).powi(4);
+    }
+
+    let count = power.len() - NORMALIZED_POWER_WINDOW + 1;
+    return Some((total / count as f64).powf(0.25));
+}
+
+/// Load of a session, values which can't be calculated are 0
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct TrainingLoad {
+    pub normalized_power: f64,
+    /// Normalized power over the threshold power
+    pub intensity_factor: f64,
+    /// Normalized power over the average power
+    pub variability_index: f64,
+    pub training_stress_score: f64
+}
+
+impl TrainingLoad {
+    /// Values which only depend on the session, the others are added with `with_threshold`
+    pub fn from_records(records: &[Record], avg_power: i64) -> Self {
+        let normalized_power = normalized_power(&power_per_second(records)).unwrap_or(0.0);
+
+        return Self {
+            normalized_power,
+            variability_index: if avg_power > 0 { normalized_power / avg_power as f64 } else { 0.0 },
+            ..Self::default()
+        };
+    }
+
+    /// Intensity factor and TSS at the threshold power which applied to the session
+    pub fn with_threshold(self, threshold_power: i64, moving_time: f64) -> Self {
+        if threshold_power <= 0 {
+            return Self { intensity_factor: 0.0, training_stress_score: 0.0, ..self };
+        }
+
+        let intensity_factor = self.normalized_power / threshold_power as f64;
+
+        return Self {
+            intensity_factor,
+            training_stress_score: moving_time * self.normalized_power * intensity_factor / (threshold_power as f64 * 3600.0) * 100.0,
+            ..self
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(timestamp: i64, power: i64) -> Record {
+        return Record { timestamp, power, ..Record::default() };
+    }
+
+    #[test]
+    fn power_per_second_fills_smart_recording_gaps() {
+        let records = vec![record(100, 200), record(103, 300), record(104, 250)];
+        assert_eq!(power_per_second(&records), vec![200.0, 200.0, 200.0, 300.0, 250.0]);
+    }
+
+    #[test]
+    fn power_per_second_leaves_stops_at_zero() {
+        let records = vec![record(100, 200), record(130, 300)];
+        let power = power_per_second(&records);
+
+        assert_eq!(power.len(), 31);
+        assert_eq!(power[0], 200.0);
+        assert!(power[1..30].iter().all(|x| *x == 0.0));
+        assert_eq!(power[30], 300.0);
+    }
+
+    #[test]
+    fn power_per_second_skips_missing_timestamps_and_sorts() {
+        let records = vec![record(0, 999), record(102, 300), record(100, 200), record(101, -5)];
+        assert_eq!(power_per_second(&records), vec![200.0, 0.0, 300.0]);
+    }
+
+    #[test]
+    fn power_per_second_cuts_long_stops() {
+        let records = vec![record(100, 200), record(100 + 10 * MAX_STOP_SECONDS, 300)];
+        assert_eq!(power_per_second(&records).len(), MAX_STOP_SECONDS as usize + 1);
+        assert!(power_per_second(&[]).is_empty());
+    }
+
+    #[test]
+    fn record_seconds_follow_the_record_order() {
+        let records = vec![record(105, 0), record(0, 0), record(100, 0)];
+        assert_eq!(record_seconds(&records), vec![Some(5), None, Some(0)]);
+    }
+
+    #[test]
+    fn record_durations_match_the_filled_gaps() {
+        let records = vec![record(103, 0), record(100, 0), record(0, 0), record(130, 0)];
+        assert_eq!(record_durations(&records), vec![1.0, 3.0, 0.0, 1.0]);
+    }
+
+    #[test]
+    fn best_average_power_finds_the_best_window() {
+        let power = vec![100.0, 300.0, 300.0, 100.0, 200.0];
+
+        assert_eq!(best_average_power(&power, 2), Some(300.0));
+        assert_eq!(best_average_power(&power, 5), Some(200.0));
+        assert_eq!(best_average_power(&power, 6), None);
+        assert_eq!(best_average_power(&power, 0), None);
+    }
+
+    #[test]
+    fn normalized_power_of_steady_power_is_the_power() {
+        let np = normalized_power(&vec![250.0; 600]).unwrap();
+        assert!((np - 250.0).abs() < 1e-9);
+        assert_eq!(normalized_power(&vec![250.0; 29]), None);
+    }
+
+    #[test]
+    fn normalized_power_is_above_average_for_intervals() {
+        let power: Vec<f64> = (0..1200).map(|x| if (x / 60) % 2 == 0 { 400.0 } else { 100.0 }).collect();
+        let np = normalized_power(&power).unwrap();
+
+        assert!(np > 250.0 && np < 400.0, "{}", np);
+    }
+}
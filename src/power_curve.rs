@@ -0,0 +1,157 @@
+use std::str::FromStr;
+use anyhow::{Result, bail};
+use chrono::{Datelike, Local};
+
+use crate::athlete::Athlete;
+use crate::parser::Record;
+use crate::power;
+use crate::store::SessionStore;
+use crate::summary::DateRange;
+use crate::ui::util::date_as_string;
+use crate::util::positional_args;
+
+/// Seconds the curve has a point for, spread evenly over the log time axis
+pub const CURVE_DURATIONS: [i64; 26] = [
+    1, 2, 3, 5, 10, 15, 20, 30, 45,
+    60, 90, 120, 180, 240, 300, 420, 600, 900, 1200, 1800, 2700,
+    3600, 5400, 7200, 10800, 14400
+];
+
+/// Best average power held for a duration, with the session it was held in
+#[derive(Debug, Clone)]
+pub struct PowerCurvePoint {
+    pub seconds: i64,
+    pub power: f64,
+    pub session_id: i64,
+    /// Start time of the session
+    pub achieved_at: i64
+}
+
+/// Mean-maximal power of one session or the best of many, shortest duration first
+#[derive(Debug, Clone, Default)]
+pub struct PowerCurve {
+    pub points: Vec<PowerCurvePoint>
+}
+
+impl PowerCurve {
+    /// Keeps the best point of every duration
+    pub fn merge(&mut self, other: PowerCurve) {
+        for point in other.points {
+            match self.points.iter_mut().find(|x| x.seconds == point.seconds) {
+                Some(existing) if existing.power >= point.power => (),
+                Some(existing) => *existing = point,
+                None => self.points.push(point)
+            }
+        }
+
+        self.points.sort_by_key(|x| x.seconds);
+    }
+}
+
+/// Seconds and watts of the session's curve, durations longer than the session are left out
+pub fn session_curve(records: &[Record]) -> Vec<(i64, f64)> {
+    let power = power::power_per_second(records);
+
+    return CURVE_DURATIONS.iter()
+        .filter_map(|seconds| power::best_average_power(&power, *seconds as usize).map(|value| (*seconds, value)))
+        .collect();
+}
+
+/// Date ranges the power curve view can show
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CurvePeriod {
+    Last42Days,
+    Season,
+    LastSeason,
+    AllTime
+}
+
+pub const CURVE_PERIODS: [CurvePeriod; 4] = [
+    CurvePeriod::Last42Days,
+    CurvePeriod::Season,
+    CurvePeriod::LastSeason,
+    CurvePeriod::AllTime
+];
+
+impl FromStr for CurvePeriod {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        return match CURVE_PERIODS.iter().find(|x| x.as_str() == value) {
+            Some(period) => Ok(*period),
+            None => bail!("Unknown power curve period '{}'", value)
+        };
+    }
+}
+
+impl CurvePeriod {
+    pub fn as_str(&self) -> &str {
+        return match self {
+            CurvePeriod::Last42Days => "last-42-days",
+            CurvePeriod::Season => "season",
+            CurvePeriod::LastSeason => "last-season",
+            CurvePeriod::AllTime => "all-time"
+        };
+    }
+
+    pub fn label(&self) -> &str {
+        return match self {
+            CurvePeriod::Last42Days => "Last 42 days",
+            CurvePeriod::Season => "This season",
+            CurvePeriod::LastSeason => "Last season",
+            CurvePeriod::AllTime => "All time"
+        };
+    }
+
+    /// Seasons are calendar years
+    pub fn range(&self) -> Result<DateRange> {
+        let now = Local::now();
+
+        return match self {
+            CurvePeriod::Last42Days => Ok(DateRange::rolling(42)),
+            CurvePeriod::Season => DateRange::year(now.year()),
+            CurvePeriod::LastSeason => DateRange::year(now.year() - 1),
+            CurvePeriod::AllTime => DateRange::custom(0, now.timestamp())
+        };
+    }
+
+    pub fn next(&self) -> Self {
+        let idx = CURVE_PERIODS.iter().position(|x| x == self).unwrap_or(0);
+        return CURVE_PERIODS[(idx + 1) % CURVE_PERIODS.len()];
+    }
+}
+
+/// Seconds as `5s`, `20m` or `1h30m`
+pub fn duration_as_string(seconds: i64) -> String {
+    return match seconds {
+        value if value < 60 => format!("{}s", value),
+        value if value < 3600 && value % 60 == 0 => format!("{}m", value / 60),
+        value if value < 3600 => format!("{}m{}s", value / 60, value % 60),
+        value if value % 3600 == 0 => format!("{}h", value / 3600),
+        value => format!("{}h{}m", value / 3600, value % 3600 / 60)
+    };
+}
+
+/// Handles `curve [<period> | <date range>]`, all time when no range is given
+pub fn command(store: &dyn SessionStore, args: &[String]) -> Result<()> {
+    let athlete = Athlete::from_args(store, args)?;
+    let positional = positional_args(args);
+
+    let range = match positional.first() {
+        None => CurvePeriod::AllTime.range()?,
+        Some(value) => match value.parse::<CurvePeriod>() {
+            Ok(period) => period.range()?,
+            Err(_) => DateRange::from_args(&positional)?
+        }
+    };
+
+    for point in store.get_power_curve(athlete.id, &range)?.points {
+        println!("{}: {:.0}W ({}, session {})"
+            , duration_as_string(point.seconds)
+            , point.power
+            , date_as_string(point.achieved_at)
+            , point.session_id);
+    }
+
+    return Ok(());
+}
@@ -0,0 +1,883 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet};
+use anyhow::{Result, bail};
+use itertools::Itertools;
+use uuid::Uuid;
+
+use crate::athlete::Athlete;
+use crate::critical_power::{self, CriticalPowerFit};
+use crate::db::SourceFile;
+use crate::gear::{Bike, Component};
+use crate::import::ImportLogEntry;
+use crate::parser::Session;
+use crate::personal_record::{self, PersonalRecord, PersonalRecordBoard, SessionBests};
+use crate::power_curve::{self, PowerCurve, PowerCurvePoint};
+use crate::summary::{Summary, DateRange, day_start};
+use crate::threshold::{Threshold, ThresholdHistory};
+
+/// Where sessions are kept, the dashboard and the importer only talk to the storage through this
+pub trait SessionStore {
+    /// Stores a new session and returns its id
+    fn insert_session(&mut self, session: Session, source_file: &SourceFile) -> Result<i64>;
+    /// Replaces the data of an existing session with a new import of it, keeping its id and uuid
+    fn replace_session(&mut self, existing: &Session, session: Session, source_file: &SourceFile) -> Result<i64>;
+    /// Saves the fields which can be edited by the user, the rest come from the FIT file
+    fn update_session(&mut self, session: &Session) -> Result<()>;
+    fn delete_session(&mut self, session_id: i64) -> Result<()>;
+    /// Gets session headers of the athlete only, newest first
+    fn get_all_sessions(&self, athlete_id: i64) -> Result<Vec<Session>>;
+    /// Gets a session header by id
+    fn get_session(&self, session_id: i64) -> Result<Session>;
+    /// Loads laps and records of a session which are left out of the headers
+    fn get_session_details(&self, session: &Session) -> Result<Session>;
+    /// Gets the headers of sessions whose title, notes, tags or sub sport contain every word of the query
+    fn search_sessions(&self, athlete_id: i64, query: &str) -> Result<Vec<Session>>;
+    /// Finds a session of the same athlete imported from the same file, or recorded by the same device at an overlapping time
+    fn find_duplicate_session(&self, session: &Session) -> Result<Option<Session>>;
+    fn get_overall_summary(&self, athlete_id: i64, range: &DateRange) -> Result<Summary>;
+    /// Gets summary for each sub sport separately
+    fn get_detailed_summary(&self, athlete_id: i64, range: &DateRange) -> Result<Vec<Summary>>;
+    /// Gets a summary for every day with sessions, keyed by the start of the day
+    fn get_daily_summaries(&self, athlete_id: i64, range: &DateRange) -> Result<Vec<(i64, Summary)>>;
+    fn get_bikes(&self) -> Result<Vec<Bike>>;
+    /// Adds a bike, new sessions matching its rules are assigned to it
+    fn insert_bike(&mut self, name: &str, rule_sub_sport: Option<&String>, rule_serial_number: Option<i64>) -> Result<i64>;
+    /// Deletes a bike with its components, its sessions are left without a bike
+    fn delete_bike(&mut self, bike_id: i64) -> Result<()>;
+    /// Runs the bike rules over every session which has no bike yet, returns how many got one
+    fn apply_bike_rules(&mut self) -> Result<i64>;
+    fn insert_component(&mut self, bike_id: i64, name: &str, installed_at: i64, service_distance: Option<f64>, service_time: Option<f64>) -> Result<i64>;
+    /// Restarts the service interval of a component from the given time
+    fn service_component(&mut self, component_id: i64, serviced_at: i64) -> Result<()>;
+    fn delete_component(&mut self, component_id: i64) -> Result<()>;
+    /// Gets all athletes, the first one is the default profile
+    fn get_athletes(&self) -> Result<Vec<Athlete>>;
+    fn insert_athlete(&mut self, name: &str) -> Result<i64>;
+    /// Deletes an athlete with their thresholds, only athletes without sessions can be deleted
+    fn delete_athlete(&mut self, athlete_id: i64) -> Result<()>;
+    /// Gets the dated thresholds of the athlete
+    fn get_thresholds(&self, athlete_id: i64) -> Result<ThresholdHistory>;
+    /// Adds a threshold entry and recalculates the training load of the sessions it applies to
+    fn insert_threshold(&mut self, threshold: &Threshold) -> Result<i64>;
+    /// Deletes a threshold entry and recalculates the training load of the athlete's sessions
+    fn delete_threshold(&mut self, threshold_id: i64) -> Result<()>;
+    /// Gets the current and beaten personal records of the athlete
+    fn get_personal_records(&self, athlete_id: i64) -> Result<PersonalRecordBoard>;
+    /// Gets the best mean-maximal power of every duration over the sessions in the range
+    fn get_power_curve(&self, athlete_id: i64, range: &DateRange) -> Result<PowerCurve>;
+    /// Gets the critical power fits of every day with sessions, oldest first
+    fn get_critical_power_history(&self, athlete_id: i64) -> Result<Vec<CriticalPowerFit>>;
+    /// Gets the ids of sessions which have their original FIT file archived
+    fn get_archived_session_ids(&self) -> Result<Vec<i64>>;
+    fn get_source_file(&self, session_id: i64) -> Result<SourceFile>;
+    /// Updates the fields read from the FIT file after it has been parsed again, the user edited fields are kept
+    fn reprocess_session(&mut self, session_id: i64, session: Session) -> Result<()>;
+    /// Personal record boards which have to be built again as a whole are left until `end_import`,
+    /// so an import or reprocessing run builds each of them once
+    fn begin_import(&mut self);
+    /// Builds every board the writes since `begin_import` left behind once
+    fn end_import(&mut self) -> Result<()>;
+    /// Remembers what happened to a file during an import
+    fn log_import(&mut self, entry: &ImportLogEntry) -> Result<i64>;
+    /// Gets the latest import log entries, newest first
+    fn get_import_log(&self, limit: i64) -> Result<Vec<ImportLogEntry>>;
+}
+
+/// Keeps sessions in memory only, nothing is written to disk. Everything the database derives from
+/// the sessions is derived the same way here
+#[derive(Default)]
+pub struct MemoryStore {
+    athletes: Vec<Athlete>,
+    /// Bikes whose rules are applied to new sessions
+    bikes: Vec<Bike>,
+    sessions: Vec<Session>,
+    source_files: BTreeMap<i64, SourceFile>,
+    /// Mean-maximal power of every session, keyed by session id
+    power_curves: BTreeMap<i64, PowerCurve>,
+    personal_records: Vec<PersonalRecord>,
+    thresholds: Vec<Threshold>,
+    critical_power: Vec<CriticalPowerFit>,
+    import_log: Vec<ImportLogEntry>,
+    /// Athletes whose boards are built again when the running import ends, none outside of imports
+    stale_boards: Option<BTreeSet<i64>>,
+    next_id: i64
+}
+
+/// How a session was written, it decides what is derived from it again
+#[derive(Clone, Copy, PartialEq)]
+enum SessionWrite {
+    Insert,
+    Replace,
+    Reprocess
+}
+
+impl MemoryStore {
+    pub fn new(athletes: Vec<Athlete>, bikes: Vec<Bike>) -> Self {
+        return Self { athletes, bikes, ..MemoryStore::default() };
+    }
+
+    fn default_athlete_id(&self) -> Option<i64> {
+        return self.athletes.first().map(|x| x.id);
+    }
+
+    fn headers(&self, filter: impl Fn(&Session) -> bool) -> Vec<Session> {
+        let mut sessions: Vec<Session> = self.sessions.iter()
+            .filter(|x| filter(x))
+            .map(|x| Session { laps: Vec::new(), records: Vec::new(), ..x.clone() })
+            .collect();
+        sessions.sort_by_key(|x| std::cmp::Reverse(x.start_time));
+
+        return sessions;
+    }
+
+    fn component_mut(&mut self, component_id: i64) -> Result<&mut Component> {
+        return match self.bikes.iter_mut().flat_map(|x| x.components.iter_mut()).find(|x| x.id == component_id) {
+            Some(component) => Ok(component),
+            None => bail!("Component {} does not exist", component_id)
+        };
+    }
+
+    fn position(&self, session_id: i64) -> Result<usize> {
+        return match self.sessions.iter().position(|x| x.id == Some(session_id)) {
+            Some(idx) => Ok(idx),
+            None => bail!("Session {} does not exist", session_id)
+        };
+    }
+
+    /// Same as the database, the records of a new session are added to the board unless it is older than one of them
+    fn record_personal_records(&mut self, idx: usize) {
+        let session = &self.sessions[idx];
+        let athlete_id = session.athlete_id.unwrap_or(0);
+        let bests = SessionBests { session_id: session.id.unwrap_or(0), start_time: session.start_time, bests: personal_record::session_bests(session) };
+
+        match self.get_personal_records(athlete_id).ok().and_then(|x| x.appended(athlete_id, &bests)) {
+            Some(records) => self.personal_records.extend(records),
+            None => self.rebuild_session_board(athlete_id)
+        }
+    }
+
+    /// Builds the board again, during an import the athlete is kept for `end_import` instead
+    fn rebuild_session_board(&mut self, athlete_id: i64) {
+        match &mut self.stale_boards {
+            Some(athletes) => {
+                athletes.insert(athlete_id);
+            },
+            None => self.rebuild_personal_records(athlete_id)
+        }
+    }
+
+    /// Same as the database, the board is built again from every session of the athlete
+    fn rebuild_personal_records(&mut self, athlete_id: i64) {
+        let sessions = self.sessions.iter()
+            .filter(|x| x.athlete_id == Some(athlete_id))
+            .map(|x| SessionBests { session_id: x.id.unwrap_or(0), start_time: x.start_time, bests: personal_record::session_bests(x) })
+            .collect();
+
+        self.personal_records.retain(|x| x.athlete_id != athlete_id);
+        self.personal_records.extend(PersonalRecordBoard::from_sessions(athlete_id, sessions).records);
+    }
+
+    /// Same as the database, a bike matching both sub sport and device wins over one matching only either of them
+    fn bike_by_rules(&self, session: &Session) -> Option<i64> {
+        return self.bikes.iter()
+            .filter(|x| x.has_rules()
+                && x.rule_sub_sport.as_ref().is_none_or(|value| *value == session.sub_sport)
+                && x.rule_serial_number.is_none_or(|value| value == session.serial_num))
+            .max_by_key(|x| (x.rule_sub_sport.is_some() as i64 + x.rule_serial_number.is_some() as i64, Reverse(x.id)))
+            .map(|x| x.id);
+    }
+
+    /// Adds the thresholds the device recorded with the session when they changed, the entry updates the later sessions
+    fn record_device_thresholds(&mut self, idx: usize) -> Result<bool> {
+        let session = &self.sessions[idx];
+        let athlete_id = session.athlete_id.unwrap_or(0);
+
+        return match self.get_thresholds(athlete_id)?.device_entry(athlete_id, session.id.unwrap_or(0), session) {
+            Some(threshold) => {
+                self.insert_threshold(&threshold)?;
+                Ok(true)
+            },
+            None => Ok(false)
+        };
+    }
+
+    /// Applies the threshold history to the intensity factor and TSS of the athlete's sessions from the given time
+    fn update_training_loads(&mut self, athlete_id: i64, since: i64) -> Result<()> {
+        let history = self.get_thresholds(athlete_id)?;
+        for session in self.sessions.iter_mut().filter(|x| x.athlete_id == Some(athlete_id) && x.start_time >= since) {
+            session.training_load = session.training_load.with_threshold(history.ftp_for(session), session.total_moving_time);
+        }
+
+        return Ok(());
+    }
+
+    fn write_power_curve(&mut self, idx: usize) {
+        let session = &self.sessions[idx];
+        let points = power_curve::session_curve(&session.records).into_iter()
+            .map(|(seconds, power)| PowerCurvePoint {
+                seconds,
+                power,
+                session_id: session.id.unwrap_or(0),
+                achieved_at: session.start_time
+            })
+            .collect();
+
+        self.power_curves.insert(session.id.unwrap_or(0), PowerCurve { points });
+    }
+
+    /// Same as the database, the models are fitted again for every day with sessions in the range
+    fn update_critical_power(&mut self, athlete_id: i64, days: &DateRange) -> Result<()> {
+        self.critical_power.retain(|x| x.athlete_id != athlete_id || x.fitted_at <= days.start || x.fitted_at > days.end);
+
+        let sessions = self.headers(|x| x.athlete_id == Some(athlete_id));
+        let session_days: Vec<i64> = sessions.iter()
+            .filter(|x| x.start_time >= days.start && x.start_time < days.end)
+            .map(|x| day_start(x.start_time))
+            .sorted()
+            .dedup()
+            .collect();
+
+        for day in session_days {
+            let window = critical_power::fit_window(day);
+            let curve = self.get_power_curve(athlete_id, &window)?;
+            self.critical_power.extend(critical_power::fit_models(athlete_id, window.end, &curve, critical_power::device_threshold_power(&sessions, &window)));
+        }
+
+        return Ok(());
+    }
+
+    /// Everything derived from a session which was added or changed, in the order of the database
+    fn record_session(&mut self, idx: usize, write: SessionWrite) -> Result<()> {
+        let athlete_id = self.sessions[idx].athlete_id.unwrap_or(0);
+        let start_time = self.sessions[idx].start_time;
+
+        self.write_power_curve(idx);
+        let threshold_added = write != SessionWrite::Replace && self.record_device_thresholds(idx)?;
+        if !threshold_added {
+            let ftp = self.get_thresholds(athlete_id)?.ftp_for(&self.sessions[idx]);
+            let session = &mut self.sessions[idx];
+            session.training_load = session.training_load.with_threshold(ftp, session.total_moving_time);
+        }
+
+        match write {
+            SessionWrite::Insert => self.record_personal_records(idx),
+            SessionWrite::Replace | SessionWrite::Reprocess => self.rebuild_session_board(athlete_id)
+        }
+        self.update_critical_power(athlete_id, &critical_power::fit_days(start_time))?;
+
+        return Ok(());
+    }
+
+    /// Same as the database, the user edited fields are kept
+    fn update_session_data(&mut self, session_id: i64, session: Session) -> Result<usize> {
+        let idx = self.position(session_id)?;
+        let stored = &self.sessions[idx];
+
+        self.sessions[idx] = Session {
+            id: stored.id,
+            uuid: stored.uuid.clone(),
+            sub_sport: stored.sub_sport.clone(),
+            title: stored.title.clone(),
+            notes: stored.notes.clone(),
+            tags: stored.tags.clone(),
+            bike_id: stored.bike_id,
+            athlete_id: stored.athlete_id,
+            ..session
+        };
+
+        return Ok(idx);
+    }
+
+    fn in_range(session: &Session, athlete_id: i64, range: &DateRange) -> bool {
+        return session.athlete_id == Some(athlete_id)
+            && session.start_time >= range.start
+            && session.start_time < range.end;
+    }
+}
+
+impl SessionStore for MemoryStore {
+    fn insert_session(&mut self, mut session: Session, source_file: &SourceFile) -> Result<i64> {
+        self.next_id += 1;
+        session.id = Some(self.next_id);
+        session.uuid = Some(Uuid::new_v4().to_string());
+        session.athlete_id = session.athlete_id.or(self.default_athlete_id());
+        session.bike_id = session.bike_id.or(self.bike_by_rules(&session));
+        self.sessions.push(session);
+        self.source_files.insert(self.next_id, source_file.clone());
+        self.record_session(self.sessions.len() - 1, SessionWrite::Insert)?;
+
+        return Ok(self.next_id);
+    }
+
+    fn replace_session(&mut self, existing: &Session, session: Session, source_file: &SourceFile) -> Result<i64> {
+        let session_id = existing.id.unwrap_or(0);
+        let idx = self.update_session_data(session_id, session)?;
+        self.source_files.insert(session_id, source_file.clone());
+        self.record_session(idx, SessionWrite::Replace)?;
+
+        return Ok(session_id);
+    }
+
+    fn update_session(&mut self, session: &Session) -> Result<()> {
+        let idx = self.position(session.id.unwrap_or(0))?;
+        let stored = &mut self.sessions[idx];
+
+        stored.sub_sport = session.sub_sport.clone();
+        stored.title = session.title.clone().filter(|x| !x.trim().is_empty());
+        stored.notes = session.notes.clone().filter(|x| !x.trim().is_empty());
+        stored.tags = session.tags.clone();
+        stored.bike_id = session.bike_id;
+
+        return Ok(());
+    }
+
+    fn delete_session(&mut self, session_id: i64) -> Result<()> {
+        let idx = self.position(session_id)?;
+        let removed = self.sessions.remove(idx);
+        self.source_files.remove(&session_id);
+        self.power_curves.remove(&session_id);
+
+        if let Some(athlete_id) = removed.athlete_id {
+            self.rebuild_personal_records(athlete_id);
+            self.update_critical_power(athlete_id, &critical_power::fit_days(removed.start_time))?;
+        }
+
+        return Ok(());
+    }
+
+    fn get_all_sessions(&self, athlete_id: i64) -> Result<Vec<Session>> {
+        return Ok(self.headers(|x| x.athlete_id == Some(athlete_id)));
+    }
+
+    fn get_session(&self, session_id: i64) -> Result<Session> {
+        let idx = self.position(session_id)?;
+        let session = &self.sessions[idx];
+
+        return Ok(Session { laps: Vec::new(), records: Vec::new(), ..session.clone() });
+    }
+
+    fn get_session_details(&self, session: &Session) -> Result<Session> {
+        let idx = self.position(session.id.unwrap_or(0))?;
+
+        return Ok(self.sessions[idx].clone());
+    }
+
+    fn search_sessions(&self, athlete_id: i64, query: &str) -> Result<Vec<Session>> {
+        let query_words: Vec<String> = query.split_whitespace()
+            .map(|x| x.to_lowercase())
+            .collect();
+
+        // Split into words the way the full text index does, every query word has to prefix one of them
+        return Ok(self.headers(|session| {
+            if session.athlete_id != Some(athlete_id) {
+                return false;
+            }
+
+            let text = format!("{} {} {} {}"
+                , session.title.clone().unwrap_or_default()
+                , session.notes.clone().unwrap_or_default()
+                , session.tags.join(" ")
+                , session.sub_sport)
+                .to_lowercase();
+
+            let words: Vec<&str> = text
+                .split(|x: char| !x.is_alphanumeric())
+                .filter(|x| !x.is_empty())
+                .collect();
+
+            return query_words.iter().all(|query_word| {
+                let query_word: String = query_word.chars().filter(|x| x.is_alphanumeric()).collect();
+                return words.iter().any(|x| x.starts_with(&query_word));
+            });
+        }));
+    }
+
+    fn find_duplicate_session(&self, session: &Session) -> Result<Option<Session>> {
+        let start_time = session.start_time;
+        let end_time = session.total_elapsed_time as i64 + start_time;
+        let athlete_id = session.athlete_id.or(self.default_athlete_id());
+        let athlete_sessions: Vec<&Session> = self.sessions.iter()
+            .filter(|x| x.athlete_id == athlete_id)
+            .collect();
+
+        let same_file = athlete_sessions.iter()
+            .find(|x| session.file_hash.is_some() && x.file_hash == session.file_hash);
+
+        let overlapping = athlete_sessions.iter()
+            .find(|x| x.serial_num == session.serial_num
+                && x.start_time < end_time
+                && (start_time as f64) < x.start_time as f64 + x.total_elapsed_time);
+
+        return Ok(same_file.or(overlapping)
+            .map(|&x| Session { laps: Vec::new(), records: Vec::new(), ..x.clone() }));
+    }
+
+    fn get_overall_summary(&self, athlete_id: i64, range: &DateRange) -> Result<Summary> {
+        let sessions: Vec<&Session> = self.sessions.iter()
+            .filter(|x| MemoryStore::in_range(x, athlete_id, range))
+            .collect();
+
+        return Ok(Summary {
+            sub_sport: None,
+            total_distance: sessions.iter().map(|x| x.total_distance).sum(),
+            total_time: sessions.iter().map(|x| x.total_moving_time).sum(),
+            rides_count: sessions.len() as i64,
+            total_ascent: sessions.iter().map(|x| x.total_ascent).sum(),
+            total_work: sessions.iter().map(|x| x.total_work).sum(),
+            total_training_stress: sessions.iter().map(|x| x.training_load.training_stress_score).sum()
+        });
+    }
+
+    fn get_detailed_summary(&self, athlete_id: i64, range: &DateRange) -> Result<Vec<Summary>> {
+        let mut by_sub_sport: BTreeMap<String, Summary> = BTreeMap::new();
+
+        for session in self.sessions.iter().filter(|x| MemoryStore::in_range(x, athlete_id, range)) {
+            let summary = by_sub_sport.entry(session.sub_sport.clone())
+                .or_insert_with(|| Summary {
+                    sub_sport: Some(session.sub_sport.clone()),
+                    ..Summary::default()
+                });
+
+            summary.total_distance += session.total_distance;
+            summary.total_time += session.total_moving_time;
+            summary.rides_count += 1;
+            summary.total_ascent += session.total_ascent;
+            summary.total_work += session.total_work;
+            summary.total_training_stress += session.training_load.training_stress_score;
+        }
+
+        return Ok(by_sub_sport.into_values().collect());
+    }
+
+    fn get_daily_summaries(&self, athlete_id: i64, range: &DateRange) -> Result<Vec<(i64, Summary)>> {
+        let mut by_day: BTreeMap<i64, Summary> = BTreeMap::new();
+
+        for session in self.sessions.iter().filter(|x| MemoryStore::in_range(x, athlete_id, range)) {
+            by_day.entry(day_start(session.start_time))
+                .or_default()
+                .accumulate(&Summary {
+                    sub_sport: None,
+                    total_distance: session.total_distance,
+                    total_time: session.total_moving_time,
+                    rides_count: 1,
+                    total_ascent: session.total_ascent,
+                    total_work: session.total_work,
+                    total_training_stress: session.training_load.training_stress_score
+                });
+        }
+
+        return Ok(by_day.into_iter().collect());
+    }
+
+    /// Same as the database, distance and time are summed over the sessions of the bike, a component's from its install
+    fn get_bikes(&self) -> Result<Vec<Bike>> {
+        let bikes = self.bikes.iter()
+            .map(|bike| {
+                let sessions: Vec<&Session> = self.sessions.iter().filter(|x| x.bike_id == Some(bike.id)).collect();
+                let since = |time: i64| -> (f64, f64) {
+                    let after: Vec<&&Session> = sessions.iter().filter(|x| x.start_time >= time).collect();
+                    return (after.iter().map(|x| x.total_distance).sum(), after.iter().map(|x| x.total_moving_time).sum());
+                };
+                let (total_distance, total_time) = since(i64::MIN);
+
+                return Bike {
+                    total_distance,
+                    total_time,
+                    components: bike.components.iter()
+                        .map(|component| {
+                            let (total_distance, total_time) = since(component.installed_at);
+                            let (distance_since_service, time_since_service) = since(component.serviced_at.max(component.installed_at));
+
+                            return Component { total_distance, total_time, distance_since_service, time_since_service, ..component.clone() };
+                        })
+                        .collect(),
+                    ..bike.clone()
+                };
+            })
+            .collect();
+
+        return Ok(bikes);
+    }
+
+    fn insert_bike(&mut self, name: &str, rule_sub_sport: Option<&String>, rule_serial_number: Option<i64>) -> Result<i64> {
+        let bike_id = self.bikes.iter().map(|x| x.id).max().unwrap_or(0) + 1;
+        self.bikes.push(Bike {
+            id: bike_id,
+            name: name.to_string(),
+            rule_sub_sport: rule_sub_sport.cloned(),
+            rule_serial_number,
+            total_distance: 0.0,
+            total_time: 0.0,
+            components: Vec::new()
+        });
+
+        return Ok(bike_id);
+    }
+
+    fn delete_bike(&mut self, bike_id: i64) -> Result<()> {
+        let idx = match self.bikes.iter().position(|x| x.id == bike_id) {
+            Some(idx) => idx,
+            None => bail!("Bike {} does not exist", bike_id)
+        };
+
+        self.bikes.remove(idx);
+        for session in self.sessions.iter_mut().filter(|x| x.bike_id == Some(bike_id)) {
+            session.bike_id = None;
+        }
+
+        return Ok(());
+    }
+
+    fn apply_bike_rules(&mut self) -> Result<i64> {
+        let mut assigned = 0;
+
+        for idx in 0..self.sessions.len() {
+            if self.sessions[idx].bike_id.is_some() {
+                continue;
+            }
+
+            self.sessions[idx].bike_id = self.bike_by_rules(&self.sessions[idx]);
+            if self.sessions[idx].bike_id.is_some() {
+                assigned += 1;
+            }
+        }
+
+        return Ok(assigned);
+    }
+
+    fn insert_component(&mut self, bike_id: i64, name: &str, installed_at: i64, service_distance: Option<f64>, service_time: Option<f64>) -> Result<i64> {
+        let component_id = self.bikes.iter().flat_map(|x| x.components.iter()).map(|x| x.id).max().unwrap_or(0) + 1;
+        let bike = match self.bikes.iter_mut().find(|x| x.id == bike_id) {
+            Some(bike) => bike,
+            None => bail!("Bike {} does not exist", bike_id)
+        };
+
+        bike.components.push(Component {
+            id: component_id,
+            bike_id,
+            name: name.to_string(),
+            installed_at,
+            serviced_at: installed_at,
+            service_distance,
+            service_time,
+            total_distance: 0.0,
+            total_time: 0.0,
+            distance_since_service: 0.0,
+            time_since_service: 0.0
+        });
+
+        return Ok(component_id);
+    }
+
+    fn service_component(&mut self, component_id: i64, serviced_at: i64) -> Result<()> {
+        self.component_mut(component_id)?.serviced_at = serviced_at;
+
+        return Ok(());
+    }
+
+    fn delete_component(&mut self, component_id: i64) -> Result<()> {
+        let bike_id = self.component_mut(component_id)?.bike_id;
+
+        if let Some(bike) = self.bikes.iter_mut().find(|x| x.id == bike_id) {
+            bike.components.retain(|x| x.id != component_id);
+        }
+
+        return Ok(());
+    }
+
+    fn get_athletes(&self) -> Result<Vec<Athlete>> {
+        let athletes = self.athletes.iter()
+            .map(|athlete| Athlete {
+                sessions_count: self.sessions.iter().filter(|x| x.athlete_id == Some(athlete.id)).count() as i64,
+                ..athlete.clone()
+            })
+            .collect();
+
+        return Ok(athletes);
+    }
+
+    fn insert_athlete(&mut self, name: &str) -> Result<i64> {
+        let athlete_id = self.athletes.iter().map(|x| x.id).max().unwrap_or(0) + 1;
+        self.athletes.push(Athlete { id: athlete_id, name: name.to_string(), sessions_count: 0 });
+
+        return Ok(athlete_id);
+    }
+
+    fn delete_athlete(&mut self, athlete_id: i64) -> Result<()> {
+        let session_count = self.sessions.iter().filter(|x| x.athlete_id == Some(athlete_id)).count();
+        if session_count > 0 {
+            bail!("Athlete {} still has {} sessions", athlete_id, session_count);
+        }
+
+        let idx = match self.athletes.iter().position(|x| x.id == athlete_id) {
+            Some(idx) => idx,
+            None => bail!("Athlete {} does not exist", athlete_id)
+        };
+
+        self.athletes.remove(idx);
+        self.thresholds.retain(|x| x.athlete_id != athlete_id);
+
+        return Ok(());
+    }
+
+    fn get_thresholds(&self, athlete_id: i64) -> Result<ThresholdHistory> {
+        return Ok(ThresholdHistory::new(self.thresholds.iter().filter(|x| x.athlete_id == athlete_id).cloned().collect()));
+    }
+
+    fn insert_threshold(&mut self, threshold: &Threshold) -> Result<i64> {
+        let threshold_id = self.thresholds.iter().filter_map(|x| x.id).max().unwrap_or(0) + 1;
+        self.thresholds.push(Threshold { id: Some(threshold_id), ..threshold.clone() });
+        self.update_training_loads(threshold.athlete_id, threshold.effective_from)?;
+
+        return Ok(threshold_id);
+    }
+
+    fn delete_threshold(&mut self, threshold_id: i64) -> Result<()> {
+        let idx = match self.thresholds.iter().position(|x| x.id == Some(threshold_id)) {
+            Some(idx) => idx,
+            None => bail!("Threshold {} does not exist", threshold_id)
+        };
+
+        let removed = self.thresholds.remove(idx);
+        self.update_training_loads(removed.athlete_id, removed.effective_from)?;
+
+        return Ok(());
+    }
+
+    fn get_personal_records(&self, athlete_id: i64) -> Result<PersonalRecordBoard> {
+        let records = self.personal_records.iter()
+            .filter(|x| x.athlete_id == athlete_id)
+            .cloned()
+            .collect();
+
+        return Ok(PersonalRecordBoard::new(records));
+    }
+
+    fn get_power_curve(&self, athlete_id: i64, range: &DateRange) -> Result<PowerCurve> {
+        let mut curve = PowerCurve::default();
+
+        for session in self.sessions.iter().filter(|x| MemoryStore::in_range(x, athlete_id, range)) {
+            if let Some(session_curve) = self.power_curves.get(&session.id.unwrap_or(0)) {
+                curve.merge(session_curve.clone());
+            }
+        }
+
+        return Ok(curve);
+    }
+
+    fn get_critical_power_history(&self, athlete_id: i64) -> Result<Vec<CriticalPowerFit>> {
+        let history = self.critical_power.iter()
+            .filter(|x| x.athlete_id == athlete_id)
+            .sorted_by_key(|x| (x.fitted_at, x.model.as_str().to_string()))
+            .cloned()
+            .collect();
+
+        return Ok(history);
+    }
+
+    fn get_archived_session_ids(&self) -> Result<Vec<i64>> {
+        return Ok(self.source_files.keys().copied().collect());
+    }
+
+    fn get_source_file(&self, session_id: i64) -> Result<SourceFile> {
+        return match self.source_files.get(&session_id) {
+            Some(source_file) => Ok(source_file.clone()),
+            None => bail!("Session {} has no archived FIT file", session_id)
+        };
+    }
+
+    fn reprocess_session(&mut self, session_id: i64, session: Session) -> Result<()> {
+        let idx = self.update_session_data(session_id, session)?;
+        self.record_session(idx, SessionWrite::Reprocess)?;
+
+        return Ok(());
+    }
+
+    fn begin_import(&mut self) {
+        self.stale_boards = Some(BTreeSet::new());
+    }
+
+    fn end_import(&mut self) -> Result<()> {
+        for athlete_id in self.stale_boards.take().unwrap_or_default() {
+            self.rebuild_personal_records(athlete_id);
+        }
+
+        return Ok(());
+    }
+
+    fn log_import(&mut self, entry: &ImportLogEntry) -> Result<i64> {
+        let entry_id = self.import_log.len() as i64 + 1;
+        self.import_log.push(ImportLogEntry { id: Some(entry_id), ..entry.clone() });
+
+        return Ok(entry_id);
+    }
+
+    fn get_import_log(&self, limit: i64) -> Result<Vec<ImportLogEntry>> {
+        return Ok(self.import_log.iter()
+            .rev()
+            .take(limit.max(0) as usize)
+            .cloned()
+            .collect());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::power::TrainingLoad;
+    use crate::threshold::ThresholdSource;
+
+    fn store() -> MemoryStore {
+        let athletes = vec![Athlete { id: 1, name: String::from("Default"), sessions_count: 0 }];
+        let bike = |id: i64, rule_sub_sport: Option<&str>, rule_serial_number: Option<i64>| Bike {
+            id,
+            name: format!("Bike {}", id),
+            rule_sub_sport: rule_sub_sport.map(String::from),
+            rule_serial_number,
+            total_distance: 0.0,
+            total_time: 0.0,
+            components: Vec::new()
+        };
+
+        return MemoryStore::new(athletes, vec![bike(1, Some("road"), None), bike(2, Some("road"), Some(7)), bike(3, None, Some(8))]);
+    }
+
+    fn session(start_time: i64, serial_num: i64, threshold_power: i64) -> Session {
+        return Session {
+            start_time,
+            sub_sport: String::from("road"),
+            serial_num,
+            threshold_power,
+            total_moving_time: 3600.0,
+            total_distance: 30000.0,
+            training_load: TrainingLoad { normalized_power: 200.0, ..TrainingLoad::default() },
+            ..Session::default()
+        };
+    }
+
+    fn source_file() -> SourceFile {
+        return SourceFile { file_name: String::from("ride.fit"), bytes: vec![1, 2, 3] };
+    }
+
+    #[test]
+    fn insert_applies_the_most_specific_bike_rule() {
+        let mut store = store();
+        let matching_both = store.insert_session(session(1000, 7, 0), &source_file()).unwrap();
+        let matching_sub_sport = store.insert_session(session(2000, 9, 0), &source_file()).unwrap();
+
+        assert_eq!(store.get_session(matching_both).unwrap().bike_id, Some(2));
+        assert_eq!(store.get_session(matching_sub_sport).unwrap().bike_id, Some(1));
+        assert_eq!(store.get_bikes().unwrap()[1].total_distance, 30000.0);
+    }
+
+    #[test]
+    fn bike_rules_and_component_service() {
+        let mut store = MemoryStore::new(store().athletes, Vec::new());
+        let session_id = store.insert_session(session(1000, 7, 0), &source_file()).unwrap();
+        store.insert_session(session(3000, 7, 0), &source_file()).unwrap();
+        assert_eq!(store.get_session(session_id).unwrap().bike_id, None);
+
+        let bike_id = store.insert_bike("Road bike", Some(&String::from("road")), None).unwrap();
+        let component_id = store.insert_component(bike_id, "Chain", 0, Some(50000.0), None).unwrap();
+        assert_eq!(store.apply_bike_rules().unwrap(), 2);
+        assert_eq!(store.apply_bike_rules().unwrap(), 0);
+        assert!(store.get_bikes().unwrap()[0].components[0].service_due());
+
+        store.service_component(component_id, 2000).unwrap();
+        let component = store.get_bikes().unwrap()[0].components[0].clone();
+        assert_eq!((component.total_distance, component.distance_since_service), (60000.0, 30000.0));
+        assert!(!component.service_due());
+
+        store.delete_component(component_id).unwrap();
+        assert!(store.service_component(component_id, 3000).is_err());
+        store.delete_bike(bike_id).unwrap();
+        assert_eq!(store.get_session(session_id).unwrap().bike_id, None);
+        assert!(store.insert_component(bike_id, "Chain", 0, None, None).is_err());
+    }
+
+    #[test]
+    fn only_athletes_without_sessions_are_deleted() {
+        let mut store = store();
+        store.insert_session(session(1000, 7, 250), &source_file()).unwrap();
+        let athlete_id = store.insert_athlete("Alice").unwrap();
+
+        assert_eq!(athlete_id, 2);
+        assert!(store.delete_athlete(1).is_err());
+        store.delete_athlete(athlete_id).unwrap();
+        assert!(store.delete_athlete(athlete_id).is_err());
+        assert_eq!(store.get_athletes().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn insert_records_device_thresholds_and_training_load() {
+        let mut store = store();
+        let session_id = store.insert_session(session(1000, 7, 250), &source_file()).unwrap();
+
+        let history = store.get_thresholds(1).unwrap();
+        assert_eq!(history.entries.len(), 1);
+        assert_eq!(history.entries[0].ftp, Some(250));
+        assert_eq!(history.entries[0].source, ThresholdSource::Device);
+
+        let stored = store.get_session(session_id).unwrap();
+        assert!((stored.training_load.intensity_factor - 0.8).abs() < 1e-9);
+    }
+
+    #[test]
+    fn delete_threshold_recalculates_training_load() {
+        let mut store = store();
+        let session_id = store.insert_session(session(1000, 7, 0), &source_file()).unwrap();
+        let threshold_id = store.insert_threshold(&Threshold {
+            id: None,
+            athlete_id: 1,
+            effective_from: 0,
+            ftp: Some(200),
+            lthr: None,
+            max_heart_rate: None,
+            weight: None,
+            source: ThresholdSource::Manual,
+            session_id: None
+        }).unwrap();
+        assert!((store.get_session(session_id).unwrap().training_load.training_stress_score - 100.0).abs() < 1e-9);
+
+        store.delete_threshold(threshold_id).unwrap();
+        assert_eq!(store.get_session(session_id).unwrap().training_load.training_stress_score, 0.0);
+        assert!(store.delete_threshold(threshold_id).is_err());
+    }
+
+    #[test]
+    fn boards_left_during_an_import_are_built_once_at_its_end() {
+        let mut store = store();
+        store.begin_import();
+        let newer = store.insert_session(session(2000, 7, 0), &source_file()).unwrap();
+        let older = store.insert_session(Session { total_distance: 40000.0, ..session(1000, 7, 0) }, &source_file()).unwrap();
+        store.end_import().unwrap();
+
+        let board = store.get_personal_records(1).unwrap();
+        let longest: Vec<(i64, f64)> = board.records.iter()
+            .filter(|x| x.kind == personal_record::RecordKind::LongestRide)
+            .map(|x| (x.session_id, x.value))
+            .collect();
+        assert_eq!(longest, vec![(older, 40000.0)]);
+        assert!(board.for_session(newer).is_empty());
+
+        let newest = store.insert_session(Session { total_distance: 50000.0, ..session(3000, 7, 0) }, &source_file()).unwrap();
+        assert_eq!(store.get_personal_records(1).unwrap().current()[0].session_id, newest);
+    }
+
+    #[test]
+    fn reprocess_keeps_the_edited_fields() {
+        let mut store = store();
+        let session_id = store.insert_session(session(1000, 7, 0), &source_file()).unwrap();
+        let mut edited = store.get_session(session_id).unwrap();
+        edited.title = Some(String::from("Hill repeats"));
+        store.update_session(&edited).unwrap();
+
+        assert_eq!(store.get_archived_session_ids().unwrap(), vec![session_id]);
+        store.reprocess_session(session_id, Session { total_distance: 31000.0, ..session(1000, 7, 0) }).unwrap();
+
+        let stored = store.get_session(session_id).unwrap();
+        assert_eq!(stored.title, Some(String::from("Hill repeats")));
+        assert_eq!(stored.total_distance, 31000.0);
+        assert_eq!(store.get_source_file(session_id).unwrap().bytes, vec![1, 2, 3]);
+    }
+}
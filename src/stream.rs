@@ -0,0 +1,186 @@
+use std::io::{Read, Write};
+use anyhow::{Result, bail};
+use flate2::Compression;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+
+use crate::parser::Record;
+
+/// Decimal streams are stored as integers in these units
+const DISTANCE_SCALE: f64 = 100.0; // cm
+const SPEED_SCALE: f64 = 1000.0; // mm/s
+const ALTITUDE_SCALE: f64 = 100.0; // cm
+
+/// Records of one session stored column by column, every column is delta encoded and compressed
+pub struct RecordColumns {
+    pub count: i64,
+    pub timestamp: Vec<u8>,
+    pub heart_rate: Vec<u8>,
+    pub power: Vec<u8>,
+    pub cadence: Vec<u8>,
+    pub distance: Vec<u8>,
+    pub speed: Vec<u8>,
+    pub altitude: Vec<u8>,
+    pub position_lat: Vec<u8>,
+    pub position_long: Vec<u8>
+}
+
+impl RecordColumns {
+    pub fn from_records(records: &[Record]) -> Result<Self> {
+        return Ok(Self {
+            count: records.len() as i64,
+            timestamp: encode_column(records.iter().map(|x| x.timestamp))?,
+            heart_rate: encode_column(records.iter().map(|x| x.heart_rate))?,
+            power: encode_column(records.iter().map(|x| x.power))?,
+            cadence: encode_column(records.iter().map(|x| x.cadence))?,
+            distance: encode_column(records.iter().map(|x| (x.distance * DISTANCE_SCALE).round() as i64))?,
+            speed: encode_column(records.iter().map(|x| (x.speed * SPEED_SCALE).round() as i64))?,
+            altitude: encode_column(records.iter().map(|x| (x.altitude * ALTITUDE_SCALE).round() as i64))?,
+            position_lat: encode_column(records.iter().map(|x| x.position_lat))?,
+            position_long: encode_column(records.iter().map(|x| x.position_long))?
+        });
+    }
+
+    pub fn into_records(self) -> Result<Vec<Record>> {
+        let count = self.count as usize;
+        let timestamp = decode_column(&self.timestamp, count)?;
+        let heart_rate = decode_column(&self.heart_rate, count)?;
+        let power = decode_column(&self.power, count)?;
+        let cadence = decode_column(&self.cadence, count)?;
+        let distance = decode_column(&self.distance, count)?;
+        let speed = decode_column(&self.speed, count)?;
+        let altitude = decode_column(&self.altitude, count)?;
+        let position_lat = decode_column(&self.position_lat, count)?;
+        let position_long = decode_column(&self.position_long, count)?;
+
+        let records = (0..count)
+            .map(|idx| Record {
+                timestamp: timestamp[idx],
+                heart_rate: heart_rate[idx],
+                power: power[idx],
+                cadence: cadence[idx],
+                distance: distance[idx] as f64 / DISTANCE_SCALE,
+                speed: speed[idx] as f64 / SPEED_SCALE,
+                altitude: altitude[idx] as f64 / ALTITUDE_SCALE,
+                position_lat: position_lat[idx],
+                position_long: position_long[idx]
+            })
+            .collect();
+
+        return Ok(records);
+    }
+}
+
+/// Differences to the previous value as zigzag varints, so slowly changing streams take a byte per value before compression
+fn encode_column(values: impl Iterator<Item = i64>) -> Result<Vec<u8>> {
+    let mut bytes: Vec<u8> = Vec::new();
+    let mut previous: i64 = 0;
+
+    for value in values {
+        let delta = value.wrapping_sub(previous);
+        let mut zigzag = ((delta << 1) ^ (delta >> 63)) as u64;
+        previous = value;
+
+        while zigzag >= 0x80 {
+            bytes.push((zigzag as u8 & 0x7f) | 0x80);
+            zigzag >>= 7;
+        }
+        bytes.push(zigzag as u8);
+    }
+
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(&bytes)?;
+
+    return Ok(encoder.finish()?);
+}
+
+fn decode_column(compressed: &[u8], count: usize) -> Result<Vec<i64>> {
+    let mut bytes = Vec::new();
+    ZlibDecoder::new(compressed).read_to_end(&mut bytes)?;
+
+    // Every value takes at least a byte, a corrupted count can't reserve more than that
+    let mut values: Vec<i64> = Vec::with_capacity(count.min(bytes.len()));
+    let mut previous: i64 = 0;
+    let mut zigzag: u64 = 0;
+    let mut shift = 0;
+
+    for byte in bytes {
+        if shift > 63 {
+            bail!("Record stream is corrupted");
+        }
+
+        zigzag |= ((byte & 0x7f) as u64) << shift;
+        shift += 7;
+
+        if byte & 0x80 == 0 {
+            let delta = (zigzag >> 1) as i64 ^ -((zigzag & 1) as i64);
+            previous = previous.wrapping_add(delta);
+            values.push(previous);
+            zigzag = 0;
+            shift = 0;
+        }
+    }
+
+    if shift > 0 {
+        bail!("Record stream is corrupted");
+    }
+
+    if values.len() != count {
+        bail!("Record stream has {} values, expected {}", values.len(), count);
+    }
+
+    return Ok(values);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(timestamp: i64, altitude: f64, position_lat: i64) -> Record {
+        return Record { timestamp, power: 250, distance: 12.34, speed: 8.321, altitude, position_lat, position_long: -(position_lat / 2), ..Record::default() };
+    }
+
+    #[test]
+    fn records_round_trip_with_negative_deltas() {
+        let records = vec![record(1000, 120.5, 600000000), record(1001, 98.25, -600000000), record(999, -3.5, i64::MIN), record(1002, 0.0, i64::MAX)];
+        let decoded = RecordColumns::from_records(&records).unwrap().into_records().unwrap();
+
+        assert_eq!(decoded.len(), records.len());
+        for (decoded, record) in decoded.iter().zip(records.iter()) {
+            assert_eq!(decoded.timestamp, record.timestamp);
+            assert_eq!(decoded.power, record.power);
+            assert_eq!(decoded.distance, record.distance);
+            assert_eq!(decoded.speed, record.speed);
+            assert_eq!(decoded.altitude, record.altitude);
+            assert_eq!(decoded.position_lat, record.position_lat);
+            assert_eq!(decoded.position_long, record.position_long);
+        }
+    }
+
+    #[test]
+    fn empty_stream_round_trips() {
+        let columns = RecordColumns::from_records(&[]).unwrap();
+        assert_eq!(columns.count, 0);
+        assert!(columns.into_records().unwrap().is_empty());
+    }
+
+    #[test]
+    fn wrong_count_is_an_error() {
+        let mut columns = RecordColumns::from_records(&[record(1000, 10.0, 0), record(1001, 11.0, 0)]).unwrap();
+        columns.count = 3;
+        assert!(columns.into_records().is_err());
+
+        let mut columns = RecordColumns::from_records(&[record(1000, 10.0, 0)]).unwrap();
+        columns.count = i64::MAX;
+        assert!(columns.into_records().is_err());
+    }
+
+    #[test]
+    fn corrupted_column_is_an_error() {
+        assert!(decode_column(&[1, 2, 3], 1).is_err());
+
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&[0x80, 0x80]).unwrap();
+        assert!(decode_column(&encoder.finish().unwrap(), 0).is_err());
+    }
+}
@@ -1,28 +1,239 @@
-use anyhow::Result;
+use std::str::FromStr;
+use anyhow::{Result, bail};
+use chrono::{Datelike, Duration, Local, NaiveDate, TimeZone, Weekday};
 
-use crate::db;
+use crate::store::SessionStore;
 
-#[derive(Clone)]
+/// Days of the rolling windows on the trends view
+pub const ROLLING_WINDOWS: [i64; 3] = [7, 28, 365];
+
+pub const DAY_SECONDS: i64 = 24 * 60 * 60;
+
+#[derive(Clone, Default)]
 pub struct Summary {
     pub sub_sport: Option<String>,
     pub total_distance: f64,
     pub total_time: f64,
-    pub rides_count: i64
+    pub rides_count: i64,
+    /// Meters
+    pub total_ascent: f64,
+    /// Joules
+    pub total_work: f64,
+    pub total_training_stress: f64
 }
 
-impl Summary {
-    pub fn new(sub_sport: String) -> Self {
-        return Self {
-            sub_sport: Some(sub_sport),
-            total_distance: 0.0,
-            total_time: 0.0,
-            rides_count: 0
+/// Totals of one calendar period
+#[derive(Clone)]
+pub struct PeriodSummary {
+    pub label: String,
+    pub summary: Summary
+}
+
+/// Running totals of a year, the summary of every day includes the days before it
+#[derive(Clone)]
+pub struct CumulativeYear {
+    pub year: i32,
+    pub days: Vec<Summary>
+}
+
+impl CumulativeYear {
+    /// Totals from the start of the year to the end of the given day (1 is January 1st)
+    pub fn at(&self, day_of_year: usize) -> Summary {
+        let idx = day_of_year.clamp(1, self.days.len().max(1)) - 1;
+        return self.days.get(idx).cloned().unwrap_or_default();
+    }
+
+    /// Day the year is compared at, today for the current year and the last day for past years
+    pub fn comparison_day(&self) -> usize {
+        let today = Local::now().date_naive();
+
+        return match self.year.cmp(&today.year()) {
+            std::cmp::Ordering::Less => self.days.len(),
+            std::cmp::Ordering::Equal => today.ordinal() as usize,
+            std::cmp::Ordering::Greater => 0
+        };
+    }
+}
+
+/// Calendar periods summaries are grouped by
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Period {
+    #[default]
+    Week,
+    Month,
+    Year
+}
+
+impl FromStr for Period {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        return match value {
+            "week" => Ok(Period::Week),
+            "month" => Ok(Period::Month),
+            "year" => Ok(Period::Year),
+            _ => bail!("Unknown period '{}', expected week, month or year", value)
+        };
+    }
+}
+
+impl Period {
+    pub fn as_str(&self) -> &str {
+        return match self {
+            Period::Week => "week",
+            Period::Month => "month",
+            Period::Year => "year"
+        };
+    }
+
+    /// Period the date is in
+    pub fn containing(&self, date: NaiveDate) -> Result<DateRange> {
+        return match self {
+            Period::Week => DateRange::iso_week(date.iso_week().year(), date.iso_week().week()),
+            Period::Month => DateRange::month(date.year(), date.month()),
+            Period::Year => DateRange::year(date.year())
+        };
+    }
+
+    /// `2023-W12`, `2023-05` or `2023`, the same formats `DateRange::from_args` reads
+    pub fn label(&self, range: &DateRange) -> String {
+        let date = local_date(range.start);
+
+        return match self {
+            Period::Week => format!("{}-W{:02}", date.iso_week().year(), date.iso_week().week()),
+            Period::Month => date.format("%Y-%m").to_string(),
+            Period::Year => date.format("%Y").to_string()
+        };
+    }
+
+    /// Periods overlapping the range, oldest first
+    pub fn ranges(&self, range: &DateRange) -> Result<Vec<DateRange>> {
+        let mut ranges: Vec<DateRange> = Vec::new();
+        let mut current = self.containing(local_date(range.start))?;
+
+        while current.start < range.end {
+            ranges.push(current);
+            current = self.containing(local_date(current.end))?;
+        }
+
+        return Ok(ranges);
+    }
+
+    /// The given number of periods up to the current one, oldest first
+    pub fn latest(&self, count: usize) -> Result<Vec<DateRange>> {
+        let mut ranges: Vec<DateRange> = Vec::new();
+        let mut current = self.containing(Local::now().date_naive())?;
+
+        while ranges.len() < count {
+            ranges.insert(0, current);
+            current = self.containing(local_date(current.start - 1))?;
+        }
+
+        return Ok(ranges);
+    }
+}
+
+/// Local date of the timestamp, days and periods are all in local time like the dates on screen
+pub fn local_date(timestamp: i64) -> NaiveDate {
+    return Local.timestamp_opt(timestamp, 0).single().map(|x| x.date_naive()).unwrap_or_default();
+}
+
+/// Timestamp of the local midnight the date starts with, the first hour after it when a clock change skips midnight
+pub fn local_midnight(date: NaiveDate) -> i64 {
+    let midnight = date.and_hms_opt(0, 0, 0).unwrap_or_default();
+
+    return Local.from_local_datetime(&midnight).earliest()
+        .or_else(|| Local.from_local_datetime(&(midnight + Duration::hours(1))).earliest())
+        .map(|x| x.timestamp())
+        .unwrap_or_else(|| midnight.timestamp());
+}
+
+/// Start of the local day the timestamp is in
+pub fn day_start(timestamp: i64) -> i64 {
+    return local_midnight(local_date(timestamp));
+}
+
+/// Time span summaries are calculated over, start inclusive and end exclusive (unix timestamps)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DateRange {
+    pub start: i64,
+    pub end: i64
+}
+
+impl DateRange {
+    pub fn year(year: i32) -> Result<Self> {
+        return Self::between(NaiveDate::from_ymd_opt(year, 1, 1), NaiveDate::from_ymd_opt(year + 1, 1, 1));
+    }
+
+    pub fn month(year: i32, month: u32) -> Result<Self> {
+        let (next_year, next_month) = if month == 12 { (year + 1, 1) } else { (year, month + 1) };
+        return Self::between(NaiveDate::from_ymd_opt(year, month, 1), NaiveDate::from_ymd_opt(next_year, next_month, 1));
+    }
+
+    pub fn iso_week(year: i32, week: u32) -> Result<Self> {
+        let start = NaiveDate::from_isoywd_opt(year, week, Weekday::Mon);
+        let end = start.and_then(|x| x.checked_add_signed(Duration::weeks(1)));
+        return Self::between(start, end);
+    }
+
+    /// The given number of days up to now
+    pub fn rolling(days: i64) -> Self {
+        let end = Local::now().timestamp();
+        return Self { start: end - days * DAY_SECONDS, end };
+    }
+
+    pub fn custom(start: i64, end: i64) -> Result<Self> {
+        if start > end {
+            bail!("Date range start is after its end");
+        }
+        return Ok(Self { start, end });
+    }
+
+    /// Parses `2023`, `2023-05`, `2023-W12` or a `2023-01-01 2023-03-31` pair (end date inclusive)
+    pub fn from_args(args: &[String]) -> Result<Self> {
+        if args.len() > 2 {
+            bail!("Unexpected arguments '{}' after the date range", args[2..].join(" "));
+        }
+
+        if args.len() == 2 {
+            let start = NaiveDate::parse_from_str(&args[0], "%Y-%m-%d")?;
+            let end = NaiveDate::parse_from_str(&args[1], "%Y-%m-%d")?;
+            return Self::between(Some(start), end.succ_opt());
+        }
+
+        let value = match args.first() {
+            Some(value) => value,
+            None => bail!("Missing date range")
+        };
+
+        let parts: Vec<&str> = value.split('-').collect();
+        if parts.len() > 2 {
+            bail!("Invalid date range '{}', a single date needs an end date", value);
+        }
+
+        let year = parts[0].parse::<i32>()?;
+
+        return match parts.get(1) {
+            None => Self::year(year),
+            Some(part) if part.starts_with('W') => Self::iso_week(year, part[1..].parse::<u32>()?),
+            Some(part) => Self::month(year, part.parse::<u32>()?)
         };
     }
 
+    fn between(start: Option<NaiveDate>, end: Option<NaiveDate>) -> Result<Self> {
+        let (start_date, end_date) = match (start, end) {
+            (Some(start_date), Some(end_date)) => (start_date, end_date),
+            _ => bail!("Invalid date range")
+        };
+
+        return Self::custom(local_midnight(start_date), local_midnight(end_date));
+    }
+}
+
+impl Summary {
     /// Gets summary for each sub sport separately
-    pub fn detailed(year: i64) -> Result<Vec<Summary>> {
-        let summary_result = db::get_detailed_summary(year);
+    pub fn detailed(store: &dyn SessionStore, athlete_id: i64, range: &DateRange) -> Result<Vec<Summary>> {
+        let summary_result = store.get_detailed_summary(athlete_id, range);
 
         if summary_result.is_ok() {
             return summary_result;
@@ -30,18 +241,111 @@ impl Summary {
         return Ok(Vec::new());
     }
 
-    pub fn overall(year: i64) -> Result<Summary> {
-        let summary_result = db::get_overall_summary(year);
+    pub fn overall(store: &dyn SessionStore, athlete_id: i64, range: &DateRange) -> Result<Summary> {
+        let summary_result = store.get_overall_summary(athlete_id, range);
 
         if summary_result.is_ok() {
             return summary_result;
         }
 
-        return Ok(Summary {
-            sub_sport: None,
-            total_distance: 0.0,
-            total_time: 0.0,
-            rides_count: 0
-        });
+        return Ok(Summary::default());
+    }
+
+    /// Adds the totals of another summary to this one
+    pub fn accumulate(&mut self, other: &Summary) {
+        self.total_distance += other.total_distance;
+        self.total_time += other.total_time;
+        self.rides_count += other.rides_count;
+        self.total_ascent += other.total_ascent;
+        self.total_work += other.total_work;
+        self.total_training_stress += other.total_training_stress;
+    }
+
+    /// Gets the running totals of every day of the year
+    pub fn cumulative_year(store: &dyn SessionStore, athlete_id: i64, year: i32) -> Result<CumulativeYear> {
+        let range = DateRange::year(year)?;
+        let mut days = vec![Summary::default(); local_date(range.end - 1).ordinal() as usize];
+
+        for (day_start, summary) in store.get_daily_summaries(athlete_id, &range)? {
+            if let Some(day) = days.get_mut(local_date(day_start).ordinal0() as usize) {
+                *day = summary;
+            }
+        }
+
+        for idx in 1..days.len() {
+            let previous = days[idx - 1].clone();
+            days[idx].accumulate(&previous);
+        }
+
+        return Ok(CumulativeYear { year, days });
+    }
+
+    /// Running totals of the year and the years before it, newest first
+    pub fn compared_years(store: &dyn SessionStore, athlete_id: i64, year: i32, count: usize) -> Result<Vec<CumulativeYear>> {
+        return (0..count as i32)
+            .map(|idx| Summary::cumulative_year(store, athlete_id, year - idx))
+            .collect();
+    }
+
+    /// Gets a summary for every period overlapping the range
+    pub fn by_period(store: &dyn SessionStore, athlete_id: i64, period: Period, range: &DateRange) -> Result<Vec<PeriodSummary>> {
+        return Summary::of_periods(store, athlete_id, period, period.ranges(range)?);
+    }
+
+    /// Gets a summary for the given number of periods up to the current one
+    pub fn latest_periods(store: &dyn SessionStore, athlete_id: i64, period: Period, count: usize) -> Result<Vec<PeriodSummary>> {
+        return Summary::of_periods(store, athlete_id, period, period.latest(count)?);
+    }
+
+    /// Gets the summary of the given number of days up to now
+    pub fn rolling(store: &dyn SessionStore, athlete_id: i64, days: i64) -> Result<Summary> {
+        return store.get_overall_summary(athlete_id, &DateRange::rolling(days));
+    }
+
+    fn of_periods(store: &dyn SessionStore, athlete_id: i64, period: Period, ranges: Vec<DateRange>) -> Result<Vec<PeriodSummary>> {
+        let mut summaries: Vec<PeriodSummary> = Vec::new();
+
+        for range in ranges {
+            summaries.push(PeriodSummary {
+                label: period.label(&range),
+                summary: store.get_overall_summary(athlete_id, &range)?
+            });
+        }
+
+        return Ok(summaries);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(values: &[&str]) -> Vec<String> {
+        return values.iter().map(|x| x.to_string()).collect();
+    }
+
+    #[test]
+    fn ranges_start_and_end_at_local_midnight() {
+        let range = DateRange::from_args(&args(&["2023-03"])).unwrap();
+
+        assert_eq!(range.start, local_midnight(NaiveDate::from_ymd_opt(2023, 3, 1).unwrap()));
+        assert_eq!(local_date(range.start), NaiveDate::from_ymd_opt(2023, 3, 1).unwrap());
+        assert_eq!(local_date(range.end - 1), NaiveDate::from_ymd_opt(2023, 3, 31).unwrap());
+        assert_eq!(Period::Month.label(&range), "2023-03");
+    }
+
+    #[test]
+    fn end_date_of_a_pair_is_inclusive() {
+        let range = DateRange::from_args(&args(&["2023-01-01", "2023-01-07"])).unwrap();
+
+        assert_eq!(local_date(range.end), NaiveDate::from_ymd_opt(2023, 1, 8).unwrap());
+        assert_eq!(Period::Week.ranges(&range).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn extra_arguments_are_rejected() {
+        assert!(DateRange::from_args(&args(&["2023-01-01", "2023-01-07", "2023-02-01"])).is_err());
+        assert!(DateRange::from_args(&args(&["2023-01-01"])).is_err());
+        assert!(DateRange::from_args(&args(&[])).is_err());
     }
 }
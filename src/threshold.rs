@@ -0,0 +1,214 @@
+use std::str::FromStr;
+use anyhow::{Result, bail};
+
+use crate::athlete::Athlete;
+use crate::ftp_estimate;
+use crate::parser::Session;
+use crate::store::SessionStore;
+use crate::ui::util::date_as_string;
+use crate::util::{date_flag_or_now, flag_value};
+
+/// Where a threshold entry came from, device values never override manual ones
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ThresholdSource {
+    Manual,
+    Device
+}
+
+impl FromStr for ThresholdSource {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        return match value {
+            "manual" => Ok(ThresholdSource::Manual),
+            "device" => Ok(ThresholdSource::Device),
+            _ => bail!("Unknown threshold source '{}'", value)
+        };
+    }
+}
+
+impl ThresholdSource {
+    pub fn as_str(&self) -> &str {
+        return match self {
+            ThresholdSource::Manual => "manual",
+            ThresholdSource::Device => "device"
+        };
+    }
+}
+
+/// Values which apply from `effective_from` until a later entry changes them, empty values are inherited from earlier entries
+#[derive(Debug, Clone)]
+pub struct Threshold {
+    pub id: Option<i64>,
+    pub athlete_id: i64,
+    pub effective_from: i64,
+    pub ftp: Option<i64>,
+    pub lthr: Option<i64>,
+    pub max_heart_rate: Option<i64>,
+    /// Kilograms
+    pub weight: Option<f64>,
+    pub source: ThresholdSource,
+    /// Session the values were read from when they came from the device
+    pub session_id: Option<i64>
+}
+
+/// Threshold entries of one athlete, oldest first
+#[derive(Debug, Clone, Default)]
+pub struct ThresholdHistory {
+    pub entries: Vec<Threshold>
+}
+
+impl ThresholdHistory {
+    pub fn new(mut entries: Vec<Threshold>) -> Self {
+        entries.sort_by_key(|x| (x.effective_from, x.id));
+        return Self { entries };
+    }
+
+    /// Latest value of a field set on or before the timestamp, with the source of the entry it came from
+    fn value_at<T: Copy>(&self, timestamp: i64, field: impl Fn(&Threshold) -> Option<T>) -> Option<(T, ThresholdSource)> {
+        return self.entries.iter()
+            .filter(|x| x.effective_from <= timestamp)
+            .filter_map(|x| field(x).map(|value| (value, x.source)))
+            .last();
+    }
+
+    pub fn ftp_at(&self, timestamp: i64) -> Option<i64> {
+        return self.value_at(timestamp, |x| x.ftp).map(|x| x.0);
+    }
+
+    pub fn lthr_at(&self, timestamp: i64) -> Option<i64> {
+        return self.value_at(timestamp, |x| x.lthr).map(|x| x.0);
+    }
+
+    pub fn max_heart_rate_at(&self, timestamp: i64) -> Option<i64> {
+        return self.value_at(timestamp, |x| x.max_heart_rate).map(|x| x.0);
+    }
+
+    pub fn weight_at(&self, timestamp: i64) -> Option<f64> {
+        return self.value_at(timestamp, |x| x.weight).map(|x| x.0);
+    }
+
+    /// Threshold power for a session, the value the device recorded when the history has none
+    pub fn ftp_for(&self, session: &Session) -> i64 {
+        return self.ftp_at(session.start_time).unwrap_or(session.threshold_power);
+    }
+
+    /// Threshold heart rate for a session, the value the device recorded when the history has none
+    pub fn lthr_for(&self, session: &Session) -> i64 {
+        return self.lthr_at(session.start_time).unwrap_or(session.threshold_heart_rate);
+    }
+
+    /// Max heart rate for a session, the value the device recorded when the history has none
+    pub fn max_heart_rate_for(&self, session: &Session) -> i64 {
+        return self.max_heart_rate_at(session.start_time).unwrap_or(session.max_heart_rate);
+    }
+
+    /// FTP after every change, for the dashboard sparkline
+    pub fn ftp_series(&self) -> Vec<u64> {
+        return self.entries.iter()
+            .filter_map(|x| x.ftp)
+            .map(|x| x.max(0) as u64)
+            .collect();
+    }
+
+    /// Entry with the device values of a session which differ from the history at that time.
+    /// Values are left out where a manual entry applies or the device didn't record them
+    pub fn device_entry(&self, athlete_id: i64, session_id: i64, session: &Session) -> Option<Threshold> {
+        let start_time = session.start_time;
+
+        fn changed<T: Copy + PartialEq + Default>(recorded: T, current: Option<(T, ThresholdSource)>) -> Option<T> {
+            return match current {
+                _ if recorded == T::default() => None,
+                Some((_, ThresholdSource::Manual)) => None,
+                Some((value, _)) if value == recorded => None,
+                _ => Some(recorded)
+            };
+        }
+
+        let entry = Threshold {
+            id: None,
+            athlete_id,
+            effective_from: start_time,
+            ftp: changed(session.threshold_power, self.value_at(start_time, |x| x.ftp)),
+            lthr: changed(session.threshold_heart_rate, self.value_at(start_time, |x| x.lthr)),
+            max_heart_rate: changed(session.max_heart_rate, self.value_at(start_time, |x| x.max_heart_rate)),
+            weight: changed(session.weight, self.value_at(start_time, |x| x.weight)),
+            source: ThresholdSource::Device,
+            session_id: Some(session_id)
+        };
+
+        if entry.ftp.is_none() && entry.lthr.is_none() && entry.max_heart_rate.is_none() && entry.weight.is_none() {
+            return None;
+        }
+
+        return Some(entry);
+    }
+}
+
+/// Handles `threshold <add|list|delete>` commands
+pub fn command(store: &mut dyn SessionStore, args: &[String]) -> Result<()> {
+    let command: Vec<&str> = args.iter().take(2).map(|x| x.as_str()).collect();
+
+    match command[..] {
+        ["threshold", "add"] => {
+            let athlete = Athlete::from_args(store, args)?;
+
+            let threshold = Threshold {
+                id: None,
+                athlete_id: athlete.id,
+                effective_from: date_flag_or_now(args, "--date")?,
+                ftp: number_flag(args, "--ftp")?,
+                lthr: number_flag(args, "--lthr")?,
+                max_heart_rate: number_flag(args, "--max-hr")?,
+                weight: match flag_value(args, "--weight") {
+                    Some(value) => Some(value.parse::<f64>()?),
+                    None => None
+                },
+                source: ThresholdSource::Manual,
+                session_id: None
+            };
+
+            if threshold.ftp.is_none() && threshold.lthr.is_none() && threshold.max_heart_rate.is_none() && threshold.weight.is_none() {
+                bail!("Give at least one of --ftp, --lthr, --max-hr or --weight");
+            }
+
+            let threshold_id = store.insert_threshold(&threshold)?;
+            println!("Threshold {} added for {}", threshold_id, athlete.name);
+        },
+        ["threshold", "list"] => {
+            let athlete = Athlete::from_args(store, args)?;
+
+            for threshold in store.get_thresholds(athlete.id)?.entries {
+                println!("{}: {} FTP {} LTHR {} max HR {} weight {} ({})"
+                    , threshold.id.unwrap_or(0)
+                    , date_as_string(threshold.effective_from)
+                    , optional_to_string(threshold.ftp)
+                    , optional_to_string(threshold.lthr)
+                    , optional_to_string(threshold.max_heart_rate)
+                    , optional_to_string(threshold.weight)
+                    , threshold.source.as_str());
+            }
+        },
+        ["threshold", "estimate"] => {
+            ftp_estimate::command(store, args)?;
+        },
+        ["threshold", "delete"] if args.len() > 2 => {
+            store.delete_threshold(args[2].parse::<i64>()?)?;
+            println!("Threshold {} deleted", args[2]);
+        },
+        _ => bail!("Unknown threshold command: {}", args.join(" "))
+    }
+
+    return Ok(());
+}
+
+fn number_flag(args: &[String], flag: &str) -> Result<Option<i64>> {
+    return match flag_value(args, flag) {
+        Some(value) => Ok(Some(value.parse::<i64>()?)),
+        None => Ok(None)
+    };
+}
+
+pub fn optional_to_string<T: ToString>(value: Option<T>) -> String {
+    return value.map(|x| x.to_string()).unwrap_or(String::from("-"));
+}
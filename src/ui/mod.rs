@@ -1,40 +1,54 @@
-use chrono::{NaiveDateTime, Datelike, format};
+use chrono::{NaiveDate, Datelike, Local};
 use itertools::Itertools;
 use tui::{
     backend::Backend,
     layout::{Constraint, Direction, Layout, Rect},
-    widgets::{Block, Borders, Dataset, GraphType, Chart, Axis, ListItem, List, ListState, Paragraph, BarChart},
-    Frame, text::{Span, Spans}, style::{Style, Color, Modifier}, symbols::{self},
+    widgets::{Block, Borders, Dataset, GraphType, Chart, Axis, ListItem, List, ListState, Paragraph, BarChart, Clear, Wrap, Sparkline, Tabs, Table, Row, Cell},
+    Frame, text::{Span, Spans}, style::{Style, Color, Modifier}, symbols::{self}, layout::Alignment,
 };
 
-use crate::{app::App, summary::Summary};
+use crate::{app::{App, EditField, InputMode, View, VIEWS, PMC_WINDOWS}, critical_power::CpModel, import::log_entry_as_string, pmc, power_curve::{self, CURVE_DURATIONS, PowerCurve}, store::SessionStore, zones::TimeInZones, summary::{Summary, DateRange, CumulativeYear, ROLLING_WINDOWS, local_date}, threshold::optional_to_string};
+
+/// Periods shown on the trends view
+pub const TREND_PERIODS_COUNT: usize = 12;
+
+/// Years overlaid on the year over year view, the selected year first
+pub const COMPARED_YEARS_COUNT: usize = 3;
+const COMPARED_YEAR_COLORS: [Color; COMPARED_YEARS_COUNT] = [Color::Cyan, Color::Magenta, Color::Yellow];
 
 pub mod util;
 
-struct Total {
+/// Totals of the dashboard cards, kept in the app and read again with the sessions or the summary year
+#[derive(Default)]
+pub struct Total {
     overall_distance: f64,
     overall_duration: f64,
     overall_rides_count: i64,
+    overall_ascent: f64,
+    overall_work: f64,
     indoor_distance: f64,
     indoor_duration: f64,
     indoor_rides_count: i64,
     road_distance: f64,
     road_duration: f64,
     road_rides_count: i64,
+    road_ascent: f64,
     mtb_distance: f64,
     mtb_duration: f64,
     mtb_rides_count: i64,
+    mtb_ascent: f64
 }
 
 impl Total {
-    pub fn new() -> Self {
-        //todo: get current year
-        let overall = Summary::overall(2022);
-        let detailed = Summary::detailed(2022);
+    pub fn new(store: &dyn SessionStore, athlete_id: i64, range: &DateRange) -> Self {
+        let overall = Summary::overall(store, athlete_id, range);
+        let detailed = Summary::detailed(store, athlete_id, range);
 
         let mut total_overall_distance = 0.0;
         let mut total_overall_duration = 0.0;
         let mut total_overall_rides = 0;
+        let mut total_overall_ascent = 0.0;
+        let mut total_overall_work = 0.0;
 
         let mut total_indoor_distance = 0.0;
         let mut total_indoor_duration = 0.0;
@@ -43,82 +57,621 @@ impl Total {
         let mut total_road_distance = 0.0;
         let mut total_road_duration = 0.0;
         let mut total_road_rides = 0;
+        let mut total_road_ascent = 0.0;
         
         let mut total_mtb_distance = 0.0;
         let mut total_mtb_duration = 0.0;
         let mut total_mtb_rides = 0;
+        let mut total_mtb_ascent = 0.0;
 
-        if overall.is_ok() {
-            let overall_data = overall.unwrap();
+        if let Ok(overall_data) = overall {
             total_overall_distance = overall_data.total_distance;
             total_overall_duration = overall_data.total_time;
             total_overall_rides = overall_data.rides_count;
+            total_overall_ascent = overall_data.total_ascent;
+            total_overall_work = overall_data.total_work;
         }
 
-        if detailed.is_ok() {
-            let details = detailed.unwrap();
+        if let Ok(details) = detailed {
 
             let indoor_summary = details.iter().find(|x| x.sub_sport == Some("indoor_cycling".to_string()));
             let road_summary = details.iter().find(|x| x.sub_sport == Some("road_cycling".to_string())); // don't know the string in the file
             let mtb_summary = details.iter().find(|x| x.sub_sport == Some("mountain_bike_ride".to_string())); // don't know the string in the file
 
-            match indoor_summary {
-                Some(value) => {
-                    total_indoor_duration = value.total_time;
-                    total_indoor_distance = value.total_distance;
-                    total_indoor_rides = value.rides_count;
-                },
-                None => ()
-            };
+            if let Some(value) = indoor_summary {
+                total_indoor_duration = value.total_time;
+                total_indoor_distance = value.total_distance;
+                total_indoor_rides = value.rides_count;
+            }
 
-            match road_summary {
-                Some(value) => {
-                    total_road_duration = value.total_time;
-                    total_road_distance = value.total_distance;
-                    total_road_rides = value.rides_count;
-                },
-                None => ()
-            };
+            if let Some(value) = road_summary {
+                total_road_duration = value.total_time;
+                total_road_distance = value.total_distance;
+                total_road_rides = value.rides_count;
+                total_road_ascent = value.total_ascent;
+            }
 
-            match mtb_summary {
-                Some(value) => {
-                    total_mtb_duration = value.total_time;
-                    total_mtb_distance = value.total_distance;
-                    total_mtb_rides = value.rides_count;
-                },
-                None => ()
-            };
+            if let Some(value) = mtb_summary {
+                total_mtb_duration = value.total_time;
+                total_mtb_distance = value.total_distance;
+                total_mtb_rides = value.rides_count;
+                total_mtb_ascent = value.total_ascent;
+            }
         }
         
         return Self {
             overall_distance: total_overall_distance,
             overall_duration: total_overall_duration,
             overall_rides_count: total_overall_rides,
+            overall_ascent: total_overall_ascent,
+            overall_work: total_overall_work,
             indoor_distance: total_indoor_distance,
             indoor_duration: total_indoor_duration,
             indoor_rides_count: total_indoor_rides,
             road_distance: total_road_distance,
             road_duration: total_road_duration,
             road_rides_count: total_road_rides,
+            road_ascent: total_road_ascent,
             mtb_distance: total_mtb_distance,
             mtb_duration: total_mtb_duration, 
-            mtb_rides_count: total_mtb_rides
+            mtb_rides_count: total_mtb_rides,
+            mtb_ascent: total_mtb_ascent
         };
     }
 }
 
-pub fn draw_dashboard<B: Backend>(f: &mut Frame<B>, app: &App) {
+pub fn draw<B: Backend>(f: &mut Frame<B>, app: &App) {
     let parent_layout = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([
+            Constraint::Length(1),
+            Constraint::Min(0),
+        ].as_ref())
+        .margin(1)
+        .split(f.size());
+
+    draw_tabs(f, parent_layout[0], app);
+
+    match app.view {
+        View::Sessions => draw_dashboard(f, parent_layout[1], app),
+        View::Trends => draw_trends(f, parent_layout[1], app),
+        View::YearOverYear => draw_year_over_year(f, parent_layout[1], app),
+        View::Performance => draw_performance(f, parent_layout[1], app),
+        View::PowerCurve => draw_power_curve(f, parent_layout[1], app),
+        View::PersonalRecords => draw_personal_records(f, parent_layout[1], app),
+        View::Imports => draw_import_log(f, parent_layout[1], app)
+    }
+
+    if app.input_mode != InputMode::Normal {
+        draw_input_popup(f, app);
+    }
+}
+
+fn draw_tabs<B: Backend>(f: &mut Frame<B>, layout: Rect, app: &App) {
+    let titles = VIEWS.iter()
+        .map(|x| Spans::from(x.title()))
+        .collect();
+
+    let tabs = Tabs::new(titles)
+        .select(VIEWS.iter().position(|x| *x == app.view).unwrap_or(0))
+        .highlight_style(Style::default().fg(Color::Yellow).add_modifier(Modifier::BOLD));
+
+    let mut keys = vec![Span::raw("tab: switch view, q: quit")];
+
+    // An error of the last key press takes the place of the FTP estimate until the next key
+    if let Some(error) = &app.error {
+        keys.insert(0, Span::styled(format!("{}  ", error), Style::default().fg(Color::Red).add_modifier(Modifier::BOLD)));
+    } else if let Some(estimate) = &app.ftp_estimate {
+        keys.insert(0, Span::styled(
+            format!("{} from {}, a: accept  ", estimate.description(), estimate.method.label()),
+            Style::default().fg(Color::Yellow).add_modifier(Modifier::BOLD)));
+    }
+
+    let keys = Paragraph::new(Spans::from(keys))
+        .alignment(Alignment::Right);
+
+    f.render_widget(tabs, layout);
+    f.render_widget(keys, layout);
+}
+
+fn draw_dashboard<B: Backend>(f: &mut Frame<B>, layout: Rect, app: &App) {
+    let chunks = Layout::default()
         .direction(Direction::Vertical)
         .constraints([
             Constraint::Percentage(60),
             Constraint::Percentage(40),
         ].as_ref())
+        .split(layout);
+
+    draw_summary(f, chunks[0], app);
+    draw_session_chart(f, chunks[1], app);
+}
+
+fn draw_trends<B: Backend>(f: &mut Frame<B>, layout: Rect, app: &App) {
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([
+            Constraint::Length(7),
+            Constraint::Min(0)
+        ].as_ref())
         .margin(1)
-        .split(f.size());
+        .split(layout);
+
+    let rolling_chunks = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints(ROLLING_WINDOWS.iter().map(|_| Constraint::Ratio(1, ROLLING_WINDOWS.len() as u32)).collect::<Vec<Constraint>>())
+        .split(chunks[0]);
+
+    for ((days, summary), area) in ROLLING_WINDOWS.iter().zip(app.rolling_summaries.iter()).zip(rolling_chunks.iter()) {
+        let paragraph = Paragraph::new(summary_as_text(summary))
+            .block(Block::default().title(format!("Last {} days", days)).borders(Borders::ALL));
+
+        f.render_widget(paragraph, *area);
+    }
+
+    let period_chunks = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([
+            Constraint::Percentage(60),
+            Constraint::Percentage(40)
+        ].as_ref())
+        .split(chunks[1]);
+
+    let periods = &app.trend_periods;
+
+    let data: Vec<(&str, u64)> = periods.iter()
+        .map(|x| (x.label.as_str(), (x.summary.total_time / 60.0) as u64))
+        .collect();
+
+    let bar_chart = BarChart::default()
+        .block(Block::default()
+            .title(format!("Minutes by {} (w/m/y: week/month/year)", app.trend_period.as_str()))
+            .borders(Borders::ALL))
+        .bar_width(8)
+        .bar_gap(1)
+        .data(&data);
 
-    draw_summary(f, parent_layout[0], &app);
-    draw_session_chart(f, parent_layout[1], &app);
+    let items: Vec<ListItem> = periods.iter()
+        .rev()
+        .map(|x| ListItem::new(format!("{}: {} rides, {}, {}, {}, {}"
+            , x.label
+            , x.summary.rides_count
+            , util::distance_as_string(x.summary.total_distance)
+            , util::moving_time_to_hour_minute_string(x.summary.total_time)
+            , util::ascent_as_string(x.summary.total_ascent)
+            , util::work_as_string(x.summary.total_work))))
+        .collect();
+
+    let list = List::new(items)
+        .block(Block::default().title("Totals").borders(Borders::ALL));
+
+    f.render_widget(bar_chart, period_chunks[0]);
+    f.render_widget(list, period_chunks[1]);
+}
+
+fn draw_year_over_year<B: Backend>(f: &mut Frame<B>, layout: Rect, app: &App) {
+    let years = &app.compared_years;
+
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([
+            Constraint::Min(0),
+            Constraint::Length(COMPARED_YEARS_COUNT as u16 + 3)
+        ].as_ref())
+        .margin(1)
+        .split(layout);
+
+    let chart_chunks = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([
+            Constraint::Percentage(50),
+            Constraint::Percentage(50)
+        ].as_ref())
+        .split(chunks[0]);
+
+    let day = years.first().map(|x| x.comparison_day()).unwrap_or(0);
+
+    draw_cumulative_chart(f, chart_chunks[0], years, day, "Cumulative distance (km)", |x| x.total_distance / 1000.0);
+    draw_cumulative_chart(f, chart_chunks[1], years, day, "Cumulative hours", |x| x.total_time / 3600.0);
+
+    let current = years.first().map(|x| x.at(day)).unwrap_or_default();
+    let rows: Vec<Row> = years.iter()
+        .enumerate()
+        .map(|(idx, year)| {
+            let summary = year.at(day);
+            let distance_delta = current.total_distance - summary.total_distance;
+            let time_delta = current.total_time - summary.total_time;
+            let delta_style = match distance_delta {
+                value if value > 0.0 => Style::default().fg(Color::Green),
+                value if value < 0.0 => Style::default().fg(Color::Red),
+                _ => Style::default()
+            };
+            let (distance_delta_text, time_delta_text) = match idx {
+                0 => (String::new(), String::new()),
+                _ => (signed_as_string(distance_delta, util::distance_as_string)
+                    , signed_as_string(time_delta, util::moving_time_to_hour_minute_string))
+            };
+
+            return Row::new(vec![
+                Cell::from(year.year.to_string()).style(Style::default().fg(COMPARED_YEAR_COLORS[idx % COMPARED_YEAR_COLORS.len()])),
+                Cell::from(util::distance_as_string(summary.total_distance)),
+                Cell::from(util::moving_time_to_hour_minute_string(summary.total_time)),
+                Cell::from(summary.rides_count.to_string()),
+                Cell::from(distance_delta_text).style(delta_style),
+                Cell::from(time_delta_text).style(delta_style),
+                Cell::from(util::distance_as_string(year.at(year.days.len()).total_distance)),
+            ]);
+        })
+        .collect();
+
+    let day_label = NaiveDate::from_yo_opt(app.summary_year, day.max(1) as u32)
+        .map(|x| x.format("%b %d").to_string())
+        .unwrap_or_default();
+
+    let table = Table::new(rows)
+        .header(Row::new(vec!["Year", "Distance", "Time", "Rides", "Distance ahead", "Time ahead", "Full year"])
+            .style(Style::default().add_modifier(Modifier::BOLD)))
+        .block(Block::default()
+            .title(format!("Up to {} (h/l: year)", day_label))
+            .borders(Borders::ALL))
+        .widths(&[
+            Constraint::Length(6),
+            Constraint::Length(12),
+            Constraint::Length(10),
+            Constraint::Length(6),
+            Constraint::Length(16),
+            Constraint::Length(12),
+            Constraint::Length(12)
+        ]);
+
+    f.render_widget(table, chunks[1]);
+}
+
+/// Overlays the running totals of the years, the selected year only up to the comparison day
+fn draw_cumulative_chart<B: Backend>(f: &mut Frame<B>, layout: Rect, years: &[CumulativeYear], day: usize, title: &str, value: fn(&Summary) -> f64) {
+    let data: Vec<Vec<(f64, f64)>> = years.iter()
+        .enumerate()
+        .map(|(idx, year)| year.days.iter()
+            .take(if idx == 0 { day } else { year.days.len() })
+            .enumerate()
+            .map(|(day_idx, summary)| ((day_idx + 1) as f64, value(summary)))
+            .collect())
+        .collect();
+
+    let max_y = data.iter()
+        .flatten()
+        .map(|x| x.1)
+        .fold(0.0, f64::max)
+        .max(1.0);
+
+    let datasets = years.iter()
+        .zip(data.iter())
+        .enumerate()
+        .map(|(idx, (year, points))| Dataset::default()
+            .name(year.year.to_string())
+            .marker(symbols::Marker::Braille)
+            .graph_type(GraphType::Line)
+            .style(Style::default().fg(COMPARED_YEAR_COLORS[idx % COMPARED_YEAR_COLORS.len()]))
+            .data(points))
+        .collect();
+
+    let chart = Chart::new(datasets)
+        .block(Block::default().title(title.to_string()).borders(Borders::ALL))
+        .x_axis(Axis::default()
+            .style(Style::default().fg(Color::White))
+            .bounds([1.0, 366.0])
+            .labels(["Jan", "Apr", "Jul", "Oct", "Dec"].iter().cloned().map(Span::from).collect()))
+        .y_axis(Axis::default()
+            .style(Style::default().fg(Color::White))
+            .bounds([0.0, max_y])
+            .labels(["0".to_string(), format!("{:.0}", max_y)].iter().cloned().map(Span::from).collect()));
+
+    f.render_widget(chart, layout);
+}
+
+/// Formats a difference with its sign, the formatter gets the absolute value
+fn signed_as_string(value: f64, format: fn(f64) -> String) -> String {
+    return format!("{}{}", if value < 0.0 { "-" } else { "+" }, format(value.abs()));
+}
+
+fn summary_as_text(summary: &Summary) -> Vec<Spans<'_>> {
+    return vec![
+        Spans::from(format!("Duration: {}", util::moving_time_to_hour_minute_string(summary.total_time))),
+        Spans::from(format!("Distance: {}", util::distance_as_string(summary.total_distance))),
+        Spans::from(format!("Rides: {}", summary.rides_count)),
+        Spans::from(format!("Elevation: {} Work: {}"
+            , util::ascent_as_string(summary.total_ascent)
+            , util::work_as_string(summary.total_work))),
+        Spans::from(format!("Training stress: {:.0}", summary.total_training_stress)),
+    ];
+}
+
+fn draw_power_curve<B: Backend>(f: &mut Frame<B>, layout: Rect, app: &App) {
+    let periods: Vec<_> = [Some(app.curve_period), app.compared_curve_period].into_iter()
+        .flatten()
+        .collect();
+    let curves = &app.power_curves;
+
+    let chunks = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([
+            Constraint::Percentage(75),
+            Constraint::Percentage(25)
+        ].as_ref())
+        .margin(1)
+        .split(layout);
+
+    let data: Vec<Vec<(f64, f64)>> = curves.iter()
+        .map(|curve| curve.points.iter()
+            .map(|x| ((x.seconds as f64).log10(), x.power))
+            .collect())
+        .collect();
+
+    let max_x = (CURVE_DURATIONS[CURVE_DURATIONS.len() - 1] as f64).log10();
+    let max_y = (data.iter().flatten().map(|x| x.1).fold(0.0, f64::max) * 1.1).max(100.0).ceil();
+
+    // The 3-parameter model of the first period, the 2-parameter one when the short efforts are missing
+    let fits = &app.power_curve_fits;
+    let fit = fits.iter().find(|x| x.model == CpModel::ThreeParameter).or(fits.first());
+
+    let model_data: Vec<(f64, f64)> = match fit {
+        Some(value) => (0..=100)
+            .map(|idx| max_x * idx as f64 / 100.0)
+            .map(|x| (x, value.power_at(10f64.powf(x)).min(max_y)))
+            .collect(),
+        None => Vec::new()
+    };
+
+    let mut datasets: Vec<Dataset> = periods.iter()
+        .zip(data.iter())
+        .zip([Color::Cyan, Color::Magenta])
+        .map(|((period, points), color)| Dataset::default()
+            .name(period.label().to_string())
+            .marker(symbols::Marker::Braille)
+            .graph_type(GraphType::Line)
+            .style(Style::default().fg(color))
+            .data(points))
+        .collect();
+
+    if let Some(value) = fit {
+        datasets.push(Dataset::default()
+            .name(format!("{} model", value.model.label()))
+            .marker(symbols::Marker::Braille)
+            .graph_type(GraphType::Line)
+            .style(Style::default().fg(Color::White))
+            .data(&model_data));
+    }
+
+    // Labels are spread evenly over the log axis, rounded to the minute or hour
+    let x_labels: Vec<Span> = (0..5)
+        .map(|idx| {
+            let seconds = 10f64.powf(max_x * idx as f64 / 4.0).round() as i64;
+            let rounded = match seconds {
+                value if value < 60 => value,
+                value if value < 3600 => (value as f64 / 60.0).round() as i64 * 60,
+                value => (value as f64 / 3600.0).round() as i64 * 3600
+            };
+            return Span::from(power_curve::duration_as_string(rounded));
+        })
+        .collect();
+
+    let chart = Chart::new(datasets)
+        .block(Block::default()
+            .title("Mean-maximal power (c: period, v: compared period)")
+            .borders(Borders::ALL))
+        .x_axis(Axis::default()
+            .style(Style::default().fg(Color::White))
+            .bounds([0.0, max_x])
+            .labels(x_labels))
+        .y_axis(Axis::default()
+            .title(Span::styled("W", Style::default().fg(Color::Red)))
+            .style(Style::default().fg(Color::White))
+            .bounds([0.0, max_y])
+            .labels(["0".to_string(), format!("{:.0}", max_y)].iter().cloned().map(Span::from).collect()));
+
+    let power_at = |curve: Option<&PowerCurve>, seconds: i64| curve
+        .and_then(|x| x.points.iter().find(|point| point.seconds == seconds))
+        .map(|x| format!("{:.0}W", x.power))
+        .unwrap_or(String::from("-"));
+
+    let text: Vec<Spans> = [5, 60, 300, 1200, 3600].iter()
+        .map(|seconds| Spans::from(format!("{}: {} / {}"
+            , power_curve::duration_as_string(*seconds)
+            , power_at(curves.first(), *seconds)
+            , power_at(curves.get(1), *seconds))))
+        .collect();
+
+    let paragraph = Paragraph::new(text)
+        .block(Block::default()
+            .title(periods.iter().map(|x| x.label()).join(" / "))
+            .borders(Borders::ALL));
+
+    let side_chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([
+            Constraint::Length(7),
+            Constraint::Length(6),
+            Constraint::Min(0)
+        ].as_ref())
+        .split(chunks[1]);
+
+    let fit_text: Vec<Spans> = match fit {
+        Some(value) => value.description().into_iter().map(Spans::from).collect(),
+        None => vec![Spans::from("Not enough efforts")]
+    };
+
+    let fit_paragraph = Paragraph::new(fit_text)
+        .block(Block::default()
+            .title(format!("Critical power, {}", fit.map(|x| x.model.label()).unwrap_or("no model")))
+            .borders(Borders::ALL))
+        .wrap(Wrap { trim: true });
+
+    // History of the same model as the fit shown
+    let history_model = fit.map(|x| x.model).unwrap_or(CpModel::ThreeParameter);
+    let cp_history: Vec<u64> = app.critical_power.iter()
+        .filter(|x| x.model == history_model)
+        .map(|x| x.critical_power.max(0.0) as u64)
+        .collect();
+
+    let sparkline = Sparkline::default()
+        .block(Block::default()
+            .title(format!("CP history ({} days)", cp_history.len()))
+            .borders(Borders::ALL))
+        .style(Style::default().fg(Color::Yellow))
+        .data(&cp_history[cp_history.len().saturating_sub(side_chunks[2].width.saturating_sub(2) as usize)..]);
+
+    f.render_widget(chart, chunks[0]);
+    f.render_widget(paragraph, side_chunks[0]);
+    f.render_widget(fit_paragraph, side_chunks[1]);
+    f.render_widget(sparkline, side_chunks[2]);
+}
+
+fn draw_performance<B: Backend>(f: &mut Frame<B>, layout: Rect, app: &App) {
+    let constants = app.time_constants;
+    let window = PMC_WINDOWS[app.pmc_window % PMC_WINDOWS.len()];
+    let days = &app.performance[app.performance.len().saturating_sub(window)..];
+
+    let chunks = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([
+            Constraint::Percentage(80),
+            Constraint::Percentage(20)
+        ].as_ref())
+        .margin(1)
+        .split(layout);
+
+    let series = |value: fn(&pmc::PmcDay) -> f64| -> Vec<(f64, f64)> {
+        return days.iter()
+            .enumerate()
+            .map(|(idx, day)| (idx as f64, value(day)))
+            .collect();
+    };
+    let fitness = series(|x| x.fitness);
+    let fatigue = series(|x| x.fatigue);
+    let form = series(|x| x.form);
+
+    let values = || fitness.iter().chain(fatigue.iter()).chain(form.iter()).map(|x| x.1);
+    let min_y = values().fold(0.0, f64::min).floor();
+    let max_y = values().fold(1.0, f64::max).ceil();
+    let max_x = days.len().saturating_sub(1).max(1) as f64;
+
+    let datasets = vec![
+        Dataset::default()
+            .name(format!("Fitness (CTL {} d)", constants.fitness_days))
+            .marker(symbols::Marker::Braille)
+            .graph_type(GraphType::Line)
+            .style(Style::default().fg(Color::Cyan))
+            .data(&fitness),
+        Dataset::default()
+            .name(format!("Fatigue (ATL {} d)", constants.fatigue_days))
+            .marker(symbols::Marker::Braille)
+            .graph_type(GraphType::Line)
+            .style(Style::default().fg(Color::Magenta))
+            .data(&fatigue),
+        Dataset::default()
+            .name("Form (TSB)")
+            .marker(symbols::Marker::Braille)
+            .graph_type(GraphType::Line)
+            .style(Style::default().fg(Color::Yellow))
+            .data(&form),
+    ];
+
+    let date_label = |day: Option<&pmc::PmcDay>| day.map(|x| util::date_as_string(x.day)).unwrap_or_default();
+    let window_title = match window {
+        usize::MAX => String::from("every day"),
+        value => format!("last {} days", value)
+    };
+
+    let chart = Chart::new(datasets)
+        .block(Block::default()
+            .title(format!("Performance, {} (z: zoom)", window_title))
+            .borders(Borders::ALL))
+        .x_axis(Axis::default()
+            .style(Style::default().fg(Color::White))
+            .bounds([0.0, max_x])
+            .labels([date_label(days.first()), date_label(days.last())].iter().cloned().map(Span::from).collect()))
+        .y_axis(Axis::default()
+            .style(Style::default().fg(Color::White))
+            .bounds([min_y, max_y])
+            .labels([format!("{:.0}", min_y), format!("{:.0}", max_y)].iter().cloned().map(Span::from).collect()));
+
+    let text: Vec<Spans> = match days.last() {
+        Some(today) => vec![
+            Spans::from(format!("Fitness: {:.1}", today.fitness)),
+            Spans::from(format!("Fatigue: {:.1}", today.fatigue)),
+            Spans::from(format!("Form: {:.1}", today.form)),
+            Spans::from(""),
+            Spans::from("Last 7 days TSS:"),
+        ].into_iter()
+            .chain(days.iter().rev().take(7).map(|x| Spans::from(format!("{} {:.0}", util::date_as_string(x.day), x.load))))
+            .collect(),
+        None => vec![Spans::from("No sessions")]
+    };
+
+    let paragraph = Paragraph::new(text)
+        .block(Block::default().title("Today").borders(Borders::ALL));
+
+    f.render_widget(chart, chunks[0]);
+    f.render_widget(paragraph, chunks[1]);
+}
+
+fn draw_personal_records<B: Backend>(f: &mut Frame<B>, layout: Rect, app: &App) {
+    let chunks = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([
+            Constraint::Percentage(50),
+            Constraint::Percentage(50)
+        ].as_ref())
+        .margin(1)
+        .split(layout);
+
+    let current: Vec<Spans> = app.personal_records.current().iter()
+        .map(|record| {
+            let session = app.sessions.iter()
+                .find(|x| x.id == Some(record.session_id))
+                .map(util::sessio_to_string)
+                .unwrap_or(format!("session {}", record.session_id));
+
+            return Spans::from(vec![
+                Span::styled(format!("{}: ", record.kind.label()), Style::default().add_modifier(Modifier::BOLD)),
+                Span::raw(format!("{} ({})", record.kind.value_as_string(record.value), session))
+            ]);
+        })
+        .collect();
+
+    let current_paragraph = Paragraph::new(current)
+        .block(Block::default()
+            .title(format!("Personal records of {} (p: athlete)", app.athlete().map(|x| x.name.clone()).unwrap_or_default()))
+            .borders(Borders::ALL));
+
+    let history: Vec<ListItem> = app.personal_records.history().into_iter()
+        .map(ListItem::new)
+        .collect();
+
+    let history_list = List::new(history)
+        .block(Block::default().title("History").borders(Borders::ALL));
+
+    f.render_widget(current_paragraph, chunks[0]);
+    f.render_widget(history_list, chunks[1]);
+}
+
+fn draw_input_popup<B: Backend>(f: &mut Frame<B>, app: &App) {
+    let (title, text) = match &app.input_mode {
+        InputMode::Edit(EditField::Title, value) => ("Session title (enter to save, esc to cancel)", format!("{}_", value)),
+        InputMode::Edit(EditField::SubSport, value) => ("Sub sport (enter to save, esc to cancel)", format!("{}_", value)),
+        InputMode::Edit(EditField::Notes, value) => ("Notes (enter to save, esc to cancel)", format!("{}_", value)),
+        InputMode::Edit(EditField::Tags, value) => ("Tags, comma separated (enter to save, esc to cancel)", format!("{}_", value)),
+        InputMode::Edit(EditField::Search, value) => ("Search (enter to keep the filter, esc to clear)", format!("{}_", value)),
+        InputMode::ConfirmDelete => ("Delete session", String::from("Delete the selected session? (y/n)")),
+        InputMode::Normal => return
+    };
+
+    let area = util::centered_rect(50, 3, f.size());
+    let paragraph = Paragraph::new(text)
+        .block(Block::default().title(title).borders(Borders::ALL));
+
+    f.render_widget(Clear, area);
+    f.render_widget(paragraph, area);
 }
 
 fn draw_summary<B: Backend>(f: &mut Frame<B>, layout: Rect, app: &App) {
@@ -131,8 +684,41 @@ fn draw_summary<B: Backend>(f: &mut Frame<B>, layout: Rect, app: &App) {
         .margin(1)
         .split(layout);
 
-    draw_overview_section(f, chunks[0], &app);
-    draw_session_list(f, chunks[1], &app);
+    let right_chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([
+            Constraint::Percentage(65),
+            Constraint::Percentage(35)
+        ].as_ref())
+        .split(chunks[1]);
+
+    draw_overview_section(f, chunks[0], app);
+    draw_session_list(f, right_chunks[0], app);
+    draw_gear_section(f, right_chunks[1], app);
+}
+
+fn draw_gear_section<B: Backend>(f: &mut Frame<B>, layout: Rect, app: &App) {
+    let mut text: Vec<Spans> = Vec::new();
+
+    for bike in &app.bikes {
+        text.push(Spans::from(Span::styled(
+            format!("{} {} {}"
+                , bike.name
+                , util::distance_as_string(bike.total_distance)
+                , util::moving_time_to_hour_minute_string(bike.total_time)),
+            Style::default().add_modifier(Modifier::BOLD))));
+
+        for component in bike.components_due() {
+            text.push(Spans::from(Span::styled(
+                format!("  {}: {}", component.name, component.service_status()),
+                Style::default().fg(Color::Red))));
+        }
+    }
+
+    let paragraph = Paragraph::new(text)
+        .block(Block::default().title("Gear (b: change bike)").borders(Borders::ALL));
+
+    f.render_widget(paragraph, layout);
 }
 
 fn draw_overview_section<B: Backend>(f: &mut Frame<B>, layout: Rect, app: &App) {
@@ -146,9 +732,8 @@ fn draw_overview_section<B: Backend>(f: &mut Frame<B>, layout: Rect, app: &App)
 
     let data: Vec<(String, u64)> = app.sessions.iter()
         .map(|session| {
-            let naive_datetime = NaiveDateTime::from_timestamp_opt(session.start_time, 0).unwrap();
             let duration = session.total_moving_time as u64;
-            return (format!("W{}", naive_datetime.iso_week().week().to_string()), duration);
+            return (format!("W{}", local_date(session.start_time).iso_week().week()), duration);
         }).collect();
 
     let summed: Vec<(String, u64)> = data.iter()
@@ -173,12 +758,61 @@ fn draw_overview_section<B: Backend>(f: &mut Frame<B>, layout: Rect, app: &App)
         .bar_gap(1)
         .data(&result);
 
-    draw_summary_section(f, chunks[0]);
-    f.render_widget(bar_chart, chunks[1]);
+    let bottom_chunks = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([
+            Constraint::Percentage(65),
+            Constraint::Percentage(35)
+        ].as_ref())
+        .split(chunks[1]);
+
+    draw_summary_section(f, chunks[0], app);
+    f.render_widget(bar_chart, bottom_chunks[0]);
+    draw_zone_charts(f, bottom_chunks[1], app, &app.week_zones, "This week");
 }
 
-fn draw_summary_section<B: Backend>(f: &mut Frame<B>, layout: Rect) {
-    let total = Total::new();
+/// Power zones over heart rate zones, minutes in every zone
+fn draw_zone_charts<B: Backend>(f: &mut Frame<B>, layout: Rect, app: &App, zones: &TimeInZones, title: &str) {
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([
+            Constraint::Percentage(50),
+            Constraint::Percentage(50)
+        ].as_ref())
+        .split(layout);
+
+    let charts = [
+        (format!("{} power zones", title), &zones.power, Color::Cyan),
+        (format!("{} HR zones ({})", title, app.zone_config.heart_rate_basis.as_str()), &zones.heart_rate, Color::Magenta)
+    ];
+
+    for ((title, seconds, color), area) in charts.into_iter().zip(chunks.iter()) {
+        let labels: Vec<String> = (1..=seconds.len()).map(|x| format!("Z{}", x)).collect();
+        let data: Vec<(&str, u64)> = labels.iter()
+            .zip(seconds.iter())
+            .map(|(label, x)| (label.as_str(), (x / 60.0) as u64))
+            .collect();
+
+        // Bars fill the width, the borders and the gaps between them left out
+        let bar_width = match data.len() as u16 {
+            0 => 1,
+            count => (area.width.saturating_sub(2 + count - 1) / count).max(1)
+        };
+
+        let bar_chart = BarChart::default()
+            .block(Block::default().title(title).borders(Borders::ALL))
+            .bar_width(bar_width)
+            .bar_gap(1)
+            .bar_style(Style::default().fg(color))
+            .data(&data);
+
+        f.render_widget(bar_chart, *area);
+    }
+}
+
+fn draw_summary_section<B: Backend>(f: &mut Frame<B>, layout: Rect, app: &App) {
+    let year = app.summary_year;
+    let total = &app.year_total;
 
     let chunks = Layout::default()
         .direction(Direction::Horizontal)
@@ -192,7 +826,9 @@ fn draw_summary_section<B: Backend>(f: &mut Frame<B>, layout: Rect) {
 
     let overall_summary_block = Block::default()
         .borders(Borders::ALL)
-        .title("Overall");
+        .title(format!("{} {} (h/l: year)"
+            , app.athlete().map(|x| x.name.clone()).unwrap_or(String::from("Overall"))
+            , year));
 
     let indoor_summary_block = Block::default()
         .borders(Borders::ALL)
@@ -206,12 +842,27 @@ fn draw_summary_section<B: Backend>(f: &mut Frame<B>, layout: Rect) {
         .borders(Borders::ALL)
         .title("MTB");
 
+    let now = Local::now().timestamp();
+    let ftp = app.thresholds.ftp_at(now);
+    let weight = app.thresholds.weight_at(now);
+    let watts_per_kg = match (ftp, weight) {
+        (Some(ftp_value), Some(weight_value)) if weight_value > 0.0 => format!(" ({:.2}W/kg)", ftp_value as f64 / weight_value),
+        _ => String::new()
+    };
+
     let overall_summary_text = vec![
-        Spans::from(format!("Threshold power: {}", "")),
+        Spans::from(format!("Threshold power: {}{}", optional_to_string(ftp), watts_per_kg)),
+        Spans::from(format!("LTHR: {} Max HR: {}"
+            , optional_to_string(app.thresholds.lthr_at(now))
+            , optional_to_string(app.thresholds.max_heart_rate_at(now)))),
+        Spans::from(format!("Weight: {}", optional_to_string(weight))),
         Spans::from(""),
         Spans::from(format!("Total duration: {}", util::moving_time_to_hour_minute_string(total.overall_duration))),
         Spans::from(format!("Total distance: {}", util::distance_as_string(total.overall_distance))),
         Spans::from(format!("Total rides: {}", total.overall_rides_count)),
+        Spans::from(format!("Elevation: {} Work: {}"
+            , util::ascent_as_string(total.overall_ascent)
+            , util::work_as_string(total.overall_work))),
         Spans::from(""),
         Spans::from(format!("Total time w/o indoor: {}", "")),
         Spans::from(format!("Total distance w/o indoor: {}", "")),
@@ -228,6 +879,7 @@ fn draw_summary_section<B: Backend>(f: &mut Frame<B>, layout: Rect) {
         Spans::from(format!("Total duration: {}", total.road_duration)),
         Spans::from(format!("Total distance: {}", total.road_distance)),
         Spans::from(format!("Total rides: {}", total.road_rides_count)),
+        Spans::from(format!("Total elevation: {}", util::ascent_as_string(total.road_ascent))),
         Spans::from(""),
         Spans::from(format!("AVG session duration: {}", "")),
         Spans::from(format!("AVG session distance: {}", "")),
@@ -237,13 +889,28 @@ fn draw_summary_section<B: Backend>(f: &mut Frame<B>, layout: Rect) {
         Spans::from(format!("Total duration: {}", total.mtb_duration)),
         Spans::from(format!("Total distance: {}", total.mtb_distance)),
         Spans::from(format!("Total rides: {}", total.mtb_rides_count)),
+        Spans::from(format!("Total elevation: {}", util::ascent_as_string(total.mtb_ascent))),
         Spans::from(""),
         Spans::from(format!("AVG session duration: {}", "")),
         Spans::from(format!("AVG session distance: {}", "")),
     ];
 
-    let overall_summary_paragraph = Paragraph::new(overall_summary_text)
-        .block(overall_summary_block);
+    let overall_inner = overall_summary_block.inner(chunks[0]);
+    let overall_chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([
+            Constraint::Min(0),
+            Constraint::Length(3)
+        ].as_ref())
+        .split(overall_inner);
+
+    let ftp_history = app.thresholds.ftp_series();
+    let ftp_sparkline = Sparkline::default()
+        .block(Block::default().title("FTP history"))
+        .style(Style::default().fg(Color::Yellow))
+        .data(&ftp_history);
+
+    let overall_summary_paragraph = Paragraph::new(overall_summary_text);
 
     let indoor_summary_paragraph = Paragraph::new(indoor_summary_text)
         .block(indoor_summary_block);
@@ -254,12 +921,39 @@ fn draw_summary_section<B: Backend>(f: &mut Frame<B>, layout: Rect) {
     let mtb_summary_paragraph = Paragraph::new(mtb_summary_text)
         .block(mtb_summary_block);
 
-    f.render_widget(overall_summary_paragraph, chunks[0]);
+    f.render_widget(overall_summary_block, chunks[0]);
+    f.render_widget(overall_summary_paragraph, overall_chunks[0]);
+    f.render_widget(ftp_sparkline, overall_chunks[1]);
     f.render_widget(indoor_summary_paragraph, chunks[1]);
     f.render_widget(road_summary_paragraph, chunks[2]);
     f.render_widget(mtb_summary_paragraph, chunks[3]);
 }
 
+fn draw_import_log<B: Backend>(f: &mut Frame<B>, layout: Rect, app: &App) {
+    let items: Vec<ListItem> = app.import_log.iter()
+        .map(|entry| {
+            let style = match entry.is_failed() {
+                true => Style::default().fg(Color::Red),
+                false => Style::default()
+            };
+            return ListItem::new(Span::styled(log_entry_as_string(entry), style));
+        })
+        .collect();
+
+    let failed_count = app.import_log.iter().filter(|x| x.is_failed()).count();
+    let list = List::new(items)
+        .block(Block::default()
+            .title(format!("Latest imports ({} failed)", failed_count))
+            .borders(Borders::ALL));
+
+    let area = Layout::default()
+        .constraints([Constraint::Min(0)].as_ref())
+        .margin(1)
+        .split(layout);
+
+    f.render_widget(list, area[0]);
+}
+
 fn draw_session_list<B: Backend>(f: &mut Frame<B>, layout: Rect, app: &App) {
     let mut state = ListState::default();
     state.select(app.selected_session_index);
@@ -269,7 +963,7 @@ fn draw_session_list<B: Backend>(f: &mut Frame<B>, layout: Rect, app: &App) {
         .collect();
 
     let list = List::new(items)
-        .block(Block::default().title("Workout list").borders(Borders::ALL))
+        .block(Block::default().title(session_list_title(app)).borders(Borders::ALL))
         .style(Style::default().fg(Color::White))
         .highlight_style(Style::default().add_modifier(Modifier::ITALIC))
         .highlight_symbol(">> ");
@@ -277,15 +971,45 @@ fn draw_session_list<B: Backend>(f: &mut Frame<B>, layout: Rect, app: &App) {
     f.render_stateful_widget(list, layout, &mut state);
 }
 
+fn session_list_title(app: &App) -> String {
+    let keys = "e/s/n/t: title/sub sport/notes/tags, /: search, d: delete, p: athlete";
+    if app.search_query.trim().is_empty() {
+        return format!("Workout list ({})", keys);
+    }
+
+    return format!("Workout list matching '{}' ({})", app.search_query.trim(), keys);
+}
+
 fn draw_session_chart<B: Backend>(f: &mut Frame<B>, layout: Rect, app: &App) {
-    let selected_session = app.selected_session.to_owned().unwrap();
-    let dataset = util::build_session_dataset(&selected_session);
+    let selected_session = match app.selected_session.to_owned() {
+        Some(value) => value,
+        None => {
+            f.render_widget(Block::default().title("Selected session").borders(Borders::ALL), layout);
+            return;
+        }
+    };
+    let threshold_power = app.selected_threshold_power();
+    let w_prime_balance = app.selected_w_prime_balance();
+    let w_prime_percent: Vec<f64> = match &w_prime_balance {
+        Some((fit, balance)) => balance.iter().map(|x| x / fit.w_prime * 100.0).collect(),
+        None => Vec::new()
+    };
+    let dataset = util::build_session_dataset(&selected_session, threshold_power, &w_prime_percent);
+    // The data panel is full, the lowest W′bal goes in the chart title
+    let chart_title = match &w_prime_balance {
+        Some((fit, balance)) => {
+            let min = balance.iter().copied().fold(fit.w_prime, f64::min);
+            format!("Selected session (min W′bal {:.1}kJ, {:.0}% of {:.1}kJ)", min / 1000.0, min / fit.w_prime * 100.0, fit.w_prime / 1000.0)
+        },
+        None => String::from("Selected session")
+    };
 
     let chunks = Layout::default()
         .direction(Direction::Horizontal)
         .constraints([
             Constraint::Percentage(15),
-            Constraint::Percentage(85)
+            Constraint::Percentage(65),
+            Constraint::Percentage(20)
         ].as_ref())
         .margin(1)
         .split(layout);
@@ -295,19 +1019,33 @@ fn draw_session_chart<B: Backend>(f: &mut Frame<B>, layout: Rect, app: &App) {
         .title("Data");
 
     let text = vec![
+        Spans::from(format!("Title: {}", selected_session.title.clone().unwrap_or_default())),
         Spans::from(format!("Date: {}", util::timestamp_as_string(selected_session.start_time))),
         Spans::from(format!("Type: {}", selected_session.sub_sport)),
+        Spans::from(format!("Bike: {}", app.bikes.iter()
+            .find(|x| Some(x.id) == selected_session.bike_id)
+            .map(|x| x.name.clone())
+            .unwrap_or_default())),
+        Spans::from(format!("Tags: {}", selected_session.tags.join(", "))),
+        Spans::from(format!("Notes: {}", selected_session.notes.clone().unwrap_or_default())),
         Spans::from(""),
         Spans::from(format!("Duration: {}", util::moving_time_to_hour_minute_string(selected_session.total_moving_time))),
         Spans::from(format!("Distance: {}", util::distance_as_string(selected_session.total_distance))),
         Spans::from(format!("AVG Heart rate: {}", selected_session.avg_heart_rate)),
-        Spans::from(format!("AVG Power: {}", selected_session.avg_power)),
+        Spans::from(format!("AVG Power: {} NP: {:.0}"
+            , selected_session.avg_power
+            , selected_session.training_load.normalized_power)),
+        Spans::from(format!("IF: {:.2} VI: {:.2} TSS: {:.0}"
+            , selected_session.training_load.intensity_factor
+            , selected_session.training_load.variability_index
+            , selected_session.training_load.training_stress_score)),
         Spans::from(format!("AVG Cadence: {}", selected_session.avg_cadence)),
-        Spans::from(format!("Threshold power: {}", selected_session.threshold_power)),
+        Spans::from(format!("Threshold power: {}", threshold_power)),
     ];
 
     let paragraph = Paragraph::new(text)
-        .block(block);
+        .block(block)
+        .wrap(Wrap { trim: true });
 
     let datasets = vec![
         Dataset::default()
@@ -328,10 +1066,16 @@ fn draw_session_chart<B: Backend>(f: &mut Frame<B>, layout: Rect, app: &App) {
             .graph_type(GraphType::Line)
             .style(Style::default().fg(Color::White))
             .data(&dataset.threshold_power),
+        Dataset::default()
+            .name("W′bal %")
+            .marker(symbols::Marker::Braille)
+            .graph_type(GraphType::Line)
+            .style(Style::default().fg(Color::Yellow))
+            .data(&dataset.w_prime_balance),
     ];
 
     let chart = Chart::new(datasets)
-        .block(Block::default().title("Selected session").borders(Borders::ALL))
+        .block(Block::default().title(chart_title.clone()).borders(Borders::ALL))
         .x_axis(Axis::default()
             .title(Span::styled("Time", Style::default().fg(Color::Red)))
             .style(Style::default().fg(Color::White))
@@ -342,11 +1086,16 @@ fn draw_session_chart<B: Backend>(f: &mut Frame<B>, layout: Rect, app: &App) {
             .style(Style::default().fg(Color::White))
             .bounds([dataset.min_y, dataset.max_y])
             .labels([
-                dataset.min_y.to_string(),
-                dataset.max_y.to_string()
+                format!("{:.0}", dataset.min_y),
+                format!("{:.0}", dataset.max_y)
             ].iter().cloned().map(Span::from).collect()));
 
     f.render_widget(paragraph, chunks[0]);
-    f.render_widget(chart, chunks[1]);
+    if selected_session.records.is_empty() {
+        f.render_widget(Block::default().title(chart_title).borders(Borders::ALL), chunks[1]);
+    } else {
+        f.render_widget(chart, chunks[1]);
+    }
+    draw_zone_charts(f, chunks[2], app, &app.selected_zones().unwrap_or_default(), "Session");
 }
 
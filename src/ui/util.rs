@@ -1,4 +1,5 @@
-use chrono::{NaiveDateTime, DateTime, Utc};
+use chrono::{Local, TimeZone};
+use tui::layout::Rect;
 
 use crate::parser::Session;
 
@@ -8,7 +9,9 @@ pub struct ChartDataset {
     pub min_y: f64,
     pub max_y: f64,
     pub max_x: f64,
-    pub threshold_power: Vec<(f64, f64)> 
+    pub threshold_power: Vec<(f64, f64)> ,
+    /// Percent of W′ left, empty without a critical power fit
+    pub w_prime_balance: Vec<(f64, f64)>
 }
 
 impl ChartDataset {
@@ -24,12 +27,14 @@ impl ChartDataset {
             min_y: min_y_value, 
             max_y: max_y_value,
             max_x: max_x_value,
-            threshold_power: threshold_power_data
+            threshold_power: threshold_power_data,
+            w_prime_balance: Vec::new()
         }
     }
 }
 
-pub fn build_session_dataset(session: &Session) -> ChartDataset {
+/// W′bal is given in percent of W′ for every record, so it shares the axis of power and heart rate
+pub fn build_session_dataset(session: &Session, threshold_power: i64, w_prime_balance: &[f64]) -> ChartDataset {
     let mut power_array: Vec<(f64, f64)> =  Vec::new();
     let mut heart_array: Vec<(f64, f64)> = Vec::new();
     let mut threshold_power_data: Vec<(f64, f64)> = Vec::new();
@@ -37,40 +42,51 @@ pub fn build_session_dataset(session: &Session) -> ChartDataset {
     for (idx, item) in session.records.iter().enumerate() {
         power_array.push((idx as f64, item.power as f64));
         heart_array.push((idx as f64, item.heart_rate as f64));
-        threshold_power_data.push((idx as f64, session.threshold_power as f64));
+        threshold_power_data.push((idx as f64, threshold_power as f64));
     }
 
+    // Sessions without records, or whose records couldn't be read, have an empty dataset
     let min_value_y = session.records.iter()
         .map(|x| x.heart_rate)
-        .collect::<Vec<i64>>()
-        .iter()
         .min()
-        .unwrap()
-        .to_owned() as f64;
+        .unwrap_or(0) as f64;
 
     let max_value_y = session.records.iter()
         .map(|x| x.power)
-        .collect::<Vec<i64>>()
-        .iter()
         .max()
-        .unwrap()
-        .to_owned() as f64;
+        .unwrap_or(0) as f64;
 
     let max_value_x = heart_array.len() as f64;
 
-    let dataset = ChartDataset::new(power_array, heart_array, min_value_y, max_value_y, max_value_x, threshold_power_data);
+    let mut dataset = ChartDataset::new(power_array, heart_array, min_value_y, max_value_y, max_value_x, threshold_power_data);
+    dataset.w_prime_balance = w_prime_balance.iter().enumerate().map(|(idx, x)| (idx as f64, *x)).collect();
+    dataset.min_y = w_prime_balance.iter().fold(dataset.min_y, |min, x| min.min(*x));
+    dataset.max_y = w_prime_balance.iter().fold(dataset.max_y, |max, x| max.max(*x));
+
     return dataset;
 }
 
 pub fn sessio_to_string(session: &Session) -> String {
-    let result = format!("{} {} {}"
+    let mut result = format!("{} {} {}"
         , timestamp_as_string(session.start_time)
         , session.sub_sport
         , moving_time_to_hour_minute_string(session.total_moving_time));
 
+    if let Some(title) = &session.title {
+        result = format!("{} {}", result, title);
+    }
+
+    if !session.tags.is_empty() {
+        result = format!("{} #{}", result, session.tags.join(" #"));
+    }
+
     return result;
 }
 
+pub fn date_as_string(timestamp: i64) -> String {
+    return timestamp_as_string(timestamp)[..10].to_string();
+}
+
 pub fn moving_time_to_hour_minute_string(moving_time: f64) -> String {
     let hours = ((moving_time / 60.0) / 60.0) as i64;
     let minutes = ((moving_time / 60.0) % 60.0) as i64;
@@ -79,8 +95,7 @@ pub fn moving_time_to_hour_minute_string(moving_time: f64) -> String {
 }
 
 pub fn timestamp_as_string(timestamp: i64) -> String {
-    let naive_datetime = NaiveDateTime::from_timestamp_opt(timestamp, 0).unwrap();
-    let start_date_time: DateTime<Utc> = DateTime::from_utc(naive_datetime, Utc);
+    let start_date_time = Local.timestamp_opt(timestamp, 0).unwrap();
     let start_date = start_date_time.format("%Y-%m-%d %H:%M:%S").to_string();
     return start_date;
 }
@@ -88,3 +103,39 @@ pub fn timestamp_as_string(timestamp: i64) -> String {
 pub fn distance_as_string(distance: f64) -> String {
     return format!("{:.2}km", distance / 1000.0);
 }
+
+pub fn ascent_as_string(ascent: f64) -> String {
+    return format!("{:.0}m", ascent);
+}
+
+/// Joules as kilojoules
+pub fn work_as_string(work: f64) -> String {
+    return format!("{:.0}kJ", work / 1000.0);
+}
+
+/// Rect of the given width percentage and height in lines in the middle of `area`
+pub fn centered_rect(width_percentage: u16, height: u16, area: Rect) -> Rect {
+    let width = area.width * width_percentage / 100;
+
+    return Rect {
+        x: area.x + (area.width - width) / 2,
+        y: area.y + area.height.saturating_sub(height) / 2,
+        width,
+        height: height.min(area.height)
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dataset_of_a_session_without_records_is_empty() {
+        let dataset = build_session_dataset(&Session::default(), 250, &[]);
+
+        assert!(dataset.power.is_empty());
+        assert!(dataset.heart_rate.is_empty());
+        assert!(dataset.threshold_power.is_empty());
+        assert_eq!((dataset.min_y, dataset.max_y, dataset.max_x), (0.0, 0.0, 0.0));
+    }
+}
@@ -1,6 +1,47 @@
-use crate::app::App;
+use anyhow::Result;
+use chrono::{Local, NaiveDate};
+use crossterm::event::KeyCode;
+
+use crate::app::{App, EditField, InputMode};
+use crate::summary::local_midnight;
+
+/// Value following a `--flag` in the command line arguments
+pub fn flag_value<'a>(args: &'a [String], flag: &str) -> Option<&'a String> {
+    return match args.iter().position(|x| x == flag) {
+        Some(idx) => args.get(idx + 1),
+        None => None
+    };
+}
+
+/// Local midnight of a `--flag 2023-05-01` argument as a unix timestamp, now when the flag is not given
+pub fn date_flag_or_now(args: &[String], flag: &str) -> Result<i64> {
+    return match flag_value(args, flag) {
+        Some(value) => Ok(local_midnight(NaiveDate::parse_from_str(value, "%Y-%m-%d")?)),
+        None => Ok(Local::now().timestamp())
+    };
+}
+
+/// Command line arguments without the `--flag value` pairs
+pub fn positional_args(args: &[String]) -> Vec<String> {
+    let mut result: Vec<String> = Vec::new();
+    let mut iter = args.iter();
+
+    while let Some(arg) = iter.next() {
+        if arg.starts_with("--") {
+            iter.next();
+            continue;
+        }
+        result.push(arg.clone());
+    }
+
+    return result;
+}
 
 pub fn move_down_event(app: &mut App) {
+    if app.sessions.is_empty() {
+        return;
+    }
+
     let max_idx = app.sessions.len();
     let index = app.selected_session_index.unwrap();
 
@@ -14,6 +55,10 @@ pub fn move_down_event(app: &mut App) {
 }
 
 pub fn move_up_event(app: &mut App) {
+    if app.sessions.is_empty() {
+        return;
+    }
+
     let max_idx = app.sessions.len();
     let index = app.selected_session_index.unwrap();
 
@@ -25,3 +70,89 @@ pub fn move_up_event(app: &mut App) {
     app.selected_session_index = Some(new_idx);
     app.change_selected_session(new_idx);
 }
+
+pub fn previous_year_event(app: &mut App) {
+    app.summary_year -= 1;
+    app.reload_year_total();
+    app.reload_compared_years();
+}
+
+pub fn next_year_event(app: &mut App) {
+    app.summary_year += 1;
+    app.reload_year_total();
+    app.reload_compared_years();
+}
+
+/// Opens the input popup prefilled with the current value of the field
+pub fn edit_event(app: &mut App, field: EditField) {
+    let value = match (&app.selected_session, field) {
+        (_, EditField::Search) => app.search_query.clone(),
+        (Some(session), EditField::Title) => session.title.clone().unwrap_or_default(),
+        (Some(session), EditField::SubSport) => session.sub_sport.clone(),
+        (Some(session), EditField::Notes) => session.notes.clone().unwrap_or_default(),
+        (Some(session), EditField::Tags) => session.tags.join(", "),
+        (None, _) => return
+    };
+
+    app.input_mode = InputMode::Edit(field, value);
+}
+
+/// Splits a comma separated list into lowercase tags without duplicates
+pub fn parse_tags(value: &str) -> Vec<String> {
+    let mut tags: Vec<String> = Vec::new();
+    for tag in value.split(',').map(|x| x.trim().to_lowercase()) {
+        if !tag.is_empty() && !tags.contains(&tag) {
+            tags.push(tag);
+        }
+    }
+
+    return tags;
+}
+
+/// Handles key presses while editing a field or confirming a deletion
+pub fn input_event(app: &mut App, key: KeyCode) -> Result<()> {
+    let mode = app.input_mode.clone();
+
+    match (mode, key) {
+        (InputMode::ConfirmDelete, KeyCode::Char('y')) => {
+            app.input_mode = InputMode::Normal;
+            app.delete_selected_session()?;
+        },
+        (InputMode::Edit(EditField::Search, _), KeyCode::Esc) => {
+            app.input_mode = InputMode::Normal;
+            app.search_query.clear();
+            app.reload_sessions();
+        },
+        (InputMode::ConfirmDelete, _) | (_, KeyCode::Esc) => {
+            app.input_mode = InputMode::Normal;
+        },
+        (InputMode::Edit(EditField::Search, _), KeyCode::Enter) => {
+            app.input_mode = InputMode::Normal;
+        },
+        (InputMode::Edit(field, value), KeyCode::Enter) => {
+            app.input_mode = InputMode::Normal;
+            app.update_selected_session(field, value)?;
+        },
+        (InputMode::Edit(field, mut value), KeyCode::Char(c)) => {
+            value.push(c);
+            search_as_you_type(app, field, &value);
+            app.input_mode = InputMode::Edit(field, value);
+        },
+        (InputMode::Edit(field, mut value), KeyCode::Backspace) => {
+            value.pop();
+            search_as_you_type(app, field, &value);
+            app.input_mode = InputMode::Edit(field, value);
+        },
+        _ => ()
+    }
+
+    return Ok(());
+}
+
+fn search_as_you_type(app: &mut App, field: EditField, value: &str) {
+    if field == EditField::Search {
+        app.search_query = value.to_string();
+        app.selected_session_index = Some(0);
+        app.reload_sessions();
+    }
+}
@@ -0,0 +1,228 @@
+use std::str::FromStr;
+use anyhow::{Context, Result, bail};
+
+use crate::athlete::Athlete;
+use crate::parser::Session;
+use crate::power;
+use crate::store::SessionStore;
+use crate::summary::{DateRange, Period};
+use crate::threshold::ThresholdHistory;
+use crate::ui::util::moving_time_to_hour_minute_string;
+use crate::util::{flag_value, positional_args};
+
+/// Weeks printed by the `zones` command when `--weeks` isn't given
+const DEFAULT_PRINTED_WEEKS: usize = 4;
+
+/// Coggan's 7 power zones, upper bounds of the first 6 in percent of FTP
+const COGGAN_POWER_ZONES: [f64; 6] = [55.0, 75.0, 90.0, 105.0, 120.0, 150.0];
+/// Coggan's 5 heart rate zones in percent of LTHR
+const LTHR_ZONES: [f64; 4] = [68.0, 83.0, 94.0, 105.0];
+/// The usual 5 heart rate zones in percent of max heart rate
+const MAX_HEART_RATE_ZONES: [f64; 4] = [60.0, 70.0, 80.0, 90.0];
+
+/// Heart rate the zones are percentages of
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HeartRateBasis {
+    Lthr,
+    MaxHeartRate
+}
+
+impl FromStr for HeartRateBasis {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        return match value {
+            "lthr" => Ok(HeartRateBasis::Lthr),
+            "max-hr" => Ok(HeartRateBasis::MaxHeartRate),
+            _ => bail!("Unknown heart rate zone basis '{}', expected lthr or max-hr", value)
+        };
+    }
+}
+
+impl HeartRateBasis {
+    pub fn as_str(&self) -> &str {
+        return match self {
+            HeartRateBasis::Lthr => "lthr",
+            HeartRateBasis::MaxHeartRate => "max-hr"
+        };
+    }
+}
+
+/// Upper bounds of every zone but the last, the last zone has no upper bound
+#[derive(Debug, Clone, PartialEq)]
+pub struct ZoneConfig {
+    /// Percent of FTP
+    pub power: Vec<f64>,
+    pub heart_rate_basis: HeartRateBasis,
+    /// Percent of LTHR or max heart rate
+    pub heart_rate: Vec<f64>
+}
+
+impl Default for ZoneConfig {
+    fn default() -> Self {
+        return Self {
+            power: COGGAN_POWER_ZONES.to_vec(),
+            heart_rate_basis: HeartRateBasis::Lthr,
+            heart_rate: LTHR_ZONES.to_vec()
+        };
+    }
+}
+
+impl ZoneConfig {
+    /// Read from `POWER_ZONES`, `HR_ZONE_BASIS` and `HR_ZONES` in .env, Coggan's zones when they aren't set
+    pub fn from_env() -> Result<Self> {
+        let heart_rate_basis = match dotenv::var("HR_ZONE_BASIS") {
+            Ok(value) => value.parse::<HeartRateBasis>()?,
+            Err(_) => HeartRateBasis::Lthr
+        };
+        let default_heart_rate = match heart_rate_basis {
+            HeartRateBasis::Lthr => LTHR_ZONES.to_vec(),
+            HeartRateBasis::MaxHeartRate => MAX_HEART_RATE_ZONES.to_vec()
+        };
+
+        return Ok(Self {
+            power: zone_bounds(dotenv::var("POWER_ZONES").ok(), COGGAN_POWER_ZONES.to_vec())?,
+            heart_rate_basis,
+            heart_rate: zone_bounds(dotenv::var("HR_ZONES").ok(), default_heart_rate)?
+        });
+    }
+}
+
+/// Comma separated percentages which have to go up
+fn zone_bounds(value: Option<String>, default: Vec<f64>) -> Result<Vec<f64>> {
+    let value = match value {
+        Some(value) => value,
+        None => return Ok(default)
+    };
+
+    let bounds = value.split(',')
+        .map(|x| x.trim().parse::<f64>().with_context(|| format!("Invalid zone bound '{}'", x)))
+        .collect::<Result<Vec<f64>>>()?;
+
+    if bounds.iter().any(|x| *x <= 0.0) || bounds.windows(2).any(|x| x[0] >= x[1]) {
+        bail!("Zone bounds '{}' have to be positive and increasing", value);
+    }
+
+    return Ok(bounds);
+}
+
+/// Seconds spent in every zone, zone 1 first
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TimeInZones {
+    pub power: Vec<f64>,
+    pub heart_rate: Vec<f64>
+}
+
+impl TimeInZones {
+    fn empty(config: &ZoneConfig) -> Self {
+        return Self {
+            power: vec![0.0; config.power.len() + 1],
+            heart_rate: vec![0.0; config.heart_rate.len() + 1]
+        };
+    }
+
+    /// Time of the session's records, a record stands for the seconds until the next one unless that is a stop.
+    /// Power is left out without an FTP or any power, heart rate without the reference heart rate and records without heart rate
+    pub fn of_session(session: &Session, thresholds: &ThresholdHistory, config: &ZoneConfig) -> Self {
+        let mut zones = Self::empty(config);
+        let ftp = thresholds.ftp_for(session);
+        let reference_heart_rate = match config.heart_rate_basis {
+            HeartRateBasis::Lthr => thresholds.lthr_for(session),
+            HeartRateBasis::MaxHeartRate => thresholds.max_heart_rate_for(session)
+        };
+        let has_power = session.records.iter().any(|x| x.power > 0);
+
+        for (record, seconds) in session.records.iter().zip(power::record_durations(&session.records)) {
+            if ftp > 0 && has_power {
+                zones.power[zone_of(record.power as f64, ftp as f64, &config.power)] += seconds;
+            }
+
+            if reference_heart_rate > 0 && record.heart_rate > 0 {
+                zones.heart_rate[zone_of(record.heart_rate as f64, reference_heart_rate as f64, &config.heart_rate)] += seconds;
+            }
+        }
+
+        return zones;
+    }
+
+    /// Adds the time of another session, zones it doesn't have are left as they are
+    pub fn accumulate(&mut self, other: &TimeInZones) {
+        for (total, value) in self.power.iter_mut().zip(other.power.iter()) {
+            *total += value;
+        }
+
+        for (total, value) in self.heart_rate.iter_mut().zip(other.heart_rate.iter()) {
+            *total += value;
+        }
+    }
+
+    /// Time in every zone as `Z1 1h5m, Z2 30m, ...`
+    pub fn as_string(values: &[f64]) -> String {
+        return values.iter()
+            .enumerate()
+            .map(|(idx, x)| format!("Z{} {}", idx + 1, moving_time_to_hour_minute_string(*x)))
+            .collect::<Vec<String>>()
+            .join(", ");
+    }
+}
+
+/// Zone of the value, a value on a bound is in the lower zone
+fn zone_of(value: f64, reference: f64, bounds: &[f64]) -> usize {
+    return bounds.iter().filter(|x| value * 100.0 / reference > **x).count();
+}
+
+/// Time in zones of the athlete's sessions in every range, records are read one session at a time
+pub fn of_ranges(store: &dyn SessionStore, athlete_id: i64, thresholds: &ThresholdHistory, config: &ZoneConfig, ranges: &[DateRange]) -> Result<Vec<TimeInZones>> {
+    let mut totals = vec![TimeInZones::empty(config); ranges.len()];
+
+    for session in store.get_all_sessions(athlete_id)? {
+        let idx = match ranges.iter().position(|x| session.start_time >= x.start && session.start_time < x.end) {
+            Some(idx) => idx,
+            None => continue
+        };
+
+        let detailed = store.get_session_details(&session)?;
+        totals[idx].accumulate(&TimeInZones::of_session(&detailed, thresholds, config));
+    }
+
+    return Ok(totals);
+}
+
+/// Handles `zones [<session id>] [--weeks N]`, the time in zones of a session or of the latest weeks
+pub fn command(store: &dyn SessionStore, args: &[String]) -> Result<()> {
+    let config = ZoneConfig::from_env()?;
+    let positional = positional_args(args);
+
+    if let Some(value) = positional.first() {
+        let session = store.get_session(value.parse::<i64>()?)?;
+        let athlete_id = match session.athlete_id {
+            Some(athlete_id) => athlete_id,
+            None => bail!("Session {} has no athlete", value)
+        };
+        let thresholds = store.get_thresholds(athlete_id)?;
+        let zones = TimeInZones::of_session(&store.get_session_details(&session)?, &thresholds, &config);
+
+        println!("Power: {}", TimeInZones::as_string(&zones.power));
+        println!("Heart rate: {}", TimeInZones::as_string(&zones.heart_rate));
+
+        return Ok(());
+    }
+
+    let athlete = Athlete::from_args(store, args)?;
+    let count = match flag_value(args, "--weeks") {
+        Some(value) => value.parse::<usize>()?,
+        None => DEFAULT_PRINTED_WEEKS
+    };
+
+    let weeks = Period::Week.latest(count)?;
+    let thresholds = store.get_thresholds(athlete.id)?;
+
+    for (range, zones) in weeks.iter().zip(of_ranges(store, athlete.id, &thresholds, &config, &weeks)?) {
+        println!("{}: power {}; heart rate {}"
+            , Period::Week.label(range)
+            , TimeInZones::as_string(&zones.power)
+            , TimeInZones::as_string(&zones.heart_rate));
+    }
+
+    return Ok(());
+}